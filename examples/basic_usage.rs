@@ -1,4 +1,4 @@
-use taior::{Taior, SendOptions, RoutingMode};
+use taior::{Taior, SendOptions, SendOutcome, RoutingMode};
 
 fn main() {
     println!("=== Taior Basic Usage Demo ===\n");
@@ -15,11 +15,13 @@ fn main() {
     println!("1. Modo Fast (baja latencia, 1-2 saltos):");
     let message = b"Hola desde Taior en modo Fast";
     match taior.send(message, SendOptions::fast()) {
-        Ok(packet) => {
+        Ok(SendOutcome::Ready(sent)) => {
+            let packet = sent.packet;
             println!("   ✓ Paquete creado: {} bytes cifrados", packet.size());
             println!("   ✓ TTL: {}", packet.ttl);
-            println!("   ✓ Cover traffic: {}", packet.is_cover);
+            println!("   ✓ Marca de cover traffic cifrada dentro del paquete");
         }
+        Ok(SendOutcome::Queued) => println!("   … En cola hasta tener saltos suficientes"),
         Err(e) => println!("   ✗ Error: {}", e),
     }
     println!();
@@ -28,11 +30,17 @@ fn main() {
     taior.enable_cover_traffic(true, 0.3);
     let message = b"Mensaje privado en modo Mix";
     match taior.send(message, SendOptions::mix()) {
-        Ok(packet) => {
+        Ok(SendOutcome::Ready(sent)) => {
+            let packet = sent.packet;
             println!("   ✓ Paquete creado: {} bytes cifrados", packet.size());
             println!("   ✓ TTL: {}", packet.ttl);
             println!("   ✓ Padding indistinguible aplicado");
+            println!(
+                "   ✓ Saltos: {} de {} solicitados",
+                sent.anonymity.hops, sent.anonymity.requested_hops
+            );
         }
+        Ok(SendOutcome::Queued) => println!("   … En cola hasta tener saltos suficientes"),
         Err(e) => println!("   ✗ Error: {}", e),
     }
     println!();
@@ -40,10 +48,12 @@ fn main() {
     println!("3. Modo Adaptive (balance dinámico):");
     let message = b"Mensaje adaptativo";
     match taior.send(message, SendOptions::adaptive()) {
-        Ok(packet) => {
+        Ok(SendOutcome::Ready(sent)) => {
+            let packet = sent.packet;
             println!("   ✓ Paquete creado: {} bytes cifrados", packet.size());
             println!("   ✓ TTL: {}", packet.ttl);
         }
+        Ok(SendOutcome::Queued) => println!("   … En cola hasta tener saltos suficientes"),
         Err(e) => println!("   ✗ Error: {}", e),
     }
     println!();
@@ -51,10 +61,12 @@ fn main() {
     println!("4. Modo Custom (configuración manual):");
     let message = b"Mensaje custom";
     match taior.send(message, SendOptions::custom(RoutingMode::Mix, 3)) {
-        Ok(packet) => {
+        Ok(SendOutcome::Ready(sent)) => {
+            let packet = sent.packet;
             println!("   ✓ Paquete creado: {} bytes cifrados", packet.size());
             println!("   ✓ TTL: {} (personalizado)", packet.ttl);
         }
+        Ok(SendOutcome::Queued) => println!("   … En cola hasta tener saltos suficientes"),
         Err(e) => println!("   ✗ Error: {}", e),
     }
     println!();
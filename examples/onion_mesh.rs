@@ -0,0 +1,62 @@
+use taior::circuit::{CircuitBuilder, OnionEncryptor, DEFAULT_HOP_TIMEOUT};
+use taior::packet::TaiorPacket;
+use taior::transport::{Transport, UdpTransport};
+use taior::OnionRelay;
+use rand_core::OsRng;
+use x25519_dalek::StaticSecret;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    println!("=== Taior Onion Mesh Demo ===\n");
+
+    // 1. Levantar 3 relays en localhost, cada uno con su socket UDP
+    let mut relays = Vec::new();
+    let mut transports = Vec::new();
+    for _ in 0..3 {
+        relays.push(OnionRelay::new(StaticSecret::random_from_rng(OsRng)));
+        transports.push(UdpTransport::bind("127.0.0.1:0".parse()?).await?);
+    }
+    let endpoints: Vec<_> = transports.iter().map(|t| t.local_addr()).collect::<Result<_, _>>()?;
+
+    // 2. Registrar los relays por su endpoint y darles la tabla de vecinos
+    let mut builder = CircuitBuilder::new(1, 5, 600);
+    let addresses: Vec<_> = relays.iter().map(|r| r.address().clone()).collect();
+    for (i, relay) in relays.iter_mut().enumerate() {
+        builder.add_node_with_onion_key(endpoints[i].to_string(), relay.address().clone(), relay.onion_key());
+        for (address, endpoint) in addresses.iter().zip(&endpoints) {
+            relay.add_peer(address.clone(), *endpoint);
+        }
+        println!("Relay {}: {} en {}", i, relay.address().as_str(), endpoints[i]);
+    }
+
+    // 3. Arrancar los relays
+    let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+    for (mut relay, mut transport) in relays.into_iter().zip(transports) {
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            while let Ok(result) = relay.serve_one(&mut transport).await {
+                if let Some(delivery) = result {
+                    let _ = tx.send(delivery.packet).await;
+                }
+            }
+        });
+    }
+
+    // 4. Extender un circuito de 3 saltos con handshake autenticado en cada uno
+    let mut client = UdpTransport::bind("127.0.0.1:0".parse()?).await?;
+    let mut circuit = builder.build_circuit(&mut client, 3, DEFAULT_HOP_TIMEOUT).await?;
+    let entry = addresses.iter().position(|a| *a == circuit.nodes[0].address).unwrap_or(0);
+    println!("\n✓ Circuito de {} saltos establecido", circuit.hop_count());
+
+    // 5. Enviar un paquete onion al primer salto
+    let inner = TaiorPacket::new(b"Hola a traves de la malla", 3, 64, false)?;
+    let packet = OnionEncryptor::new(&mut circuit).wrap_packet(&inner)?;
+    client.send(&packet, endpoints[entry]).await?;
+    println!("✓ Paquete enviado al relay de entrada ({} bytes)", packet.size());
+
+    let delivered = rx.recv().await.ok_or("mesh closed")?;
+    let contents = delivered.decrypt_with_ikm()?;
+    println!("✓ Entregado en el último salto: {:?}", String::from_utf8_lossy(&contents.data));
+
+    Ok(())
+}
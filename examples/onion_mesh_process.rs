@@ -0,0 +1,87 @@
+use std::net::SocketAddr;
+use taior::circuit::{CircuitBuilder, OnionEncryptor, DEFAULT_HOP_TIMEOUT};
+use taior::packet::TaiorPacket;
+use taior::transport::{Transport, UdpTransport};
+use taior::OnionRelay;
+use x25519_dalek::StaticSecret;
+
+// La malla de `onion_mesh`, con cada relay en su propio proceso:
+//
+//   cargo run --example onion_mesh_process -- relay 0
+//   cargo run --example onion_mesh_process -- relay 1
+//   cargo run --example onion_mesh_process -- relay 2
+//   cargo run --example onion_mesh_process -- client
+
+const RELAYS: usize = 3;
+
+// Claves fijas solo para la demo: así cada proceso conoce las de los demás sin intercambiarlas
+fn relay(index: usize) -> OnionRelay {
+    OnionRelay::new(StaticSecret::from([index as u8 + 1; 32]))
+}
+
+fn endpoint(index: usize) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], 7300 + index as u16))
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        ["relay", index] => run_relay(index.parse()?).await,
+        ["client"] => run_client().await,
+        _ => Err("uso: onion_mesh_process relay <0-2> | client".into()),
+    }
+}
+
+async fn run_relay(index: usize) -> Result<(), Box<dyn std::error::Error>> {
+    if index >= RELAYS {
+        return Err(format!("solo hay relays 0 a {}", RELAYS - 1).into());
+    }
+    let mut relay = relay(index);
+    for peer in 0..RELAYS {
+        relay.add_peer(self::relay(peer).address().clone(), endpoint(peer));
+    }
+    let mut transport = UdpTransport::bind(endpoint(index)).await?;
+    println!("Relay {}: {} en {}", index, relay.address().as_str(), endpoint(index));
+
+    // Los paquetes que llegan al último salto se contestan con un eco por el circuito
+    loop {
+        let Some(delivery) = relay.serve_one(&mut transport).await? else {
+            continue;
+        };
+        let data = delivery.packet.decrypt_with_ikm()?.data;
+        println!("✓ Entregado: {:?}", String::from_utf8_lossy(&data));
+        if let Some(circuit_id) = delivery.circuit_id {
+            let mut echo = b"eco: ".to_vec();
+            echo.extend_from_slice(&data);
+            relay.send_reply(&mut transport, &circuit_id, &TaiorPacket::new(&echo, 3, 64, false)?).await?;
+        }
+    }
+}
+
+async fn run_client() -> Result<(), Box<dyn std::error::Error>> {
+    let mut builder = CircuitBuilder::new(1, 5, 600);
+    for index in 0..RELAYS {
+        let relay = relay(index);
+        builder.add_node_with_onion_key(endpoint(index).to_string(), relay.address().clone(), relay.onion_key());
+    }
+
+    let mut client = UdpTransport::bind("127.0.0.1:0".parse()?).await?;
+    let mut circuit = builder.build_circuit(&mut client, RELAYS, DEFAULT_HOP_TIMEOUT).await?;
+    let entry = (0..RELAYS)
+        .find(|index| *relay(*index).address() == circuit.nodes[0].address)
+        .map(endpoint)
+        .ok_or("entrada desconocida")?;
+    println!("✓ Circuito de {} saltos establecido", circuit.hop_count());
+
+    let inner = TaiorPacket::new(b"Hola entre procesos", 3, 64, false)?;
+    let packet = OnionEncryptor::new(&mut circuit).wrap_packet(&inner)?;
+    client.send(&packet, entry).await?;
+    println!("✓ Paquete enviado al relay de entrada ({} bytes)", packet.size());
+
+    let (reply, _) = tokio::time::timeout(DEFAULT_HOP_TIMEOUT, client.receive()).await??;
+    let reply = OnionEncryptor::new(&mut circuit).decrypt_backward(&reply)?;
+    println!("✓ Respuesta: {:?}", String::from_utf8_lossy(&reply.decrypt_with_ikm()?.data));
+
+    Ok(())
+}
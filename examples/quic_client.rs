@@ -24,7 +24,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     for i in 1..=3 {
         let message = format!("Mensaje {} via QUIC", i);
-        let packet = taior.send(message.as_bytes(), SendOptions::fast())?;
+        let packet = taior.send(message.as_bytes(), SendOptions::fast())?
+            .ready()
+            .ok_or("envío en cola")?
+            .packet;
         
         println!("Enviando mensaje {}...", i);
         transport.send(&packet, server_addr).await?;
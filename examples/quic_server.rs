@@ -29,8 +29,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         match transport_conn.receive().await {
                             Ok((packet, addr)) => {
                                 println!("  → Paquete recibido de {}", addr);
-                                println!("    TTL: {}, Tamaño: {} bytes, Cover: {}", 
-                                    packet.ttl, packet.size(), packet.is_cover);
+                                println!("    TTL: {}, Tamaño: {} bytes",
+                                    packet.ttl, packet.size());
                             }
                             Err(e) => {
                                 println!("  ✗ Error recibiendo: {}", e);
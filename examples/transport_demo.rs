@@ -78,7 +78,7 @@ async fn main() {
             
             // Simular envío
             let mut taior = Taior::with_bootstrap(vec!["node1.taior.net".to_string()]);
-            let packet = taior.send(b"Mensaje via relay", SendOptions::mix()).unwrap();
+            let packet = taior.send(b"Mensaje via relay", SendOptions::mix()).unwrap().ready().unwrap().packet;
             let dest_addr = TaiorAddress("taior://destino".to_string());
             
             match client.send_via_relay(&packet, &dest_addr).await {
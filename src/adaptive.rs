@@ -0,0 +1,146 @@
+//! Network-aware configuration for `RoutingMode::Adaptive`. Instead of a fixed
+//! preset, every adaptive send gets as many hops as the known nodes and the
+//! application's latency budget allow, spends what is left of the budget on
+//! jitter, and backs off padding and cover traffic when the network loses packets.
+
+use crate::modes::{ModeConfig, RoutingMode};
+use std::time::Duration;
+
+/// Hops adaptive sends aim for when nothing holds them back, as in Mix mode.
+pub const MAX_ADAPTIVE_HOPS: u8 = 4;
+/// Round trip assumed per hop until one has been measured.
+pub const ASSUMED_RTT: Duration = Duration::from_millis(100);
+/// Jitter bound when the application sets no latency budget.
+pub const DEFAULT_JITTER: Duration = Duration::from_millis(50);
+/// Largest jitter bound, however much budget is left.
+pub const MAX_JITTER: Duration = Duration::from_millis(200);
+/// Cover ratio under good conditions.
+pub const COVER_RATIO: f32 = 0.3;
+/// Loss rate from which packets are padded to the small block size.
+pub const LOSSY: f32 = 0.05;
+/// Loss rate from which no cover traffic is added to the network.
+pub const CONGESTED: f32 = 0.2;
+
+/// What is known about the network when an adaptive send is made.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NetworkConditions {
+    /// Round-trip time to neighbours, when measured.
+    pub rtt: Option<Duration>,
+    /// Fraction of packets lost, from 0.0 to 1.0.
+    pub loss: f32,
+    /// Nodes known through `NodeDiscovery`.
+    pub known_nodes: usize,
+}
+
+/// Configuration chosen for an adaptive send, reported back so that applications
+/// can display it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AdaptiveChoice {
+    pub config: ModeConfig,
+    /// Probability of a cover packet alongside the send, when cover traffic is enabled.
+    pub cover_ratio: f32,
+    /// One-way latency the route is expected to add, jitter included.
+    pub expected_latency: Duration,
+    pub conditions: NetworkConditions,
+}
+
+/// Chooses the configuration of an adaptive send. `max_hops` caps the hops, e.g.
+/// from `SendOptions::hops`; `latency_budget` bounds the expected latency, although
+/// a single hop is always used.
+pub fn choose(conditions: NetworkConditions, max_hops: Option<u8>, latency_budget: Option<Duration>) -> AdaptiveChoice {
+    let hop_latency = conditions.rtt.unwrap_or(ASSUMED_RTT) / 2;
+
+    let mut hops = max_hops
+        .unwrap_or(MAX_ADAPTIVE_HOPS)
+        .min(conditions.known_nodes.min(u8::MAX as usize) as u8)
+        .max(1);
+    if let Some(budget) = latency_budget {
+        while hops > 1 && hop_latency * hops as u32 > budget {
+            hops -= 1;
+        }
+    }
+    let route_latency = hop_latency * hops as u32;
+
+    // Jitter is uniform, so on average it adds half of its bound
+    let jitter = match latency_budget {
+        Some(budget) => budget.saturating_sub(route_latency).min(MAX_JITTER),
+        None => DEFAULT_JITTER,
+    };
+
+    // Smaller packets cost less to lose; longer routes are worth the larger blocks
+    let padding_size = if conditions.loss >= LOSSY || hops < 3 {
+        ModeConfig::adaptive().padding_size
+    } else {
+        ModeConfig::mix().padding_size
+    };
+
+    let cover_ratio = if conditions.loss >= CONGESTED || conditions.known_nodes == 0 {
+        0.0
+    } else {
+        COVER_RATIO * (1.0 - conditions.loss / CONGESTED)
+    };
+
+    AdaptiveChoice {
+        config: ModeConfig {
+            mode: RoutingMode::Adaptive,
+            hops,
+            cover_traffic: cover_ratio > 0.0,
+            jitter_ms: Some(jitter.as_millis() as u64),
+            padding_size,
+        },
+        cover_ratio,
+        expected_latency: route_latency + jitter / 2,
+        conditions,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conditions(rtt_ms: u64, loss: f32, known_nodes: usize) -> NetworkConditions {
+        NetworkConditions {
+            rtt: Some(Duration::from_millis(rtt_ms)),
+            loss,
+            known_nodes,
+        }
+    }
+
+    #[test]
+    fn test_good_network_gets_mix_like_protection() {
+        let choice = choose(conditions(40, 0.0, 20), None, None);
+        assert_eq!(choice.config.hops, MAX_ADAPTIVE_HOPS);
+        assert_eq!(choice.config.padding_size, ModeConfig::mix().padding_size);
+        assert_eq!(choice.config.jitter_ms, Some(DEFAULT_JITTER.as_millis() as u64));
+        assert_eq!(choice.cover_ratio, COVER_RATIO);
+        assert_eq!(choice.expected_latency, Duration::from_millis(4 * 20 + 25));
+    }
+
+    #[test]
+    fn test_budget_and_known_nodes_limit_hops() {
+        let choice = choose(conditions(100, 0.0, 20), None, Some(Duration::from_millis(120)));
+        assert_eq!(choice.config.hops, 2);
+        assert_eq!(choice.config.jitter_ms, Some(20));
+        assert!(choice.expected_latency <= Duration::from_millis(120));
+
+        // A budget below a single hop still sends over one
+        let choice = choose(conditions(400, 0.0, 20), None, Some(Duration::from_millis(10)));
+        assert_eq!(choice.config.hops, 1);
+        assert_eq!(choice.config.jitter_ms, Some(0));
+
+        let choice = choose(conditions(40, 0.0, 2), Some(3), None);
+        assert_eq!(choice.config.hops, 2);
+        assert_eq!(choice.config.padding_size, ModeConfig::adaptive().padding_size);
+    }
+
+    #[test]
+    fn test_loss_backs_off_padding_and_cover() {
+        let lossy = choose(conditions(40, 0.1, 20), None, None);
+        assert_eq!(lossy.config.padding_size, ModeConfig::adaptive().padding_size);
+        assert!(lossy.cover_ratio > 0.0 && lossy.cover_ratio < COVER_RATIO);
+
+        let congested = choose(conditions(40, 0.3, 20), None, None);
+        assert_eq!(congested.cover_ratio, 0.0);
+        assert!(!congested.config.cover_traffic);
+    }
+}
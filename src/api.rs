@@ -1,181 +1,1606 @@
-use crate::cover::CoverTrafficGenerator;
-use crate::circuit::{Circuit, CircuitBuilder, OnionEncryptor, CircuitError};
-use crate::discovery::NodeDiscovery;
+#[cfg(feature = "mix-mode")]
+use crate::cover::{CoverTrafficGenerator, DEFAULT_COVER_RATIO};
+#[cfg(feature = "mix-mode")]
+use crate::cover_traffic::CoverTrafficConfig;
+use crate::circuit::{Circuit, CircuitBuilder, CircuitError, OnionEncryptor, MAX_CIRCUIT_HOPS, MIN_CIRCUIT_HOPS};
+use crate::descriptor::{Capabilities, Endpoint, NodeDescriptor};
+use crate::discovery::{KnownNode, NodeDiscovery};
+use crate::entropy::Entropy;
+use crate::error::TaiorError;
 use crate::identity::{EphemeralIdentity, TaiorAddress};
-use crate::modes::{ModeConfig, RoutingMode};
-use crate::packet::TaiorPacket;
-use crate::routing::Router;
+use crate::metrics::LinkMetrics;
+use crate::modes::{DelayDistribution, ModeConfig, RoutingMode};
+use crate::onion_relay::OnionRelay;
+use crate::packet::{frame_payload, pad_payload_with, padded_len, PacketContents, PacketFormat, TaiorPacket};
+use crate::profile::PrivacyProfile;
+use crate::routing::LocalPolicy;
+use crate::sphinx::{PeeledPacket, SphinxPacket, Surb, MAX_HOPS};
+use std::time::Duration;
+use x25519_dalek::PublicKey;
+#[cfg(feature = "mix-mode")]
+use crate::packet::frame_payload_with_reply;
+#[cfg(feature = "mix-mode")]
+use crate::sphinx::{SphinxError, SphinxHop, SurbSecret, BODY_OVERHEAD};
+#[cfg(feature = "mix-mode")]
+use std::collections::HashMap;
+#[cfg(feature = "mix-mode")]
+use rand_core::RngCore;
+#[cfg(feature = "adaptive-mode")]
+use crate::adaptive::{AdaptiveChoice, NetworkConditions};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::circuit::DEFAULT_HOP_TIMEOUT;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::handshake::CircuitHandshake;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::transport::Transport;
+#[cfg(not(target_arch = "wasm32"))]
+use std::net::SocketAddr;
 
 pub use crate::modes::RoutingMode as Mode;
 
+/// Body size of the reply blocks created by `Taior::create_surb`.
+#[cfg(feature = "mix-mode")]
+pub const SURB_BODY_LEN: usize = 2048;
+
+/// Sends held under `SendPolicy::QueueUntilReady` before further ones are refused.
+pub const MAX_QUEUED_SENDS: usize = 256;
+
 #[derive(Debug, Clone)]
 pub struct SendOptions {
     pub mode: RoutingMode,
     pub hops: Option<u8>,
+    /// Attach a reply block routed over this many relays, so the recipient can answer.
+    pub reply_hops: Option<u8>,
+    /// Latency adaptive sends may add at most; only honoured with the `adaptive-mode`
+    /// feature, which chooses the configuration from network conditions.
+    pub latency_budget: Option<Duration>,
+    /// Configuration pinned by a privacy profile, used instead of the mode's preset.
+    pub config: Option<ModeConfig>,
 }
 
 impl SendOptions {
+    #[cfg(feature = "fast-mode")]
     pub fn fast() -> Self {
         Self {
             mode: RoutingMode::Fast,
             hops: Some(1),
+            reply_hops: None,
+            latency_budget: None,
+            config: None,
         }
     }
 
+    #[cfg(feature = "mix-mode")]
     pub fn mix() -> Self {
         Self {
             mode: RoutingMode::Mix,
             hops: Some(4),
+            reply_hops: None,
+            latency_budget: None,
+            config: None,
         }
     }
 
+    #[cfg(feature = "adaptive-mode")]
     pub fn adaptive() -> Self {
         Self {
             mode: RoutingMode::Adaptive,
-            hops: Some(2),
+            hops: None,
+            reply_hops: None,
+            latency_budget: None,
+            config: None,
         }
     }
 
+    /// Options for `mode`, which sends only accept if it was compiled in (see
+    /// `RoutingMode::is_enabled`).
     pub fn custom(mode: RoutingMode, hops: u8) -> Self {
         Self {
             mode,
             hops: Some(hops),
+            reply_hops: None,
+            latency_budget: None,
+            config: None,
         }
     }
+
+    /// Options for the built-in privacy profile `name`; see `PrivacyProfile::builtin`.
+    pub fn profile(name: &str) -> Result<Self, TaiorError> {
+        let profile = PrivacyProfile::builtin(name)?;
+        profile.validate()?;
+        Ok(Self::from_profile(&profile))
+    }
+
+    /// Options that send with the routing configuration of `profile`.
+    pub fn from_profile(profile: &PrivacyProfile) -> Self {
+        Self {
+            mode: profile.routing.mode,
+            hops: Some(profile.routing.hops),
+            reply_hops: None,
+            latency_budget: None,
+            config: Some(profile.routing.clone()),
+        }
+    }
+
+    /// Attaches a reply block over `hops` relays; see `IncomingMessage::reply`.
+    #[cfg(feature = "mix-mode")]
+    pub fn with_reply(mut self, hops: u8) -> Self {
+        self.reply_hops = Some(hops);
+        self
+    }
+
+    pub fn with_latency_budget(mut self, budget: Duration) -> Self {
+        self.latency_budget = Some(budget);
+        self
+    }
 }
 
 impl Default for SendOptions {
+    /// Adaptive when compiled in, otherwise the most private mode available.
     fn default() -> Self {
-        Self::adaptive()
+        #[cfg(feature = "adaptive-mode")]
+        {
+            Self::adaptive()
+        }
+        #[cfg(all(feature = "mix-mode", not(feature = "adaptive-mode")))]
+        {
+            Self::mix()
+        }
+        #[cfg(not(feature = "mix-mode"))]
+        {
+            Self::fast()
+        }
     }
 }
 
+/// How an incoming message reached us.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageOrigin {
+    /// Delivered to us as the destination (plain, sealed to our address or via Sphinx).
+    Direct,
+    /// Sent back by the last hop of our active circuit.
+    CircuitReply,
+    /// Answer through one of our reply blocks.
+    SurbReply,
+}
+
+/// A real message opened by `Taior::receive`.
+#[derive(Debug, Clone)]
+pub struct IncomingMessage {
+    pub data: Vec<u8>,
+    pub origin: MessageOrigin,
+    /// Reply block attached by the sender; answer with `Taior::reply_with_surb`.
+    pub reply: Option<Surb>,
+}
+
+/// What a send does when fewer hops are available than its options request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SendPolicy {
+    /// Refuse the send with `TaiorError::Policy`.
+    Strict,
+    /// Send with the hops available; `AnonymityReport` tells how many were used.
+    #[default]
+    AllowDegraded,
+    /// Hold the message until `Taior::next_ready` finds enough hops for it.
+    QueueUntilReady,
+}
+
+/// Protection a packet actually got, next to what its options requested.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AnonymityReport {
+    pub requested_hops: u8,
+    /// Relays that remove a layer of encryption; 0 when only the end-to-end
+    /// envelope protects the payload.
+    pub hops: u8,
+    /// Whether a cover packet was generated alongside (see `Taior::take_cover_packets`).
+    pub cover_traffic: bool,
+    /// Block size the payload was padded to.
+    pub padding_size: usize,
+}
+
+impl AnonymityReport {
+    pub fn is_degraded(&self) -> bool {
+        self.hops < self.requested_hops
+    }
+}
+
+/// A packet ready to be transmitted.
+#[derive(Debug, Clone)]
+pub struct OutgoingPacket {
+    /// Node to transmit the packet to. `None` when it is not known by name, e.g. the
+    /// entry of a circuit installed with `Taior::set_circuit`.
+    pub first_hop: Option<String>,
+    pub packet: TaiorPacket,
+    pub anonymity: AnonymityReport,
+    /// How long to hold the packet, and each cover packet sent alongside, before
+    /// transmitting it (see `SendScheduler`).
+    pub delay: DelayDistribution,
+    /// Configuration chosen from network conditions, for adaptive sends.
+    #[cfg(feature = "adaptive-mode")]
+    pub adaptive: Option<AdaptiveChoice>,
+}
+
+#[derive(Debug, Clone)]
+pub enum SendOutcome {
+    Ready(Box<OutgoingPacket>),
+    /// Held back by `SendPolicy::QueueUntilReady`; see `Taior::next_ready`.
+    Queued,
+}
+
+impl SendOutcome {
+    /// The packet to transmit, unless the send was queued.
+    pub fn ready(self) -> Option<OutgoingPacket> {
+        match self {
+            Self::Ready(packet) => Some(*packet),
+            Self::Queued => None,
+        }
+    }
+}
+
+/// A send waiting for enough hops under `SendPolicy::QueueUntilReady`.
+struct QueuedSend {
+    dest: Option<TaiorAddress>,
+    data: Vec<u8>,
+    options: SendOptions,
+}
+
+/// How a packet travels to its first hop, decided once per send so that cover
+/// packets can take exactly the same way.
+enum SendPath {
+    #[cfg(feature = "mix-mode")]
+    Sphinx(Vec<SphinxHop>),
+    Circuit,
+    Direct,
+}
+
+/// Cover sent on a schedule of its own, from the `[cover]` section of a profile.
+#[cfg(feature = "mix-mode")]
+#[derive(Debug, Clone)]
+struct CoverSchedule {
+    cover: CoverTrafficConfig,
+    /// Routing of the profile, so that scheduled cover travels like its real sends.
+    routing: ModeConfig,
+}
+
 pub struct Taior {
     identity: EphemeralIdentity,
-    router: Router,
     discovery: NodeDiscovery,
+    #[cfg(feature = "mix-mode")]
     cover_generator: CoverTrafficGenerator,
+    #[cfg(feature = "mix-mode")]
+    cover_schedule: Option<CoverSchedule>,
     active_circuit: Option<Circuit>,
     circuit_builder: CircuitBuilder,
+    #[cfg(feature = "mix-mode")]
+    pending_cover: Vec<TaiorPacket>,
+    #[cfg(feature = "mix-mode")]
+    surb_secrets: HashMap<[u8; 32], SurbSecret>,
+    send_policy: SendPolicy,
+    queued_sends: Vec<QueuedSend>,
+    /// Conditions set by the application in place of the measured ones.
+    #[cfg(feature = "adaptive-mode")]
+    network: Option<NetworkConditions>,
+    /// Draws the padding of outgoing frames and the entropy of the components.
+    entropy: Entropy,
 }
 
 impl Taior {
     pub fn new() -> Self {
         Self {
             identity: EphemeralIdentity::new(),
-            router: Router::new(),
             discovery: NodeDiscovery::new(),
+            #[cfg(feature = "mix-mode")]
             cover_generator: CoverTrafficGenerator::default(),
+            #[cfg(feature = "mix-mode")]
+            cover_schedule: None,
             active_circuit: None,
-            circuit_builder: CircuitBuilder::new(1, 5, 600),
+            circuit_builder: CircuitBuilder::new(MIN_CIRCUIT_HOPS, MAX_CIRCUIT_HOPS, 600),
+            #[cfg(feature = "mix-mode")]
+            pending_cover: Vec::new(),
+            #[cfg(feature = "mix-mode")]
+            surb_secrets: HashMap::new(),
+            send_policy: SendPolicy::default(),
+            queued_sends: Vec::new(),
+            #[cfg(feature = "adaptive-mode")]
+            network: None,
+            entropy: Entropy::secure(),
         }
     }
 
     pub fn with_bootstrap(bootstrap: Vec<String>) -> Self {
         let mut instance = Self {
             identity: EphemeralIdentity::new(),
-            router: Router::new(),
             discovery: NodeDiscovery::with_bootstrap(bootstrap.clone()),
+            #[cfg(feature = "mix-mode")]
             cover_generator: CoverTrafficGenerator::default(),
+            #[cfg(feature = "mix-mode")]
+            cover_schedule: None,
             active_circuit: None,
-            circuit_builder: CircuitBuilder::new(1, 5, 600),
+            circuit_builder: CircuitBuilder::new(MIN_CIRCUIT_HOPS, MAX_CIRCUIT_HOPS, 600),
+            #[cfg(feature = "mix-mode")]
+            pending_cover: Vec::new(),
+            #[cfg(feature = "mix-mode")]
+            surb_secrets: HashMap::new(),
+            send_policy: SendPolicy::default(),
+            queued_sends: Vec::new(),
+            #[cfg(feature = "adaptive-mode")]
+            network: None,
+            entropy: Entropy::secure(),
         };
 
-        // Register bootstrap nodes in circuit builder
+        // Register bootstrap nodes in circuit builder under the addresses they resolve from
         for node in &bootstrap {
-            let (_, addr) = TaiorAddress::generate();
-            instance.circuit_builder.add_node(node.clone(), addr);
+            if let Some(addr) = instance.discovery.address_of(node) {
+                instance.circuit_builder.add_node(node.clone(), addr.clone());
+            }
         }
 
         instance
     }
 
+    /// Makes routing, circuit construction, padding and cover decisions replay exactly
+    /// from `seed`, for simulations (see `Entropy`). Keys are still drawn at random.
+    #[cfg(feature = "insecure-research")]
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        let mut entropy = Entropy::from_seed(seed);
+        self.circuit_builder.set_entropy(entropy.fork());
+        #[cfg(feature = "mix-mode")]
+        {
+            self.cover_generator = std::mem::take(&mut self.cover_generator).with_entropy(entropy.fork());
+        }
+        self.entropy = entropy;
+        self
+    }
+
     pub fn address(&self) -> &str {
         self.identity.address.as_str()
     }
 
+    pub fn public_key(&self) -> PublicKey {
+        self.identity.public_key
+    }
+
+    /// Signs a descriptor for this instance, whose identity key certifies
+    /// `signing_key`, to publish or `TaiorNode::announce`.
+    pub fn sign_descriptor(
+        &self,
+        signing_key: &ed25519_dalek::SigningKey,
+        endpoints: Vec<Endpoint>,
+        capabilities: Capabilities,
+        version: u32,
+        expires_at: u64,
+    ) -> NodeDescriptor {
+        NodeDescriptor::sign(signing_key, &self.identity.secret, endpoints, capabilities, version, expires_at)
+    }
+
     pub fn add_node(&mut self, node: String) {
-        let (_, addr) = TaiorAddress::generate();
-        self.circuit_builder.add_node(node.clone(), addr);
-        self.discovery.add_node(node);
+        let addr = self.discovery.add_node(node.clone());
+        self.circuit_builder.add_node(node, addr);
     }
 
-    fn ensure_circuit(&mut self, target_hops: usize) -> Result<(), String> {
-        // Rebuild circuit if expired or not present
-        let needs_rebuild = match &self.active_circuit {
-            Some(circuit) => circuit.is_expired(),
-            None => true,
+    /// Registers a relay whose onion key is known. Its address is derived from the key.
+    /// Only such relays can be handshaken with, so only they become circuit hops;
+    /// Mix sends over them use Sphinx packets. Naming the relay by its socket address
+    /// lets `build_circuit` reach it as an entry hop.
+    pub fn add_relay(&mut self, node: String, onion_key: PublicKey) {
+        let addr = self.discovery.add_node_with_key(node.clone(), onion_key);
+        self.circuit_builder.add_node_with_onion_key(node, addr, onion_key);
+    }
+
+    /// Registers a peer reached at `node` whose identity key is known, so it can be
+    /// used as a `send_to` destination. Unlike relays, peers never become circuit hops.
+    pub fn add_peer(&mut self, node: String, public_key: PublicKey) -> TaiorAddress {
+        self.discovery.add_node_with_key(node, public_key)
+    }
+
+    /// Registers a node from its self-signed descriptor, once it verifies. The node
+    /// becomes a destination under the address derived from its onion key, and a
+    /// circuit hop when it offers `Capabilities::RELAY`.
+    pub fn add_descriptor(&mut self, descriptor: NodeDescriptor) -> Result<TaiorAddress, TaiorError> {
+        self.learn_descriptor(descriptor, false)
+    }
+
+    /// Like `add_descriptor`, for a node found on the local network; how such nodes
+    /// are routed through is up to the `LocalPolicy`.
+    pub fn add_local_descriptor(&mut self, descriptor: NodeDescriptor) -> Result<TaiorAddress, TaiorError> {
+        self.learn_descriptor(descriptor, true)
+    }
+
+    fn learn_descriptor(&mut self, descriptor: NodeDescriptor, local: bool) -> Result<TaiorAddress, TaiorError> {
+        let previous = self.discovery.resolve(&descriptor.address()).map(|known| known.node.clone());
+        let address = if local {
+            self.discovery.add_local_descriptor(descriptor.clone())?
+        } else {
+            self.discovery.add_descriptor(descriptor.clone())?
         };
+        if let Some(previous) = previous {
+            self.circuit_builder.remove_node(&previous);
+        }
+        self.circuit_builder.add_descriptor(&descriptor);
+        self.circuit_builder.set_local(&descriptor.node_name(), local);
+        Ok(address)
+    }
 
-        if needs_rebuild {
-            match self.circuit_builder.build_circuit(target_hops) {
-                Ok(circuit) => {
-                    self.active_circuit = Some(circuit);
-                    Ok(())
-                }
-                Err(CircuitError::InsufficientNodes(_)) => {
-                    // Not enough nodes for a circuit — allow direct send with packet encryption only
-                    self.active_circuit = None;
-                    Ok(())
+    /// Whether first hops are kept on, or off, the local network.
+    pub fn set_local_policy(&mut self, policy: LocalPolicy) {
+        self.circuit_builder.router_mut().set_local_policy(policy);
+    }
+
+    /// Nodes known as destinations, with the descriptors they were learned from.
+    pub fn discovery(&self) -> &NodeDiscovery {
+        &self.discovery
+    }
+
+    /// Forgets the nodes whose descriptor has expired, as destinations and as circuit
+    /// hops, and returns their names.
+    pub fn remove_expired_nodes(&mut self) -> Vec<String> {
+        let expired = self.discovery.remove_expired();
+        for node in &expired {
+            self.circuit_builder.remove_node(node);
+        }
+        expired
+    }
+
+    /// Records that `node` was heard from, and its round-trip time when it answered a
+    /// request; see `NodeDiscovery::record_seen`.
+    pub fn record_node_seen(&mut self, node: &str, rtt: Option<Duration>) {
+        self.discovery.record_seen(node, rtt);
+        // A request was answered, which is what link loss is measured by
+        if let Some(rtt) = rtt {
+            let metrics = self.link_metrics_mut();
+            metrics.record_rtt(node, rtt);
+            metrics.record_delivery(node, true);
+        }
+    }
+
+    /// Records that sending `bytes` to `node` took `elapsed`, which ranks its bandwidth.
+    /// Only nodes the instance knows are measured.
+    pub fn record_node_transfer(&mut self, node: &str, bytes: usize, elapsed: Duration) {
+        if self.discovery.address_of(node).is_some() {
+            self.link_metrics_mut().record_transfer(node, bytes, elapsed);
+        }
+    }
+
+    /// Records that `node` failed to take a packet or to answer, which may quarantine it.
+    pub fn record_node_failure(&mut self, node: &str) {
+        self.discovery.record_failure(node);
+    }
+
+    /// Forgets the nodes that kept failing or went silent, as destinations and as
+    /// circuit hops, and returns their names.
+    pub fn evict_stale_nodes(&mut self) -> Vec<String> {
+        let stale = self.discovery.evict_stale();
+        for node in &stale {
+            self.circuit_builder.remove_node(node);
+            self.link_metrics_mut().remove(node);
+        }
+        stale
+    }
+
+    /// Entropy for a component driven alongside the instance, such as the node
+    /// runtime's gossip; seeded when the instance is.
+    pub fn fork_entropy(&mut self) -> Entropy {
+        self.entropy.fork()
+    }
+
+    /// Node behind a destination or relay address, if known.
+    pub fn resolve(&self, address: &TaiorAddress) -> Option<&KnownNode> {
+        self.discovery.resolve(address)
+    }
+
+    /// Address a known node (e.g. a bootstrap entry) resolves from as a destination.
+    pub fn node_address(&self, node: &str) -> Option<&TaiorAddress> {
+        self.discovery.address_of(node)
+    }
+
+    /// Builds a circuit of `hops` relays over `transport`, running the authenticated
+    /// handshake with every hop, and uses it for subsequent sends.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn build_circuit<T: Transport + ?Sized>(&mut self, transport: &mut T, hops: u8) -> Result<(), TaiorError> {
+        let circuit = self.circuit_builder
+            .build_circuit(transport, hops as usize, DEFAULT_HOP_TIMEOUT)
+            .await?;
+        self.active_circuit = Some(circuit);
+        Ok(())
+    }
+
+    /// Starts a circuit of `hops` relays without waiting for the answers, for callers
+    /// that drive their own receive loop (see `crate::node::TaiorNode`). The packet goes
+    /// to the returned entry hop; answers are fed to the handshake, and the finished
+    /// circuit is handed back through `set_circuit`.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn start_circuit(&mut self, hops: u8) -> Result<(SocketAddr, CircuitHandshake, TaiorPacket), TaiorError> {
+        let (entry, path) = self.circuit_builder.prepare_circuit(hops as usize)?;
+        let (handshake, request) = CircuitHandshake::start(path, self.circuit_builder.circuit_ttl())?;
+        Ok((entry, handshake, request))
+    }
+
+    /// Relay engine keyed with this instance's identity, so that the identity address
+    /// doubles as the relay address and packets for either can arrive on one endpoint.
+    pub fn relay(&self) -> OnionRelay {
+        OnionRelay::new(self.identity.secret.clone())
+    }
+
+    /// Uses an already established circuit for subsequent sends.
+    pub fn set_circuit(&mut self, circuit: Circuit) {
+        self.active_circuit = Some(circuit);
+    }
+
+    fn ensure_circuit(&mut self) {
+        // Circuits are only usable while every hop still holds their keys
+        if self.active_circuit.as_ref().is_some_and(|c| c.is_expired()) {
+            self.active_circuit = None;
+        }
+    }
+
+    /// Chooses what sends do when fewer hops are available than their options request.
+    pub fn set_send_policy(&mut self, policy: SendPolicy) {
+        self.send_policy = policy;
+    }
+
+    /// Sends `data` without a destination; the last hop of the route keeps the message.
+    pub fn send(&mut self, data: &[u8], options: SendOptions) -> Result<SendOutcome, TaiorError> {
+        self.dispatch(None, data, options)
+    }
+
+    /// Sends `data` to `dest`, which must resolve through the known nodes to one whose
+    /// key is known (relays, peers and nodes learned from descriptors), as the payload
+    /// is sealed to it. Cover packets drained afterwards with `take_cover_packets` go
+    /// to the same first hop as the returned packet.
+    ///
+    /// Mix sends travel as Sphinx packets over relays with known onion keys. Otherwise
+    /// the active circuit is used, which can only reach its own last hop, or the packet
+    /// goes straight to the destination.
+    pub fn send_to(&mut self, dest: &TaiorAddress, data: &[u8], options: SendOptions) -> Result<SendOutcome, TaiorError> {
+        self.dispatch(Some(dest), data, options)
+    }
+
+    /// Retries the sends held back by `SendPolicy::QueueUntilReady`, e.g. after adding
+    /// relays or building a circuit, and returns the first one that now has enough
+    /// hops. Cover generated alongside it is drained with `take_cover_packets`.
+    pub fn next_ready(&mut self) -> Option<Result<OutgoingPacket, TaiorError>> {
+        for index in 0..self.queued_sends.len() {
+            let QueuedSend { dest, data, options } = &self.queued_sends[index];
+            let (dest, data, options) = (dest.clone(), data.clone(), options.clone());
+            match self.try_send(dest.as_ref(), &data, &options) {
+                Ok(None) => continue,
+                result => {
+                    self.queued_sends.remove(index);
+                    return result.transpose();
                 }
-                Err(e) => Err(format!("Circuit build failed: {}", e)),
             }
-        } else {
-            Ok(())
         }
+        None
+    }
+
+    /// Number of sends waiting for enough hops.
+    pub fn queued_sends(&self) -> usize {
+        self.queued_sends.len()
+    }
+
+    fn dispatch(&mut self, dest: Option<&TaiorAddress>, data: &[u8], options: SendOptions) -> Result<SendOutcome, TaiorError> {
+        if let Some(packet) = self.try_send(dest, data, &options)? {
+            return Ok(SendOutcome::Ready(Box::new(packet)));
+        }
+        if self.queued_sends.len() >= MAX_QUEUED_SENDS {
+            return Err(TaiorError::Policy("send queue is full".to_string()));
+        }
+        self.queued_sends.push(QueuedSend {
+            dest: dest.cloned(),
+            data: data.to_vec(),
+            options,
+        });
+        Ok(SendOutcome::Queued)
     }
 
-    pub fn send(&mut self, data: &[u8], options: SendOptions) -> Result<TaiorPacket, String> {
-        let config = match options.mode {
-            RoutingMode::Fast => ModeConfig::fast(),
-            RoutingMode::Mix => ModeConfig::mix(),
-            RoutingMode::Adaptive => ModeConfig::adaptive(),
+    /// Builds the packet for a send, or returns `None` when the policy holds it back
+    /// until more hops are available.
+    fn try_send(
+        &mut self,
+        dest: Option<&TaiorAddress>,
+        data: &[u8],
+        options: &SendOptions,
+    ) -> Result<Option<OutgoingPacket>, TaiorError> {
+        let destination = match dest {
+            Some(dest) => {
+                let known = self.discovery
+                    .resolve(dest)
+                    .cloned()
+                    .ok_or_else(|| TaiorError::UnknownDestination(dest.clone()))?;
+                // Unsealed packets carry their key in the clear
+                if known.public_key.is_none() {
+                    return Err(TaiorError::Policy(format!("no key is known to seal packets to {}", dest.as_str())));
+                }
+                Some(known)
+            }
+            None => None,
         };
+        let config = mode_config(options)?;
+        // Adaptive sends may trade hops for latency, but the policy and the report go
+        // by what the caller asked for
+        let requested_hops = config.hops;
+        #[cfg(feature = "adaptive-mode")]
+        let adaptive = self.adaptive_choice(options);
+        #[cfg(feature = "adaptive-mode")]
+        let config = adaptive.as_ref().map_or(config, |choice| choice.config.clone());
 
-        let config = if let Some(hops) = options.hops {
-            config.with_hops(hops)
-        } else {
-            config
+        // Drop the circuit for onion encryption once it has expired
+        self.ensure_circuit();
+
+        // The policy is checked before framing so that refused or queued sends do not
+        // use up a reply block
+        let (first_hop, path, hops) = self.plan(dest.zip(destination.as_ref()), &config)?;
+        if hops < requested_hops {
+            match self.send_policy {
+                SendPolicy::Strict => {
+                    return Err(TaiorError::Policy(format!(
+                        "only {} of the {} requested hops are available",
+                        hops, requested_hops
+                    )));
+                }
+                SendPolicy::QueueUntilReady => return Ok(None),
+                SendPolicy::AllowDegraded => {}
+            }
+        }
+
+        let frame = self.frame(data, options, &config)?;
+        let seal_key = self.seal_key(destination.as_ref(), first_hop.as_deref(), &path);
+        let seal = |frame: &[u8]| match seal_key {
+            Some(key) => TaiorPacket::from_frame_for_recipient(frame, &key, config.hops),
+            None => TaiorPacket::from_frame(frame, config.hops),
         };
 
-        if self.cover_generator.should_send_cover() {
-            let _ = self.cover_generator.generate_cover_packet(config.padding_size, config.hops)?;
+        let packet = self.apply_path(&path, seal(&frame)?)?;
+
+        // Cover packets take the same path as real ones so they cannot be told apart on the
+        // wire; padding them to the real frame keeps attached reply blocks from standing out.
+        // A packet with neither a seal nor relay layers carries its key in the clear, which
+        // would give cover away, so none is sent alongside one. Cover is only made once the
+        // real packet is, so none is left behind for a later send to carry off
+        #[cfg(feature = "mix-mode")]
+        let exposed = seal_key.is_none() && matches!(path, SendPath::Direct);
+        #[cfg(feature = "adaptive-mode")]
+        let cover_traffic = match &adaptive {
+            Some(choice) => self.cover_generator.should_send_cover_with(choice.cover_ratio),
+            None => self.cover_generator.should_send_cover(),
+        } && !exposed;
+        #[cfg(all(feature = "mix-mode", not(feature = "adaptive-mode")))]
+        let cover_traffic = self.cover_generator.should_send_cover() && !exposed;
+        #[cfg(feature = "mix-mode")]
+        if cover_traffic {
+            let cover = seal(&self.cover_generator.generate_cover_frame(frame.len())?)?;
+            let cover = self.apply_path(&path, cover)?;
+            self.pending_cover.push(cover);
         }
+        #[cfg(not(feature = "mix-mode"))]
+        let cover_traffic = false;
+
+        Ok(Some(OutgoingPacket {
+            first_hop,
+            packet,
+            anonymity: AnonymityReport {
+                requested_hops,
+                hops,
+                cover_traffic,
+                padding_size: config.padding_size,
+            },
+            delay: config.delay(),
+            #[cfg(feature = "adaptive-mode")]
+            adaptive,
+        }))
+    }
 
+    /// Per-neighbour link measurements the router weighs every hop by, of circuits,
+    /// Sphinx routes and direct sends alike; the node runtime records into them as it
+    /// talks to its neighbours.
+    pub fn link_metrics(&self) -> &LinkMetrics {
+        self.circuit_builder.router().metrics()
+    }
+
+    pub fn link_metrics_mut(&mut self) -> &mut LinkMetrics {
+        self.circuit_builder.router_mut().metrics_mut()
+    }
+
+    /// Overrides the round trip and loss rate adaptive sends choose their hops,
+    /// jitter, padding and cover ratio from, which are otherwise measured.
+    #[cfg(feature = "adaptive-mode")]
+    pub fn set_network_conditions(&mut self, rtt: Option<Duration>, loss: f32) {
+        self.network = Some(NetworkConditions {
+            rtt,
+            loss: loss.clamp(0.0, 1.0),
+            known_nodes: 0,
+        });
+    }
+
+    /// Drops an override set with `set_network_conditions`.
+    #[cfg(feature = "adaptive-mode")]
+    pub fn clear_network_conditions(&mut self) {
+        self.network = None;
+    }
+
+    /// Network conditions adaptive sends are chosen from: the override if one is
+    /// set, otherwise the median round trip and mean loss of the known neighbours
+    /// in `LinkMetrics`.
+    #[cfg(feature = "adaptive-mode")]
+    pub fn network_conditions(&self) -> NetworkConditions {
+        let known_nodes = self.discovery.count();
+        if let Some(network) = self.network {
+            return NetworkConditions { known_nodes, ..network };
+        }
         let neighbors = self.discovery.get_neighbors();
-        let _next_hop = self.router.decide_next_hop(neighbors, &config);
+        let metrics = self.link_metrics();
+        NetworkConditions {
+            rtt: metrics.median_rtt(&neighbors),
+            loss: metrics.mean_loss(&neighbors),
+            known_nodes,
+        }
+    }
 
-        // Build/refresh circuit for onion encryption
-        self.ensure_circuit(config.hops as usize)?;
+    /// The configuration an adaptive send with `options` would get right now.
+    #[cfg(feature = "adaptive-mode")]
+    pub fn adaptive_choice(&self, options: &SendOptions) -> Option<AdaptiveChoice> {
+        if options.mode != RoutingMode::Adaptive || options.config.is_some() {
+            return None;
+        }
+        Some(crate::adaptive::choose(self.network_conditions(), options.hops, options.latency_budget))
+    }
+
+    /// Builds the inner frame of an outgoing message, attaching a fresh reply block
+    /// when the options ask for one.
+    fn frame(&mut self, data: &[u8], options: &SendOptions, config: &ModeConfig) -> Result<Vec<u8>, TaiorError> {
+        let frame = match options.reply_hops {
+            #[cfg(feature = "mix-mode")]
+            Some(hops) => {
+                let surb = self.create_surb(hops)?;
+                frame_payload_with_reply(data, &surb.to_bytes(), 0)?
+            }
+            #[cfg(not(feature = "mix-mode"))]
+            Some(_) => return Err(TaiorError::Policy("reply blocks need the mix-mode feature".to_string())),
+            None => frame_payload(data, false, 0)?,
+        };
+        Ok(pad_payload_with(&frame, padded_len(frame.len(), config.padding_size), &mut self.entropy))
+    }
 
-        // Create base packet with padding
-        let packet = TaiorPacket::new(data, config.hops, config.padding_size, false)?;
+    /// Decides how a send travels: the first hop when it is known by name, the path
+    /// and the number of hops that will remove a layer of encryption.
+    fn plan(
+        &mut self,
+        dest: Option<(&TaiorAddress, &KnownNode)>,
+        config: &ModeConfig,
+    ) -> Result<(Option<String>, SendPath, u8), TaiorError> {
+        // Mixing modes send over relays with known onion keys as fixed-size Sphinx
+        // packets, which need no circuit handshake
+        #[cfg(feature = "mix-mode")]
+        if matches!(config.mode, RoutingMode::Mix | RoutingMode::Adaptive) {
+            match dest {
+                Some((address, destination)) => {
+                    if let Some(onion_key) = destination.public_key {
+                        // The destination takes the last slot of the Sphinx header
+                        let relays = (config.hops as usize).min(MAX_HOPS - 1);
+                        if let Ok(mut route) = self.circuit_builder.select_path(relays) {
+                            route.retain(|hop| hop.address != *address);
+                            let first_hop = match route.first() {
+                                Some(hop) => self.relay_node(&hop.address)?,
+                                None => destination.node.clone(),
+                            };
+                            let hops = route.len() as u8;
+                            route.push(SphinxHop { address: address.clone(), onion_key });
+                            return Ok((Some(first_hop), SendPath::Sphinx(route), hops));
+                        }
+                    }
+                }
+                None => {
+                    if let Ok(route) = self.circuit_builder.select_path(config.hops as usize) {
+                        let first_hop = self.relay_node(&route[0].address)?;
+                        let hops = route.len() as u8;
+                        return Ok((Some(first_hop), SendPath::Sphinx(route), hops));
+                    }
+                }
+            }
+        }
 
-        // Apply onion encryption if circuit is available
         if let Some(circuit) = &self.active_circuit {
-            let encryptor = OnionEncryptor::new(circuit.clone());
-            let onion_encrypted = encryptor.encrypt_onion(&packet.encrypted_payload)
-                .map_err(|e| format!("Onion encryption failed: {}", e))?;
-
-            Ok(TaiorPacket {
-                encrypted_payload: onion_encrypted,
-                ikm: packet.ikm,
-                ttl: packet.ttl,
-                is_cover: packet.is_cover,
-            })
+            if let Some((address, _)) = dest {
+                if circuit.nodes.last().map(|exit| &exit.address) != Some(address) {
+                    return Err(TaiorError::Policy("destination is not the last hop of the active circuit".to_string()));
+                }
+            }
+            // Circuits installed with `set_circuit` may start at a node we have no name for
+            let entry = self.discovery
+                .resolve(&circuit.nodes[0].address)
+                .map(|known| known.node.clone());
+            return Ok((entry, SendPath::Circuit, circuit.hop_count() as u8));
+        }
+
+        // Otherwise the packet keeps its single-layer encryption. Nobody relays such
+        // packets, so one with a destination goes straight to it
+        if let Some((_, destination)) = dest {
+            return Ok((Some(destination.node.clone()), SendPath::Direct, 0));
+        }
+        let router = self.circuit_builder.router_mut();
+        let neighbors = router.eligible_neighbors(self.discovery.get_neighbors(), |node| self.discovery.is_local(node));
+        Ok((router.decide_next_hop(neighbors, config), SendPath::Direct, 0))
+    }
+
+    /// Key the end-to-end envelope is sealed to: the destination's or, for a packet
+    /// without one that takes no relay layers, that of the neighbour it goes to.
+    fn seal_key(&self, destination: Option<&KnownNode>, first_hop: Option<&str>, path: &SendPath) -> Option<PublicKey> {
+        if let Some(destination) = destination {
+            return destination.public_key;
+        }
+        if !matches!(path, SendPath::Direct) {
+            return None;
+        }
+        let address = self.discovery.address_of(first_hop?)?;
+        self.discovery.resolve(address)?.public_key
+    }
+
+    #[cfg(feature = "mix-mode")]
+    fn relay_node(&self, address: &TaiorAddress) -> Result<String, TaiorError> {
+        self.discovery
+            .resolve(address)
+            .map(|known| known.node.clone())
+            .ok_or_else(|| TaiorError::Discovery(format!("unknown relay {}", address.as_str())))
+    }
+
+    fn apply_path(&mut self, path: &SendPath, packet: TaiorPacket) -> Result<TaiorPacket, TaiorError> {
+        match path {
+            #[cfg(feature = "mix-mode")]
+            SendPath::Sphinx(route) => {
+                let inner = packet.encode()?;
+                let body_len = inner.len() + BODY_OVERHEAD;
+                let sphinx = SphinxPacket::build(route, &inner, body_len)?;
+                Ok(sphinx.into_taior_packet(packet.ttl))
+            }
+            SendPath::Circuit => {
+                let circuit = self.active_circuit.as_mut().ok_or(CircuitError::NoActiveCircuit)?;
+                Ok(OnionEncryptor::new(circuit).wrap_packet(&packet)?)
+            }
+            SendPath::Direct => Ok(packet),
+        }
+    }
+
+    /// Opens a packet that reached this instance: a message addressed to us, a reply
+    /// over the active circuit or a reply through one of our reply blocks. Cover
+    /// packets are discarded and yield `None`.
+    pub fn receive(&mut self, packet: &TaiorPacket) -> Result<Option<IncomingMessage>, TaiorError> {
+        let (contents, origin) = match packet.format {
+            PacketFormat::Direct => (self.open_sealed(packet)?, MessageOrigin::Direct),
+            PacketFormat::Onion { .. } => (self.open_reply(packet)?, MessageOrigin::CircuitReply),
+            PacketFormat::Sphinx => {
+                let sphinx = SphinxPacket::from_taior_packet(packet)?;
+                #[cfg(feature = "mix-mode")]
+                if self.surb_secrets.contains_key(&sphinx.alpha) {
+                    let contents = self.open_surb_reply(packet)?;
+                    return self.open_incoming(contents, MessageOrigin::SurbReply);
+                }
+                let PeeledPacket::Deliver { payload } = sphinx.peel(&self.identity.secret)?.peeled else {
+                    return Err(TaiorError::PacketFormat("sphinx packet must be forwarded, not opened".to_string()));
+                };
+                (self.open_sealed(&TaiorPacket::decode(&payload)?)?, MessageOrigin::Direct)
+            }
+            PacketFormat::Control { .. } => {
+                return Err(TaiorError::PacketFormat("control packets are handled by relays".to_string()));
+            }
+        };
+
+        self.open_incoming(contents, origin)
+    }
+
+    /// The message in opened `contents`, or `None` for cover.
+    fn open_incoming(&self, contents: PacketContents, origin: MessageOrigin) -> Result<Option<IncomingMessage>, TaiorError> {
+        if contents.is_cover {
+            return Ok(None);
+        }
+        let reply = contents.reply_block
+            .map(|block| Surb::from_bytes(&block))
+            .transpose()?;
+        Ok(Some(IncomingMessage {
+            data: contents.data,
+            origin,
+            reply,
+        }))
+    }
+
+    /// Whether `packet` answers one of our reply blocks still waiting for it.
+    #[cfg(feature = "mix-mode")]
+    pub(crate) fn is_surb_reply(&self, packet: &TaiorPacket) -> bool {
+        packet.format == PacketFormat::Sphinx
+            && SphinxPacket::from_taior_packet(packet).is_ok_and(|sphinx| self.surb_secrets.contains_key(&sphinx.alpha))
+    }
+
+    /// Decrypts the end-to-end envelope, with our identity secret if it was sealed to us.
+    fn open_sealed(&self, packet: &TaiorPacket) -> Result<PacketContents, TaiorError> {
+        if packet.ephemeral_public.is_some() {
+            packet.decrypt_for_recipient(&self.identity.secret)
         } else {
-            // No circuit available — return packet with single-layer encryption
-            Ok(packet)
+            packet.decrypt_with_ikm()
         }
     }
 
+    /// Drains the cover packets generated alongside previous sends. They are
+    /// meant to be transmitted exactly like the packets returned by `send`.
+    #[cfg(feature = "mix-mode")]
+    pub fn take_cover_packets(&mut self) -> Vec<TaiorPacket> {
+        std::mem::take(&mut self.pending_cover)
+    }
+
+    /// Opens a reply that the last hop of the active circuit sent back over it.
+    /// This gives request/response exchanges in which the responder never learns
+    /// the sender's address.
+    pub fn open_reply(&mut self, packet: &TaiorPacket) -> Result<PacketContents, TaiorError> {
+        let circuit = self.active_circuit.as_mut().ok_or(CircuitError::NoActiveCircuit)?;
+        let reply = OnionEncryptor::new(circuit).decrypt_backward(packet)?;
+        self.open_sealed(&reply)
+    }
+
+    /// Creates a single-use reply block routed over `hops` relays back to this
+    /// instance, to attach to an outgoing message (see `Surb::to_bytes`). The
+    /// recipient answers with `reply_with_surb`; no circuit has to stay open.
+    #[cfg(feature = "mix-mode")]
+    pub fn create_surb(&mut self, hops: u8) -> Result<Surb, TaiorError> {
+        self.surb_secrets.retain(|_, secret| !secret.is_expired());
+
+        let creator = SphinxHop {
+            address: self.identity.address.clone(),
+            onion_key: self.identity.public_key,
+        };
+        let (surb, secret) = self.circuit_builder.build_surb(hops as usize, creator, SURB_BODY_LEN)?;
+        self.surb_secrets.insert(secret.id, secret);
+        Ok(surb)
+    }
+
+    /// Answers through a reply block received from someone else. Returns the node
+    /// the packet must be sent to; the rest of the path stays unknown to us. Any build
+    /// can answer, as the reply block already holds the route chosen by its creator.
+    pub fn reply_with_surb(&self, surb: Surb, data: &[u8]) -> Result<(TaiorAddress, TaiorPacket), TaiorError> {
+        let inner = TaiorPacket::new(data, MAX_HOPS as u8, ModeConfig::mix().padding_size, false)?;
+        let first_hop = surb.first_hop.clone();
+        let packet = surb.into_packet(&inner.encode()?)?;
+        Ok((first_hop, packet.into_taior_packet(MAX_HOPS as u8)))
+    }
+
+    /// Opens a reply sent through one of our reply blocks. Each block is accepted once.
+    #[cfg(feature = "mix-mode")]
+    pub fn open_surb_reply(&mut self, packet: &TaiorPacket) -> Result<PacketContents, TaiorError> {
+        let sphinx = SphinxPacket::from_taior_packet(packet)?;
+        let secret = self.surb_secrets.remove(&sphinx.alpha).ok_or(SphinxError::UnknownSurb)?;
+        let payload = secret.open(&sphinx)?;
+        self.open_sealed(&TaiorPacket::decode(&payload)?)
+    }
+
+    #[cfg(feature = "mix-mode")]
     pub fn enable_cover_traffic(&mut self, enabled: bool, ratio: f32) {
-        self.cover_generator = CoverTrafficGenerator::new(enabled, ratio);
+        self.cover_generator = CoverTrafficGenerator::new(enabled, ratio).with_entropy(self.entropy.fork());
+    }
+
+    /// Applies the cover configuration of `profile`: cover alongside sends when its
+    /// routing asks for it and, with an enabled `[cover]` section, cover on a schedule
+    /// of its own (see `next_cover_delay`). Sends still take their routing from their
+    /// options, e.g. `SendOptions::from_profile`.
+    #[cfg(feature = "mix-mode")]
+    pub fn apply_profile(&mut self, profile: &PrivacyProfile) -> Result<(), TaiorError> {
+        profile.validate()?;
+        self.enable_cover_traffic(profile.routing.cover_traffic, DEFAULT_COVER_RATIO);
+        self.cover_schedule = profile.cover
+            .as_ref()
+            .filter(|cover| cover.enabled && cover.packets_per_second > 0.0)
+            .map(|cover| CoverSchedule { cover: cover.clone(), routing: profile.routing.clone() });
+        Ok(())
+    }
+
+    /// How long until the next scheduled cover packet: the interval of the profile's
+    /// rate, give or take its jitter. `None` when no schedule is set.
+    #[cfg(feature = "mix-mode")]
+    pub fn next_cover_delay(&mut self) -> Option<Duration> {
+        let cover = &self.cover_schedule.as_ref()?.cover;
+        let interval = Duration::try_from_secs_f64(1.0 / cover.packets_per_second).ok()?;
+        let offset = self.entropy.next_u64() % (2 * cover.jitter_ms + 1);
+        Some((interval + Duration::from_millis(offset)).saturating_sub(Duration::from_millis(cover.jitter_ms)))
+    }
+
+    /// Builds a scheduled cover packet over a path planned like the profile's real
+    /// sends, with a dummy payload between the schedule's `min_size` and `max_size`.
+    #[cfg(feature = "mix-mode")]
+    pub fn scheduled_cover(&mut self) -> Result<OutgoingPacket, TaiorError> {
+        let CoverSchedule { cover, routing } = self.cover_schedule
+            .clone()
+            .ok_or_else(|| TaiorError::Policy("no cover schedule is set".to_string()))?;
+        self.ensure_circuit();
+        let (first_hop, path, hops) = self.plan(None, &routing)?;
+        let sealed = match self.seal_key(None, first_hop.as_deref(), &path) {
+            Some(key) => Some(key),
+            None if matches!(path, SendPath::Direct) => {
+                return Err(TaiorError::Policy("no key is known to seal cover to its first hop".to_string()));
+            }
+            None => None,
+        };
+
+        let spread = (cover.max_size - cover.min_size) as u64 + 1;
+        let len = cover.min_size + (self.entropy.next_u64() % spread) as usize;
+        let frame = self.cover_generator.generate_cover_frame_with_len(len, routing.padding_size)?;
+        let packet = match sealed {
+            Some(key) => TaiorPacket::from_frame_for_recipient(&frame, &key, routing.hops)?,
+            None => TaiorPacket::from_frame(&frame, routing.hops)?,
+        };
+        let packet = self.apply_path(&path, packet)?;
+        Ok(OutgoingPacket {
+            first_hop,
+            packet,
+            anonymity: AnonymityReport {
+                requested_hops: routing.hops,
+                hops,
+                cover_traffic: true,
+                padding_size: routing.padding_size,
+            },
+            delay: routing.delay(),
+            #[cfg(feature = "adaptive-mode")]
+            adaptive: None,
+        })
     }
 }
 
+fn mode_config(options: &SendOptions) -> Result<ModeConfig, TaiorError> {
+    if !options.mode.is_enabled() {
+        return Err(TaiorError::Policy(format!("{:?} mode is not compiled in", options.mode)));
+    }
+
+    let config = match (&options.config, options.mode) {
+        (Some(config), _) => config.clone(),
+        (None, RoutingMode::Fast) => ModeConfig::fast(),
+        (None, RoutingMode::Mix) => ModeConfig::mix(),
+        (None, RoutingMode::Adaptive) => ModeConfig::adaptive(),
+    };
+
+    Ok(match options.hops {
+        Some(hops) => config.with_hops(hops),
+        None => config,
+    })
+}
+
 impl Default for Taior {
     fn default() -> Self {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "mix-mode")]
+    #[test]
+    fn test_cover_and_real_packets_match_on_the_wire() {
+        // Without relays the packets go straight to the one neighbour, sealed to it
+        let mut bob = Taior::new();
+        let mut taior = Taior::new();
+        taior.add_peer("10.0.0.2:7000".to_string(), bob.public_key());
+        taior.enable_cover_traffic(true, 1.0);
+
+        let sent = taior.send(b"mensaje", SendOptions::mix()).unwrap().ready().unwrap();
+        assert_eq!(sent.first_hop.as_deref(), Some("10.0.0.2:7000"));
+        let cover = taior.take_cover_packets();
+        assert_eq!(cover.len(), 1);
+
+        // An observer with only the wire bytes can neither open nor tell them apart
+        let real_wire = sent.packet.encode().unwrap();
+        let cover_wire = cover[0].encode().unwrap();
+        assert_eq!(real_wire.len(), cover_wire.len());
+        for wire in [&real_wire, &cover_wire] {
+            let observed = TaiorPacket::decode(wire).unwrap();
+            assert_eq!(observed.format, PacketFormat::Direct);
+            assert!(observed.ephemeral_public.is_some());
+            assert!(observed.decrypt_with_ikm().is_err());
+        }
+
+        assert_eq!(bob.receive(&TaiorPacket::decode(&real_wire).unwrap()).unwrap().unwrap().data, b"mensaje");
+        assert!(bob.receive(&TaiorPacket::decode(&cover_wire).unwrap()).unwrap().is_none());
+
+        // A neighbour without a known key would see the key of both, so no cover goes along
+        let mut taior = Taior::with_bootstrap(vec!["node1.taior.net".to_string()]);
+        taior.enable_cover_traffic(true, 1.0);
+        let sent = taior.send(b"mensaje", SendOptions::mix()).unwrap().ready().unwrap();
+        assert!(!sent.anonymity.cover_traffic);
+        assert!(taior.take_cover_packets().is_empty());
+    }
+
+    #[cfg(all(feature = "insecure-research", feature = "mix-mode"))]
+    #[test]
+    fn test_seeded_instances_replay_their_decisions() {
+        use x25519_dalek::StaticSecret;
+
+        let run = |seed| {
+            let mut taior = Taior::new().with_seed(seed);
+            for i in 0..6 {
+                let secret = StaticSecret::random_from_rng(rand_core::OsRng);
+                taior.add_relay(format!("relay{}.taior.net", i), PublicKey::from(&secret));
+            }
+            taior.enable_cover_traffic(true, 0.5);
+            (0..32)
+                .map(|_| {
+                    let sent = taior.send(b"replay", SendOptions::mix()).unwrap().ready().unwrap();
+                    (sent.first_hop, sent.anonymity.cover_traffic)
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(run(42), run(42));
+        assert_ne!(run(42), run(43));
+    }
+
+    #[cfg(feature = "mix-mode")]
+    #[test]
+    fn test_mix_circuit_with_onion_keys_uses_sphinx() {
+        use crate::packet::PacketFormat;
+        use x25519_dalek::StaticSecret;
+
+        let mut taior = Taior::new();
+        for i in 0..4 {
+            let secret = StaticSecret::random_from_rng(rand_core::OsRng);
+            taior.add_relay(format!("relay{}.taior.net", i), PublicKey::from(&secret));
+        }
+
+        let first = taior.send(b"corto", SendOptions::mix()).unwrap().ready().unwrap().packet;
+        let second = taior.send(&[7u8; 300], SendOptions::mix()).unwrap().ready().unwrap().packet;
+
+        assert_eq!(first.format, PacketFormat::Sphinx);
+        assert_eq!(first.encode().unwrap().len(), second.encode().unwrap().len());
+    }
+
+    #[cfg(feature = "mix-mode")]
+    #[test]
+    fn test_measured_loss_steers_circuits_away() {
+        use x25519_dalek::StaticSecret;
+
+        let mut taior = Taior::new();
+        for i in 0..6 {
+            let secret = StaticSecret::random_from_rng(rand_core::OsRng);
+            taior.add_relay(format!("relay{}.taior.net", i), PublicKey::from(&secret));
+        }
+        let lossy = ["relay0.taior.net", "relay1.taior.net"];
+        for node in lossy {
+            for _ in 0..3 {
+                taior.link_metrics_mut().record_delivery(node, false);
+            }
+        }
+
+        for _ in 0..8 {
+            let sent = taior.send(b"hola", SendOptions::mix()).unwrap().ready().unwrap();
+            let first_hop = sent.first_hop.unwrap();
+            assert!(!lossy.contains(&first_hop.as_str()), "{} was measured as lossy", first_hop);
+        }
+    }
+
+    #[cfg(feature = "mix-mode")]
+    #[test]
+    fn test_profiles_schedule_their_cover() {
+        use x25519_dalek::StaticSecret;
+
+        let mut taior = Taior::new();
+        for i in 0..4 {
+            let secret = StaticSecret::random_from_rng(rand_core::OsRng);
+            taior.add_relay(format!("relay{}.taior.net", i), PublicKey::from(&secret));
+        }
+
+        let mut profile = PrivacyProfile::builtin(crate::profile::HIGH_PRIVACY).unwrap();
+        let cover = profile.cover.take().unwrap();
+        taior.apply_profile(&profile).unwrap();
+        assert!(taior.next_cover_delay().is_none());
+        assert!(taior.scheduled_cover().is_err());
+
+        profile.cover = Some(cover.clone());
+        taior.apply_profile(&profile).unwrap();
+        let interval = Duration::from_secs_f64(1.0 / cover.packets_per_second);
+        let jitter = Duration::from_millis(cover.jitter_ms);
+        let delay = taior.next_cover_delay().unwrap();
+        assert!(delay + jitter >= interval && delay <= interval + jitter);
+
+        let scheduled = taior.scheduled_cover().unwrap();
+        assert!(scheduled.anonymity.cover_traffic);
+        assert_eq!(scheduled.packet.format, crate::packet::PacketFormat::Sphinx);
+        assert_eq!(scheduled.anonymity.hops, profile.routing.hops);
+    }
+
+    /// In-memory relays that know each other's (made-up) endpoints.
+    fn test_relays(count: u8) -> (Vec<crate::onion_relay::OnionRelay>, Vec<std::net::SocketAddr>) {
+        use crate::onion_relay::OnionRelay;
+        use x25519_dalek::StaticSecret;
+
+        let endpoints: Vec<std::net::SocketAddr> = (0..count)
+            .map(|i| format!("10.0.1.{}:9000", i).parse().unwrap())
+            .collect();
+        let mut relays: Vec<OnionRelay> = (0..count)
+            .map(|_| OnionRelay::new(StaticSecret::random_from_rng(rand_core::OsRng)))
+            .collect();
+        let addresses: Vec<_> = relays.iter().map(|r| r.address().clone()).collect();
+        for relay in relays.iter_mut() {
+            for (address, endpoint) in addresses.iter().zip(&endpoints) {
+                relay.add_peer(address.clone(), *endpoint);
+            }
+        }
+        (relays, endpoints)
+    }
+
+    /// Carries `packet`, sent from `from` to `to`, through in-memory relays until it
+    /// is delivered or leaves the relays for an endpoint outside `endpoints`.
+    fn route(
+        relays: &mut [crate::onion_relay::OnionRelay],
+        endpoints: &[std::net::SocketAddr],
+        (from, to): (std::net::SocketAddr, std::net::SocketAddr),
+        packet: TaiorPacket,
+    ) -> crate::onion_relay::RelayAction {
+        use crate::onion_relay::RelayAction;
+
+        let mut at = endpoints.iter().position(|e| *e == to).unwrap();
+        let (mut sender, mut packet) = (from, packet);
+        loop {
+            let action = relays[at].process(packet, sender).unwrap();
+            let next = match &action {
+                RelayAction::Forward { next_hop, .. } => *next_hop,
+                RelayAction::Backward { prev_hop, .. } => *prev_hop,
+                RelayAction::Deliver(_) => return action,
+            };
+            let Some(next_at) = endpoints.iter().position(|e| *e == next) else {
+                return action;
+            };
+            packet = match action {
+                RelayAction::Forward { packet, .. } | RelayAction::Backward { packet, .. } => packet,
+                RelayAction::Deliver(_) => unreachable!(),
+            };
+            sender = endpoints[at];
+            at = next_at;
+        }
+    }
+
+    #[test]
+    fn test_request_and_reply_over_handshaken_circuit() {
+        use crate::handshake::{CircuitHandshake, HandshakeStep};
+        use crate::onion_relay::RelayAction;
+        use crate::sphinx::SphinxHop;
+
+        let client: std::net::SocketAddr = "10.0.0.1:9000".parse().unwrap();
+        let (mut relays, endpoints) = test_relays(3);
+
+        let path = relays
+            .iter()
+            .map(|r| SphinxHop { address: r.address().clone(), onion_key: r.onion_key() })
+            .collect();
+        let (mut handshake, mut request) = CircuitHandshake::start(path, 600).unwrap();
+        let circuit = loop {
+            let RelayAction::Backward { packet, .. } = route(&mut relays, &endpoints, (client, endpoints[0]), request) else {
+                panic!("handshake answers travel backward");
+            };
+            match handshake.handle_reply(&packet).unwrap() {
+                HandshakeStep::Extend(next) => request = next,
+                HandshakeStep::Complete(circuit) => break circuit,
+            }
+        };
+
+        let mut taior = Taior::new();
+        taior.set_circuit(circuit);
+        let packet = taior.send(b"pregunta", SendOptions::default()).unwrap().ready().unwrap().packet;
+
+        let RelayAction::Deliver(delivery) = route(&mut relays, &endpoints, (client, endpoints[0]), packet) else {
+            panic!("request must reach the last hop");
+        };
+        assert_eq!(delivery.packet.decrypt_with_ikm().unwrap().data, b"pregunta");
+
+        let answer = TaiorPacket::new(b"respuesta", 3, 64, false).unwrap();
+        let circuit_id = delivery.circuit_id.unwrap();
+        let RelayAction::Backward { prev_hop, packet } = relays[2].reply(&circuit_id, &answer).unwrap() else {
+            panic!("replies travel backward");
+        };
+        let RelayAction::Backward { packet, .. } = route(&mut relays, &endpoints, (endpoints[2], prev_hop), packet) else {
+            panic!("reply must come back to the sender");
+        };
+
+        assert_eq!(taior.open_reply(&packet).unwrap().data, b"respuesta");
+    }
+
+    #[cfg(feature = "mix-mode")]
+    #[test]
+    fn test_surb_reply_reaches_creator_once() {
+        use crate::onion_relay::RelayAction;
+
+        let (mut relays, endpoints) = test_relays(3);
+        let alice_endpoint: std::net::SocketAddr = "10.0.0.1:9000".parse().unwrap();
+        let mut alice = Taior::new();
+        for (relay, endpoint) in relays.iter_mut().zip(&endpoints) {
+            alice.add_relay(endpoint.to_string(), relay.onion_key());
+            relay.add_peer(TaiorAddress(alice.address().to_string()), alice_endpoint);
+        }
+
+        // Alice attaches a SURB to her message; Bob answers through it without knowing her
+        let surb_bytes = alice.create_surb(3).unwrap().to_bytes();
+        let bob = Taior::new();
+        let (first_hop, reply) = bob
+            .reply_with_surb(Surb::from_bytes(&surb_bytes).unwrap(), b"respuesta")
+            .unwrap();
+        let entry = relays.iter().position(|r| *r.address() == first_hop).unwrap();
+
+        let bob_endpoint: std::net::SocketAddr = "10.0.0.2:9000".parse().unwrap();
+        let RelayAction::Forward { next_hop, packet } =
+            route(&mut relays, &endpoints, (bob_endpoint, endpoints[entry]), reply)
+        else {
+            panic!("the reply must leave the relays towards Alice");
+        };
+        assert_eq!(next_hop, alice_endpoint);
+
+        assert_eq!(alice.open_surb_reply(&packet).unwrap().data, b"respuesta");
+        assert!(alice.open_surb_reply(&packet).is_err());
+    }
+
+    #[cfg(all(feature = "fast-mode", feature = "mix-mode"))]
+    #[test]
+    fn test_two_instances_talk_through_receive() {
+        use crate::onion_relay::RelayAction;
+
+        let (mut relays, endpoints) = test_relays(3);
+        let alice_endpoint: std::net::SocketAddr = "10.0.0.1:9000".parse().unwrap();
+        let bob_endpoint: std::net::SocketAddr = "10.0.0.2:9000".parse().unwrap();
+        let mut alice = Taior::new();
+        for (relay, endpoint) in relays.iter_mut().zip(&endpoints) {
+            alice.add_relay(endpoint.to_string(), relay.onion_key());
+            relay.add_peer(TaiorAddress(alice.address().to_string()), alice_endpoint);
+        }
+        alice.enable_cover_traffic(true, 1.0);
+        let mut bob = Taior::new();
+        let bob_address = alice.add_peer(bob_endpoint.to_string(), bob.public_key());
+
+        let options = SendOptions::fast().with_reply(3);
+        let request = alice.send_to(&bob_address, b"hola bob", options).unwrap().ready().unwrap().packet;
+        let cover = alice.take_cover_packets();
+        assert_eq!(cover[0].encode().unwrap().len(), request.encode().unwrap().len());
+
+        assert!(bob.receive(&cover[0]).unwrap().is_none());
+        let message = bob.receive(&request).unwrap().unwrap();
+        assert_eq!(message.data, b"hola bob");
+        assert_eq!(message.origin, MessageOrigin::Direct);
+
+        let (first_hop, answer) = bob.reply_with_surb(message.reply.unwrap(), b"hola alice").unwrap();
+        let entry = relays.iter().position(|r| *r.address() == first_hop).unwrap();
+        let RelayAction::Forward { packet, .. } =
+            route(&mut relays, &endpoints, (bob_endpoint, endpoints[entry]), answer)
+        else {
+            panic!("the answer must leave the relays towards Alice");
+        };
+
+        let reply = alice.receive(&packet).unwrap().unwrap();
+        assert_eq!(reply.data, b"hola alice");
+        assert_eq!(reply.origin, MessageOrigin::SurbReply);
+        assert!(reply.reply.is_none());
+    }
+
+    #[cfg(feature = "mix-mode")]
+    #[test]
+    fn test_send_to_seals_for_destination_over_sphinx_route() {
+        use crate::onion_relay::RelayAction;
+
+        let (mut relays, endpoints) = test_relays(3);
+        let alice_endpoint: std::net::SocketAddr = "10.0.0.1:9000".parse().unwrap();
+        let bob_endpoint: std::net::SocketAddr = "10.0.0.2:9000".parse().unwrap();
+        let mut bob = Taior::new();
+        let mut alice = Taior::new();
+        for (relay, endpoint) in relays.iter_mut().zip(&endpoints) {
+            alice.add_relay(endpoint.to_string(), relay.onion_key());
+            relay.add_peer(TaiorAddress(bob.address().to_string()), bob_endpoint);
+        }
+        let bob_address = alice.add_peer(bob_endpoint.to_string(), bob.public_key());
+        assert_eq!(bob_address.as_str(), bob.address());
+
+        let options = SendOptions::custom(RoutingMode::Mix, 3);
+        let sent = alice.send_to(&bob_address, b"solo para bob", options).unwrap().ready().unwrap();
+        assert_eq!(sent.anonymity.hops, 3);
+        let (first_hop, packet) = (sent.first_hop.unwrap(), sent.packet);
+        let RelayAction::Forward { next_hop, packet } =
+            route(&mut relays, &endpoints, (alice_endpoint, first_hop.parse().unwrap()), packet)
+        else {
+            panic!("the packet must leave the relays towards Bob");
+        };
+        assert_eq!(next_hop, bob_endpoint);
+
+        let message = bob.receive(&packet).unwrap().unwrap();
+        assert_eq!(message.data, b"solo para bob");
+        assert!(Taior::new().receive(&packet).is_err());
+    }
+
+    #[test]
+    fn test_bootstrap_nodes_resolve_as_destinations() {
+        use x25519_dalek::StaticSecret;
+
+        let bootstrap = (1..=8).map(|i| format!("node{}.taior.net", i)).collect();
+        let mut taior = Taior::with_bootstrap(bootstrap);
+
+        // Bootstrap nodes publish no keys, so nothing can be sealed to them
+        let dest = taior.node_address("node1.taior.net").unwrap().clone();
+        let err = taior.send_to(&dest, b"hola", SendOptions::default()).unwrap_err();
+        assert!(matches!(err, TaiorError::Policy(_)));
+
+        // Once its key is known, a direct packet goes to the node itself
+        let secret = StaticSecret::random_from_rng(rand_core::OsRng);
+        let dest = taior.add_peer("node1.taior.net".to_string(), PublicKey::from(&secret));
+        let sent = taior.send_to(&dest, b"hola", SendOptions::default()).unwrap().ready().unwrap();
+        assert_eq!(sent.first_hop.unwrap(), "node1.taior.net");
+        assert_eq!(sent.packet.decrypt_for_recipient(&secret).unwrap().data, b"hola");
+
+        let (_, unknown) = TaiorAddress::generate();
+        let err = taior.send_to(&unknown, b"hola", SendOptions::default()).unwrap_err();
+        assert!(matches!(err, TaiorError::UnknownDestination(address) if address == unknown));
+
+        // Bootstrap nodes publish no onion keys, so no reply block can be routed over them
+        #[cfg(feature = "mix-mode")]
+        {
+            let err = taior.create_surb(2).unwrap_err();
+            assert!(matches!(err, TaiorError::Circuit(CircuitError::InsufficientNodes(_))));
+        }
+    }
+
+    #[cfg(feature = "mix-mode")]
+    #[test]
+    fn test_send_policy_governs_sends_below_requested_hops() {
+        use x25519_dalek::StaticSecret;
+
+        let options = SendOptions::custom(RoutingMode::Mix, 3);
+        let mut taior = Taior::with_bootstrap(vec!["node1.taior.net".to_string()]);
+
+        let sent = taior.send(b"hola", options.clone()).unwrap().ready().unwrap();
+        assert_eq!(sent.anonymity.hops, 0);
+        assert!(sent.anonymity.is_degraded());
+
+        taior.set_send_policy(SendPolicy::Strict);
+        let err = taior.send(b"hola", options.clone()).unwrap_err();
+        assert!(matches!(err, TaiorError::Policy(_)));
+
+        taior.set_send_policy(SendPolicy::QueueUntilReady);
+        assert!(matches!(taior.send(b"en cola", options).unwrap(), SendOutcome::Queued));
+        assert!(taior.next_ready().is_none());
+        assert_eq!(taior.queued_sends(), 1);
+
+        for i in 0..3 {
+            let secret = StaticSecret::random_from_rng(rand_core::OsRng);
+            taior.add_relay(format!("relay{}.taior.net", i), PublicKey::from(&secret));
+        }
+        let sent = taior.next_ready().unwrap().unwrap();
+        assert_eq!(sent.anonymity.hops, 3);
+        assert!(!sent.anonymity.is_degraded());
+        assert_eq!(sent.packet.format, PacketFormat::Sphinx);
+        assert_eq!(taior.queued_sends(), 0);
+    }
+
+    #[cfg(feature = "adaptive-mode")]
+    #[test]
+    fn test_adaptive_send_reports_configuration_chosen_from_network() {
+        use x25519_dalek::StaticSecret;
+
+        let mut taior = Taior::new();
+        for i in 0..6 {
+            let secret = StaticSecret::random_from_rng(rand_core::OsRng);
+            taior.add_relay(format!("relay{}.taior.net", i), PublicKey::from(&secret));
+        }
+        taior.set_network_conditions(Some(Duration::from_millis(100)), 0.0);
+
+        let options = SendOptions::adaptive().with_latency_budget(Duration::from_millis(120));
+        let sent = taior.send(b"hola", options.clone()).unwrap().ready().unwrap();
+        let choice = sent.adaptive.unwrap();
+        assert_eq!(choice.config.hops, 2);
+        assert_eq!(choice.conditions.known_nodes, 6);
+        assert!(choice.expected_latency <= Duration::from_millis(120));
+        assert_eq!(sent.anonymity.hops, 2);
+        assert_eq!(sent.packet.format, PacketFormat::Sphinx);
+        assert_eq!(taior.adaptive_choice(&options), Some(choice));
+
+        // Without a budget the route grows to the adaptive maximum
+        let sent = taior.send(b"hola", SendOptions::adaptive()).unwrap().ready().unwrap();
+        assert_eq!(sent.anonymity.hops, crate::adaptive::MAX_ADAPTIVE_HOPS);
+        assert!(taior.send(b"hola", SendOptions::mix()).unwrap().ready().unwrap().adaptive.is_none());
+
+        // A budget that only fits one hop degrades a send that asked for three
+        let mut options = SendOptions::adaptive().with_latency_budget(Duration::from_millis(60));
+        options.hops = Some(3);
+        let sent = taior.send(b"hola", options.clone()).unwrap().ready().unwrap();
+        assert_eq!((sent.anonymity.hops, sent.anonymity.requested_hops), (1, 3));
+        assert!(sent.anonymity.is_degraded());
+        taior.set_send_policy(SendPolicy::Strict);
+        assert!(matches!(taior.send(b"hola", options).unwrap_err(), TaiorError::Policy(_)));
+    }
+
+    #[cfg(feature = "adaptive-mode")]
+    #[test]
+    fn test_measured_links_drive_the_adaptive_choice() {
+        use x25519_dalek::StaticSecret;
+
+        let mut taior = Taior::new();
+        for i in 0..6 {
+            let secret = StaticSecret::random_from_rng(rand_core::OsRng);
+            taior.add_relay(format!("relay{}.taior.net", i), PublicKey::from(&secret));
+        }
+        let options = SendOptions::adaptive().with_latency_budget(Duration::from_millis(120));
+        let choice = taior.adaptive_choice(&options).unwrap();
+        assert_eq!(choice.conditions.rtt, None);
+        assert_eq!(choice.config.hops, 2);
+        assert!(choice.cover_ratio > 0.0);
+
+        // Slow, lossy neighbours shorten the route and turn cover traffic off
+        for neighbor in taior.discovery.get_neighbors() {
+            taior.link_metrics_mut().record_rtt(&neighbor, Duration::from_millis(300));
+            for _ in 0..3 {
+                taior.link_metrics_mut().record_delivery(&neighbor, false);
+            }
+        }
+        let choice = taior.adaptive_choice(&options).unwrap();
+        assert_eq!(choice.conditions.rtt, Some(Duration::from_millis(300)));
+        assert!(choice.conditions.loss >= crate::adaptive::CONGESTED);
+        assert_eq!(choice.config.hops, 1);
+        assert_eq!(choice.cover_ratio, 0.0);
+
+        // The manual setter overrides the measurements until it is cleared
+        taior.set_network_conditions(Some(Duration::from_millis(100)), 0.0);
+        assert_eq!(taior.adaptive_choice(&options).unwrap().config.hops, 2);
+        taior.clear_network_conditions();
+        assert_eq!(taior.adaptive_choice(&options).unwrap().config.hops, 1);
+    }
+
+    #[test]
+    fn test_descriptors_register_verified_nodes() {
+        use crate::descriptor::DescriptorError;
+        use ed25519_dalek::SigningKey;
+
+        let publish = |signing_key: &SigningKey, node: &Taior, port: u16, capabilities, version| {
+            let endpoint = Endpoint::Quic(std::net::SocketAddr::from(([10, 0, 0, 1], port)));
+            let expires_at = crate::circuit::current_timestamp() + 600;
+            node.sign_descriptor(signing_key, vec![endpoint], capabilities, version, expires_at)
+        };
+        let node = || (SigningKey::generate(&mut rand_core::OsRng), Taior::new());
+
+        let mut taior = Taior::new();
+        for port in 0..3 {
+            let (signing_key, node) = node();
+            let address = taior.add_descriptor(publish(&signing_key, &node, port, Capabilities::RELAY, 1)).unwrap();
+            assert_eq!(address.as_str(), node.address());
+            assert_eq!(taior.resolve(&address).unwrap().node, format!("10.0.0.1:{}", port));
+        }
+        let (signing_key, peer_node) = node();
+        let peer = taior.add_descriptor(publish(&signing_key, &peer_node, 10, Capabilities::NONE, 1)).unwrap();
+        assert!(taior.resolve(&peer).unwrap().descriptor.is_some());
+
+        // Only the nodes offering to relay become hops
+        taior.circuit_builder.select_path(3).unwrap();
+        assert!(taior.circuit_builder.select_path(4).is_err());
+
+        // A newer revision moves the node, an older one is refused
+        let moved = publish(&signing_key, &peer_node, 11, Capabilities::RELAY, 2);
+        taior.add_descriptor(moved.clone()).unwrap();
+        assert_eq!(taior.resolve(&peer).unwrap().node, "10.0.0.1:11");
+        assert!(taior.node_address("10.0.0.1:10").is_none());
+        taior.circuit_builder.select_path(4).unwrap();
+        let err = taior.add_descriptor(publish(&signing_key, &peer_node, 12, Capabilities::RELAY, 1)).unwrap_err();
+        assert!(matches!(err, TaiorError::Descriptor(DescriptorError::Stale(1, 2))));
+
+        // The same revision is taken again only as it is
+        taior.add_descriptor(moved).unwrap();
+        let err = taior.add_descriptor(publish(&signing_key, &peer_node, 13, Capabilities::RELAY, 2)).unwrap_err();
+        assert!(matches!(err, TaiorError::Descriptor(DescriptorError::Conflict(2))));
+        assert_eq!(taior.resolve(&peer).unwrap().node, "10.0.0.1:11");
+
+        // Someone else cannot take over the peer's address with a higher revision
+        let (attacker_key, attacker) = node();
+        let mut forged = publish(&attacker_key, &attacker, 14, Capabilities::RELAY, u32::MAX);
+        forged.onion_key = peer_node.public_key();
+        let err = taior.add_descriptor(forged).unwrap_err();
+        assert!(matches!(err, TaiorError::Descriptor(DescriptorError::InvalidCertificate)));
+        assert_eq!(taior.resolve(&peer).unwrap().node, "10.0.0.1:11");
+    }
+
+    #[test]
+    fn test_only_compiled_modes_are_accepted() {
+        let mut taior = Taior::with_bootstrap(vec!["node1.taior.net".to_string()]);
+        for mode in [RoutingMode::Fast, RoutingMode::Mix, RoutingMode::Adaptive] {
+            let result = taior.send(b"hola", SendOptions::custom(mode, 1));
+            assert_eq!(result.is_ok(), mode.is_enabled());
+            if let Err(e) = result {
+                assert!(matches!(e, TaiorError::Policy(_)));
+            }
+        }
+        assert!(SendOptions::default().mode.is_enabled());
+    }
+}
@@ -1,13 +1,52 @@
+use crate::descriptor::{Capabilities, NodeDescriptor};
+use crate::entropy::Entropy;
 use crate::identity::TaiorAddress;
+use crate::packet::{PacketFormat, TaiorPacket, CIRCUIT_ID_LEN};
 use crate::routing::Router;
+use crate::sphinx::SphinxHop;
+#[cfg(feature = "mix-mode")]
+use crate::sphinx::{Surb, SurbSecret};
 use chacha20poly1305::{aead::Aead, aead::KeyInit, ChaCha20Poly1305, Key, Nonce};
 use rand_core::{OsRng, RngCore};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use thiserror::Error;
+use x25519_dalek::PublicKey;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::transport::Transport;
+#[cfg(not(target_arch = "wasm32"))]
+use std::{net::SocketAddr, time::Duration};
 #[cfg(all(target_arch = "wasm32", feature = "wasm"))]
 use js_sys;
 
+/// Routing header at the front of every decrypted onion layer:
+/// `[command: 1][next hop address: 32][next hop circuit id: 16]`.
+pub const LAYER_HEADER_LEN: usize = 1 + 32 + CIRCUIT_ID_LEN;
+const LAYER_FORWARD: u8 = 0x01;
+const LAYER_DELIVER: u8 = 0x02;
+const LAYER_EXTEND: u8 = 0x03;
+
+/// Every layer starts with a tag from which only the receiving hop can recover the
+/// cell's sequence number: `[sequence tag: 16][ciphertext]`.
+pub const SEQUENCE_TAG_LEN: usize = 16;
+/// Sequence numbers a receiver looks ahead of the next expected one, so lost cells don't stall the circuit.
+const SEQUENCE_LOOKAHEAD: u64 = 32;
+/// Sequence numbers behind the next expected one still recognised, so stale cells are reported as such.
+const SEQUENCE_LOOKBEHIND: u64 = 32;
+const CONTEXT_SEQUENCE_TAG: &str = "taior circuit v1 sequence tag";
+
+/// First byte of what reaches the originator once every backward layer is removed:
+/// the answer to a circuit extension, or a reply from the last hop.
+pub const BACKWARD_EXTENDED: u8 = 0x01;
+pub const BACKWARD_REPLY: u8 = 0x02;
+
+/// How long a circuit extension may go unanswered before `HopTimeout` is raised.
+pub const DEFAULT_HOP_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+/// Fewest hops of the circuits and routes `Taior` builds.
+pub const MIN_CIRCUIT_HOPS: usize = 1;
+/// Most hops of the circuits and routes `Taior` builds.
+pub const MAX_CIRCUIT_HOPS: usize = 5;
+
 #[derive(Error, Debug)]
 pub enum CircuitError {
     #[error("No hay suficientes nodos para construir circuito de {0} hops")]
@@ -18,13 +57,89 @@ pub enum CircuitError {
     CircuitExpired,
     #[error("Hop {0} no responde")]
     HopTimeout(usize),
+    #[error("Capa onion malformada: {0}")]
+    MalformedLayer(String),
+    #[error("Handshake fallido: {0}")]
+    HandshakeFailed(String),
+    #[error("Sin endpoint conocido para el nodo {0}")]
+    NoEndpoint(String),
+    #[error("Error de transporte: {0}")]
+    Transport(String),
+    #[error("Celda {0} repetida o fuera de orden")]
+    ReplayedCell(u64),
+    #[error("Secuencia de celda no reconocida")]
+    UnknownSequence,
+    #[error("No hay circuito activo")]
+    NoActiveCircuit,
+}
+
+/// What a hop must do with the rest of a layer once it has removed its encryption.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LayerCommand {
+    Forward {
+        next_hop: TaiorAddress,
+        circuit_id: [u8; CIRCUIT_ID_LEN],
+    },
+    Deliver,
+    /// Hand the rest of the layer (a handshake request) to `next_hop` and
+    /// extend the circuit to it under `circuit_id`.
+    Extend {
+        next_hop: TaiorAddress,
+        circuit_id: [u8; CIRCUIT_ID_LEN],
+    },
+}
+
+/// Keys a hop shares with the circuit originator after the handshake. Forward
+/// keys protect layers travelling away from the originator, backward keys the
+/// layers added on the way back.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HopKeys {
+    pub forward_key: [u8; 32],
+    pub forward_nonce: [u8; 12],
+    pub backward_key: [u8; 32],
+    pub backward_nonce: [u8; 12],
+}
+
+/// Receive-side sequence state for one direction of one hop. Cells must arrive in
+/// increasing order; gaps left by lost cells are skipped, while anything at or
+/// below the last accepted number is rejected.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SequenceWindow {
+    next: u64,
+}
+
+impl SequenceWindow {
+    /// Lowest sequence number that would still be accepted.
+    pub fn next_expected(&self) -> u64 {
+        self.next
+    }
+
+    fn locate(&self, key: &[u8; 32], tag: &[u8]) -> Result<u64, CircuitError> {
+        let start = self.next.saturating_sub(SEQUENCE_LOOKBEHIND);
+        match find_sequence(key, tag, start..self.next + SEQUENCE_LOOKAHEAD) {
+            Some(seq) if seq < self.next => Err(CircuitError::ReplayedCell(seq)),
+            Some(seq) => Ok(seq),
+            None => Err(CircuitError::UnknownSequence),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CircuitNode {
     pub address: TaiorAddress,
-    pub shared_key: Vec<u8>,
-    pub nonce: Vec<u8>,
+    /// Identifier this hop files the circuit under. Differs per hop so
+    /// packets cannot be linked across hops by their header.
+    pub circuit_id: [u8; CIRCUIT_ID_LEN],
+    /// Keys agreed with this hop through the circuit handshake.
+    pub keys: HopKeys,
+    /// Sequence number of the next forward cell sent to this hop.
+    #[serde(default)]
+    pub forward_seq: u64,
+    /// Backward cells accepted from this hop so far.
+    #[serde(default)]
+    pub backward_window: SequenceWindow,
+    /// Long-lived X25519 onion key the handshake was authenticated against.
+    pub onion_key: PublicKey,
 }
 
 #[derive(Debug, Clone)]
@@ -60,6 +175,10 @@ impl Circuit {
 pub struct CircuitBuilder {
     router: Router,
     available_nodes: HashMap<String, TaiorAddress>,
+    onion_keys: HashMap<String, PublicKey>,
+    /// Nodes found on the local network, which the router's `LocalPolicy` weighs
+    /// when it picks the hop next to us.
+    local_nodes: HashSet<String>,
     min_hops: usize,
     max_hops: usize,
     circuit_ttl: u64,
@@ -70,40 +189,144 @@ impl CircuitBuilder {
         Self {
             router: Router::new(),
             available_nodes: HashMap::new(),
+            onion_keys: HashMap::new(),
+            local_nodes: HashSet::new(),
             min_hops,
             max_hops,
             circuit_ttl,
         }
     }
 
+    /// Registers a node without an onion key. Such nodes cannot be handshaken
+    /// with, so they are never selected as circuit hops.
     pub fn add_node(&mut self, id: String, address: TaiorAddress) {
         self.available_nodes.insert(id, address);
     }
 
+    /// Registers a node together with its onion key, making it eligible as a circuit hop.
+    /// When `id` is a socket address it is also where the node is reached as an entry hop.
+    pub fn add_node_with_onion_key(&mut self, id: String, address: TaiorAddress, onion_key: PublicKey) {
+        self.onion_keys.insert(id.clone(), onion_key);
+        self.available_nodes.insert(id, address);
+    }
+
+    /// Registers a node from its verified descriptor, under its discovery name. Only
+    /// nodes that offer `Capabilities::RELAY` become eligible as circuit hops.
+    pub fn add_descriptor(&mut self, descriptor: &NodeDescriptor) {
+        let id = descriptor.node_name();
+        if descriptor.capabilities.contains(Capabilities::RELAY) {
+            self.add_node_with_onion_key(id, descriptor.address(), descriptor.onion_key);
+        } else {
+            self.onion_keys.remove(&id);
+            self.add_node(id, descriptor.address());
+        }
+    }
+
     pub fn remove_node(&mut self, id: &str) {
         self.available_nodes.remove(id);
+        self.onion_keys.remove(id);
+        self.local_nodes.remove(id);
+    }
+
+    /// Tags `id` as found, or not, on the local network.
+    pub fn set_local(&mut self, id: &str, local: bool) {
+        if local {
+            self.local_nodes.insert(id.to_string());
+        } else {
+            self.local_nodes.remove(id);
+        }
+    }
+
+    /// Draws the hops of further paths from `entropy`.
+    pub fn set_entropy(&mut self, entropy: Entropy) {
+        self.router.set_entropy(entropy);
+    }
+
+    /// Router the hops are chosen by, with the link measurements it weighs them by.
+    pub fn router(&self) -> &Router {
+        &self.router
+    }
+
+    pub fn router_mut(&mut self) -> &mut Router {
+        &mut self.router
+    }
+
+    pub fn circuit_ttl(&self) -> u64 {
+        self.circuit_ttl
+    }
+
+    /// Picks the hops of a new route among the nodes with a known onion key.
+    pub fn select_path(&mut self, target_hops: usize) -> Result<Vec<SphinxHop>, CircuitError> {
+        Ok(self.select_nodes(target_hops)?.into_iter().map(|(_, hop)| hop).collect())
     }
 
-    pub fn build_circuit(&mut self, target_hops: usize) -> Result<Circuit, CircuitError> {
+    /// Builds a single-use reply block routed over `hops` relays back to `creator`.
+    /// It expires when a circuit built now would.
+    #[cfg(feature = "mix-mode")]
+    pub fn build_surb(
+        &mut self,
+        hops: usize,
+        creator: SphinxHop,
+        body_len: usize,
+    ) -> Result<(Surb, SurbSecret), CircuitError> {
+        // The hop next to the creator is the one that sees it, so the local policy
+        // picks the last relay rather than the first
+        let mut route = self.select_path(hops)?;
+        route.reverse();
+        route.push(creator);
+        Surb::build(&route, body_len, current_timestamp() + self.circuit_ttl)
+            .map_err(|e| CircuitError::EncryptionError(e.to_string()))
+    }
+
+    /// Selects a path and extends a circuit along it one hop at a time, running the
+    /// authenticated handshake with every hop through the ones already established.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn build_circuit<T: Transport + ?Sized>(
+        &mut self,
+        transport: &mut T,
+        target_hops: usize,
+        hop_timeout: Duration,
+    ) -> Result<Circuit, CircuitError> {
+        let (entry, path) = self.prepare_circuit(target_hops)?;
+        crate::handshake::establish_circuit(transport, entry, path, self.circuit_ttl, hop_timeout).await
+    }
+
+    /// Selects the path of a new circuit and the endpoint its entry hop is reached at.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn prepare_circuit(&mut self, target_hops: usize) -> Result<(SocketAddr, Vec<SphinxHop>), CircuitError> {
+        let selected = self.select_nodes(target_hops)?;
+        let entry_id = &selected[0].0;
+        let entry: SocketAddr = entry_id
+            .parse()
+            .map_err(|_| CircuitError::NoEndpoint(entry_id.clone()))?;
+        Ok((entry, selected.into_iter().map(|(_, hop)| hop).collect()))
+    }
+
+    fn select_nodes(&mut self, target_hops: usize) -> Result<Vec<(String, SphinxHop)>, CircuitError> {
         if target_hops < self.min_hops {
             return Err(CircuitError::InsufficientNodes(self.min_hops));
         }
 
         let hops = target_hops.min(self.max_hops);
         
-        if self.available_nodes.len() < hops {
+        if self.onion_keys.len() < hops {
             return Err(CircuitError::InsufficientNodes(hops));
         }
 
-        let mut circuit_nodes = Vec::with_capacity(hops);
+        let mut selected = Vec::with_capacity(hops);
         let mut used_nodes: Vec<String> = Vec::new();
 
         for _ in 0..hops {
-            let available: Vec<String> = self.available_nodes
+            let mut available: Vec<String> = self.onion_keys
                 .keys()
                 .filter(|k| !used_nodes.contains(k))
                 .cloned()
                 .collect();
+            // Only the entry sees our address, so only it is kept on or off the LAN
+            if selected.is_empty() {
+                let local_nodes = &self.local_nodes;
+                available = self.router.eligible_neighbors(available, |node| local_nodes.contains(node));
+            }
 
             if available.is_empty() {
                 return Err(CircuitError::InsufficientNodes(hops));
@@ -114,7 +337,7 @@ impl CircuitBuilder {
             } else {
                 let mode_config = crate::modes::ModeConfig {
                     mode: crate::modes::RoutingMode::Adaptive,
-                    hops: (hops - circuit_nodes.len()) as u8,
+                    hops: (hops - selected.len()) as u8,
                     cover_traffic: false,
                     jitter_ms: None,
                     padding_size: 0,
@@ -128,80 +351,252 @@ impl CircuitBuilder {
             let address = self.available_nodes.get(&next_hop)
                 .ok_or_else(|| CircuitError::InsufficientNodes(hops))?
                 .clone();
+            let onion_key = self.onion_keys[&next_hop];
 
-            let (shared_key, nonce) = generate_hop_keys();
-
-            circuit_nodes.push(CircuitNode {
-                address,
-                shared_key,
-                nonce,
-            });
-
+            selected.push((next_hop.clone(), SphinxHop { address, onion_key }));
             used_nodes.push(next_hop);
         }
 
-        Ok(Circuit::new(circuit_nodes, self.circuit_ttl))
+        Ok(selected)
     }
 }
 
-pub struct OnionEncryptor {
-    circuit: Circuit,
+/// Onion-encrypts over a circuit, advancing its per-hop sequence state with every cell.
+pub struct OnionEncryptor<'a> {
+    circuit: &'a mut Circuit,
 }
 
-impl OnionEncryptor {
-    pub fn new(circuit: Circuit) -> Self {
+impl<'a> OnionEncryptor<'a> {
+    pub fn new(circuit: &'a mut Circuit) -> Self {
         Self { circuit }
     }
 
-    pub fn encrypt_onion(&self, payload: &[u8]) -> Result<Vec<u8>, CircuitError> {
+    pub fn encrypt_onion(&mut self, payload: &[u8]) -> Result<Vec<u8>, CircuitError> {
         if self.circuit.is_expired() {
             return Err(CircuitError::CircuitExpired);
         }
 
-        let mut encrypted = payload.to_vec();
-
-        for node in self.circuit.nodes.iter().rev() {
-            encrypted = self.encrypt_layer(&encrypted, node)?;
-        }
-
-        Ok(encrypted)
+        build_layers(&mut self.circuit.nodes, LayerCommand::Deliver, payload)
     }
 
-    fn encrypt_layer(&self, data: &[u8], node: &CircuitNode) -> Result<Vec<u8>, CircuitError> {
-        let key = Key::from_slice(&node.shared_key);
-        let nonce = Nonce::from_slice(&node.nonce);
-        let cipher = ChaCha20Poly1305::new(key);
-
-        cipher
-            .encrypt(nonce, data)
-            .map_err(|e| CircuitError::EncryptionError(format!("{:?}", e)))
+    /// Onion-wraps an encoded end-to-end packet for transmission to the first hop.
+    /// The outer TTL equals the hop count; each relay decrements it.
+    pub fn wrap_packet(&mut self, inner: &TaiorPacket) -> Result<TaiorPacket, CircuitError> {
+        let first_circuit_id = self.circuit.nodes.first()
+            .ok_or(CircuitError::InsufficientNodes(1))?
+            .circuit_id;
+        let encoded = inner.encode().map_err(|e| CircuitError::EncryptionError(e.to_string()))?;
+
+        Ok(TaiorPacket {
+            encrypted_payload: self.encrypt_onion(&encoded)?,
+            ikm: Vec::new(),
+            ephemeral_public: None,
+            ttl: self.circuit.hop_count() as u8,
+            format: PacketFormat::Onion { circuit_id: first_circuit_id },
+        })
     }
 
+    /// Removes the forward layer of `hop_index` from a cell recently sent over this circuit.
     pub fn decrypt_layer(&self, data: &[u8], hop_index: usize) -> Result<Vec<u8>, CircuitError> {
         if hop_index >= self.circuit.nodes.len() {
             return Err(CircuitError::EncryptionError("Hop index fuera de rango".into()));
         }
 
         let node = &self.circuit.nodes[hop_index];
-        let key = Key::from_slice(&node.shared_key);
-        let nonce = Nonce::from_slice(&node.nonce);
-        let cipher = ChaCha20Poly1305::new(key);
+        if data.len() < SEQUENCE_TAG_LEN {
+            return Err(CircuitError::MalformedLayer("celda demasiado corta".into()));
+        }
+        let sent = node.forward_seq.saturating_sub(SEQUENCE_LOOKBEHIND)..node.forward_seq;
+        let seq = find_sequence(&node.keys.forward_key, &data[..SEQUENCE_TAG_LEN], sent)
+            .ok_or(CircuitError::UnknownSequence)?;
+        open(
+            &node.keys.forward_key,
+            &cell_nonce(&node.keys.forward_nonce, seq),
+            &data[SEQUENCE_TAG_LEN..],
+        )
+    }
+
+    /// Removes every hop's backward layer from a cell the last hop sent back over
+    /// this circuit and returns the reply packet it carried. The replying hop never
+    /// learns who the originator is.
+    pub fn decrypt_backward(&mut self, packet: &TaiorPacket) -> Result<TaiorPacket, CircuitError> {
+        let entry = self.circuit.nodes.first()
+            .ok_or(CircuitError::InsufficientNodes(1))?;
+        if packet.format != (PacketFormat::Onion { circuit_id: entry.circuit_id }) {
+            return Err(CircuitError::MalformedLayer("celda de otro circuito".into()));
+        }
+
+        let plaintext = remove_backward_layers(&mut self.circuit.nodes, &packet.encrypted_payload)?;
+        match plaintext.split_first() {
+            Some((&BACKWARD_REPLY, reply)) => TaiorPacket::decode(reply).map_err(|e| CircuitError::MalformedLayer(e.to_string())),
+            _ => Err(CircuitError::MalformedLayer("celda de vuelta desconocida".into())),
+        }
+    }
+}
+
+/// Wraps `payload` in one layer per node, innermost first. The last node gets
+/// `innermost` as its command; every other node is told to forward to its successor.
+pub(crate) fn build_layers(
+    nodes: &mut [CircuitNode],
+    innermost: LayerCommand,
+    payload: &[u8],
+) -> Result<Vec<u8>, CircuitError> {
+    let mut encrypted = payload.to_vec();
+    let mut next: Option<(TaiorAddress, [u8; CIRCUIT_ID_LEN])> = None;
+
+    for node in nodes.iter_mut().rev() {
+        let command = match next.take() {
+            Some((next_hop, circuit_id)) => LayerCommand::Forward { next_hop, circuit_id },
+            None => innermost.clone(),
+        };
+        let mut layer = encode_layer_header(&command)?;
+        layer.extend_from_slice(&encrypted);
+        encrypted = seal_cell(&node.keys.forward_key, &node.keys.forward_nonce, node.forward_seq, &layer)?;
+        node.forward_seq += 1;
+        next = Some((node.address.clone(), node.circuit_id));
+    }
+
+    Ok(encrypted)
+}
+
+/// Relay-side counterpart of `build_layers`: removes one layer with the hop's
+/// forward key and splits off the routing header. `window` rejects replayed and
+/// out-of-order cells and advances past the accepted one.
+pub fn peel_layer(
+    keys: &HopKeys,
+    window: &mut SequenceWindow,
+    data: &[u8],
+) -> Result<(LayerCommand, Vec<u8>), CircuitError> {
+    let mut plaintext = open_cell(&keys.forward_key, &keys.forward_nonce, window, data)?;
+
+    if plaintext.len() < LAYER_HEADER_LEN {
+        return Err(CircuitError::MalformedLayer("capa demasiado corta".into()));
+    }
+    let mut address = [0u8; 32];
+    address.copy_from_slice(&plaintext[1..33]);
+    let mut circuit_id = [0u8; CIRCUIT_ID_LEN];
+    circuit_id.copy_from_slice(&plaintext[33..LAYER_HEADER_LEN]);
+
+    let command = match plaintext[0] {
+        LAYER_FORWARD => LayerCommand::Forward {
+            next_hop: TaiorAddress::from_bytes(&address),
+            circuit_id,
+        },
+        LAYER_DELIVER => LayerCommand::Deliver,
+        LAYER_EXTEND => LayerCommand::Extend {
+            next_hop: TaiorAddress::from_bytes(&address),
+            circuit_id,
+        },
+        other => return Err(CircuitError::MalformedLayer(format!("comando desconocido {}", other))),
+    };
+
+    Ok((command, plaintext.split_off(LAYER_HEADER_LEN)))
+}
+
+/// Adds a hop's backward layer to data travelling towards the originator,
+/// consuming the next backward sequence number.
+pub fn add_backward_layer(keys: &HopKeys, seq: &mut u64, data: &[u8]) -> Result<Vec<u8>, CircuitError> {
+    let cell = seal_cell(&keys.backward_key, &keys.backward_nonce, *seq, data)?;
+    *seq += 1;
+    Ok(cell)
+}
 
-        cipher
-            .decrypt(nonce, data)
-            .map_err(|e| CircuitError::EncryptionError(format!("{:?}", e)))
+/// Removes the backward layers of `nodes`, entry hop first, from a cell sent back by the last of them.
+pub(crate) fn remove_backward_layers(nodes: &mut [CircuitNode], data: &[u8]) -> Result<Vec<u8>, CircuitError> {
+    let mut plaintext = data.to_vec();
+    for node in nodes.iter_mut() {
+        plaintext = remove_backward_layer(&node.keys, &mut node.backward_window, &plaintext)?;
     }
+    Ok(plaintext)
 }
 
-fn generate_hop_keys() -> (Vec<u8>, Vec<u8>) {
-    let mut key = vec![0u8; 32];
-    let mut nonce = vec![0u8; 12];
-    OsRng.fill_bytes(&mut key);
-    OsRng.fill_bytes(&mut nonce);
-    (key, nonce)
+/// Removes a hop's backward layer on the originator side.
+pub fn remove_backward_layer(
+    keys: &HopKeys,
+    window: &mut SequenceWindow,
+    data: &[u8],
+) -> Result<Vec<u8>, CircuitError> {
+    open_cell(&keys.backward_key, &keys.backward_nonce, window, data)
 }
 
-fn current_timestamp() -> u64 {
+fn seal_cell(key: &[u8; 32], nonce_base: &[u8; 12], seq: u64, data: &[u8]) -> Result<Vec<u8>, CircuitError> {
+    let mut cell = sequence_tag(&sequence_tag_key(key), seq).to_vec();
+    cell.extend_from_slice(&seal(key, &cell_nonce(nonce_base, seq), data)?);
+    Ok(cell)
+}
+
+fn open_cell(
+    key: &[u8; 32],
+    nonce_base: &[u8; 12],
+    window: &mut SequenceWindow,
+    cell: &[u8],
+) -> Result<Vec<u8>, CircuitError> {
+    if cell.len() < SEQUENCE_TAG_LEN {
+        return Err(CircuitError::MalformedLayer("celda demasiado corta".into()));
+    }
+    let seq = window.locate(key, &cell[..SEQUENCE_TAG_LEN])?;
+    let plaintext = open(key, &cell_nonce(nonce_base, seq), &cell[SEQUENCE_TAG_LEN..])?;
+    // Only authenticated cells move the window
+    window.next = seq + 1;
+    Ok(plaintext)
+}
+
+/// Per-cell nonce: the hop's base nonce with the sequence number XORed into its last 8 bytes.
+fn cell_nonce(base: &[u8; 12], seq: u64) -> [u8; 12] {
+    let mut nonce = *base;
+    for (byte, counter) in nonce[4..].iter_mut().zip(seq.to_be_bytes()) {
+        *byte ^= counter;
+    }
+    nonce
+}
+
+fn sequence_tag_key(key: &[u8; 32]) -> [u8; 32] {
+    blake3::derive_key(CONTEXT_SEQUENCE_TAG, key)
+}
+
+fn sequence_tag(tag_key: &[u8; 32], seq: u64) -> [u8; SEQUENCE_TAG_LEN] {
+    let mut tag = [0u8; SEQUENCE_TAG_LEN];
+    tag.copy_from_slice(&blake3::keyed_hash(tag_key, &seq.to_be_bytes()).as_bytes()[..SEQUENCE_TAG_LEN]);
+    tag
+}
+
+fn find_sequence(key: &[u8; 32], tag: &[u8], candidates: std::ops::Range<u64>) -> Option<u64> {
+    let tag_key = sequence_tag_key(key);
+    candidates.into_iter().find(|seq| sequence_tag(&tag_key, *seq) == tag)
+}
+
+fn seal(key: &[u8; 32], nonce: &[u8; 12], data: &[u8]) -> Result<Vec<u8>, CircuitError> {
+    ChaCha20Poly1305::new(Key::from_slice(key))
+        .encrypt(Nonce::from_slice(nonce), data)
+        .map_err(|e| CircuitError::EncryptionError(format!("{:?}", e)))
+}
+
+fn open(key: &[u8; 32], nonce: &[u8; 12], data: &[u8]) -> Result<Vec<u8>, CircuitError> {
+    ChaCha20Poly1305::new(Key::from_slice(key))
+        .decrypt(Nonce::from_slice(nonce), data)
+        .map_err(|e| CircuitError::EncryptionError(format!("{:?}", e)))
+}
+
+fn encode_layer_header(command: &LayerCommand) -> Result<Vec<u8>, CircuitError> {
+    let mut header = vec![0u8; LAYER_HEADER_LEN];
+    match command {
+        LayerCommand::Forward { next_hop, circuit_id } | LayerCommand::Extend { next_hop, circuit_id } => {
+            let address = next_hop.to_bytes()
+                .ok_or_else(|| CircuitError::MalformedLayer(format!("dirección inválida {}", next_hop.as_str())))?;
+            header[0] = if matches!(command, LayerCommand::Extend { .. }) {
+                LAYER_EXTEND
+            } else {
+                LAYER_FORWARD
+            };
+            header[1..33].copy_from_slice(&address);
+            header[33..].copy_from_slice(circuit_id);
+        }
+        LayerCommand::Deliver => header[0] = LAYER_DELIVER,
+    }
+    Ok(header)
+}
+
+pub(crate) fn current_timestamp() -> u64 {
     #[cfg(not(target_arch = "wasm32"))]
     {
         return std::time::SystemTime::now()
@@ -221,24 +616,78 @@ fn current_timestamp() -> u64 {
     }
 }
 
+pub(crate) fn current_timestamp_ms() -> u64 {
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        return std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+    }
+    
+    #[cfg(all(target_arch = "wasm32", feature = "wasm"))]
+    {
+        return js_sys::Date::now() as u64;
+    }
+    
+    #[cfg(all(target_arch = "wasm32", not(feature = "wasm")))]
+    {
+        0 // Fallback para WASM sin feature
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn test_node(seed: u8) -> CircuitNode {
+        let onion_key = PublicKey::from([seed.wrapping_add(9); 32]);
+        CircuitNode {
+            address: TaiorAddress::from_public_key(&onion_key),
+            circuit_id: [seed; CIRCUIT_ID_LEN],
+            keys: HopKeys {
+                forward_key: [seed; 32],
+                forward_nonce: [seed; 12],
+                backward_key: [seed.wrapping_add(1); 32],
+                backward_nonce: [seed.wrapping_add(1); 12],
+            },
+            forward_seq: 0,
+            backward_window: SequenceWindow::default(),
+            onion_key,
+        }
+    }
+
+    #[test]
+    fn test_local_policy_picks_the_entry_hop() {
+        use crate::routing::LocalPolicy;
+
+        let mut builder = CircuitBuilder::new(MIN_CIRCUIT_HOPS, MAX_CIRCUIT_HOPS, 600);
+        let mut local = Vec::new();
+        for i in 0..6u8 {
+            let onion_key = PublicKey::from([i + 1; 32]);
+            let id = format!("192.168.1.{}:7000", i);
+            builder.add_node_with_onion_key(id.clone(), TaiorAddress::from_public_key(&onion_key), onion_key);
+            if i < 3 {
+                builder.set_local(&id, true);
+                local.push(TaiorAddress::from_public_key(&onion_key));
+            }
+        }
+
+        builder.router_mut().set_local_policy(LocalPolicy::Avoid);
+        for _ in 0..6 {
+            let path = builder.select_path(3).unwrap();
+            assert!(!local.contains(&path[0].address));
+        }
+        builder.router_mut().set_local_policy(LocalPolicy::Prefer);
+        for _ in 0..6 {
+            let path = builder.select_path(3).unwrap();
+            assert!(local.contains(&path[0].address));
+        }
+    }
+
     #[test]
     fn test_circuit_creation() {
-        let nodes = vec![
-            CircuitNode {
-                address: TaiorAddress::generate().1,
-                shared_key: vec![0u8; 32],
-                nonce: vec![0u8; 12],
-            },
-            CircuitNode {
-                address: TaiorAddress::generate().1,
-                shared_key: vec![1u8; 32],
-                nonce: vec![1u8; 12],
-            },
-        ];
+        let nodes = vec![test_node(0), test_node(1)];
 
         let circuit = Circuit::new(nodes, 3600);
         assert_eq!(circuit.hop_count(), 2);
@@ -247,16 +696,10 @@ mod tests {
 
     #[test]
     fn test_onion_encryption() {
-        let nodes = vec![
-            CircuitNode {
-                address: TaiorAddress::generate().1,
-                shared_key: vec![0u8; 32],
-                nonce: vec![0u8; 12],
-            },
-        ];
+        let nodes = vec![test_node(0)];
 
-        let circuit = Circuit::new(nodes, 3600);
-        let encryptor = OnionEncryptor::new(circuit);
+        let mut circuit = Circuit::new(nodes, 3600);
+        let mut encryptor = OnionEncryptor::new(&mut circuit);
         
         let payload = b"test message";
         let encrypted = encryptor.encrypt_onion(payload).unwrap();
@@ -264,4 +707,46 @@ mod tests {
         assert_ne!(encrypted, payload);
         assert!(encrypted.len() > payload.len());
     }
+
+    #[test]
+    fn test_extend_command_reaches_last_established_hop() {
+        let mut nodes = vec![test_node(0), test_node(1)];
+        let target = TaiorAddress::from_public_key(&PublicKey::from([42u8; 32]));
+        let command = LayerCommand::Extend {
+            next_hop: target.clone(),
+            circuit_id: [7u8; CIRCUIT_ID_LEN],
+        };
+
+        let onion = build_layers(&mut nodes, command.clone(), b"create").unwrap();
+        let (first, inner) = peel_layer(&nodes[0].keys, &mut SequenceWindow::default(), &onion).unwrap();
+        assert!(matches!(first, LayerCommand::Forward { .. }));
+        let (second, body) = peel_layer(&nodes[1].keys, &mut SequenceWindow::default(), &inner).unwrap();
+        assert_eq!(second, command);
+        assert_eq!(body, b"create");
+    }
+
+    #[test]
+    fn test_cells_get_fresh_nonces_and_stale_cells_are_rejected() {
+        let mut circuit = Circuit::new(vec![test_node(3)], 3600);
+        let keys = circuit.nodes[0].keys.clone();
+        let mut encryptor = OnionEncryptor::new(&mut circuit);
+        let cells: Vec<_> = (0..3).map(|_| encryptor.encrypt_onion(b"misma carga").unwrap()).collect();
+        assert_ne!(cells[0], cells[1]);
+        assert_eq!(circuit.nodes[0].forward_seq, 3);
+
+        let mut window = SequenceWindow::default();
+        peel_layer(&keys, &mut window, &cells[0]).unwrap();
+        assert!(matches!(
+            peel_layer(&keys, &mut window, &cells[0]),
+            Err(CircuitError::ReplayedCell(0))
+        ));
+
+        // A lost cell is skipped; once past it, the late arrival counts as out of order
+        peel_layer(&keys, &mut window, &cells[2]).unwrap();
+        assert!(matches!(
+            peel_layer(&keys, &mut window, &cells[1]),
+            Err(CircuitError::ReplayedCell(1))
+        ));
+        assert_eq!(window.next_expected(), 3);
+    }
 }
@@ -1,34 +1,64 @@
-use crate::packet::TaiorPacket;
-use rand_core::{OsRng, RngCore};
+use crate::entropy::Entropy;
+use crate::error::TaiorError;
+use crate::packet::{frame_payload, pad_payload_with, padded_len, TaiorPacket};
+use rand_core::RngCore;
+
+/// Share of sends that carry a cover packet alongside, unless configured otherwise.
+pub const DEFAULT_COVER_RATIO: f32 = 0.3;
 
 pub struct CoverTrafficGenerator {
     enabled: bool,
     ratio: f32,
+    entropy: Entropy,
 }
 
 impl CoverTrafficGenerator {
     pub fn new(enabled: bool, ratio: f32) -> Self {
-        Self { enabled, ratio }
+        Self { enabled, ratio, entropy: Entropy::secure() }
+    }
+
+    /// Draws cover decisions and dummy payloads from `entropy`.
+    pub fn with_entropy(mut self, entropy: Entropy) -> Self {
+        self.entropy = entropy;
+        self
+    }
+
+    pub fn should_send_cover(&mut self) -> bool {
+        self.should_send_cover_with(self.ratio)
     }
 
-    pub fn should_send_cover(&self) -> bool {
+    /// Like `should_send_cover`, with a ratio chosen per send (see `adaptive::choose`).
+    pub fn should_send_cover_with(&mut self, ratio: f32) -> bool {
         if !self.enabled {
             return false;
         }
-        let mut rng = OsRng;
-        let rand_val = (rng.next_u32() as f32) / (u32::MAX as f32);
-        rand_val < self.ratio
+        let rand_val = (self.entropy.next_u32() as f32) / (u32::MAX as f32);
+        rand_val < ratio
     }
 
-    pub fn generate_cover_packet(&self, padding_size: usize, ttl: u8) -> Result<TaiorPacket, String> {
+    pub fn generate_cover_packet(&mut self, padding_size: usize, ttl: u8) -> Result<TaiorPacket, TaiorError> {
         let mut dummy_payload = vec![0u8; 16];
-        OsRng.fill_bytes(&mut dummy_payload);
+        self.entropy.fill_bytes(&mut dummy_payload);
         TaiorPacket::new(&dummy_payload, ttl, padding_size, true)
     }
+
+    /// Builds an unencrypted cover frame, for callers that seal it like a real one.
+    pub fn generate_cover_frame(&mut self, padding_size: usize) -> Result<Vec<u8>, TaiorError> {
+        self.generate_cover_frame_with_len(16, padding_size)
+    }
+
+    /// Like `generate_cover_frame`, with `len` dummy bytes, for cover sized like a
+    /// real message of that length.
+    pub fn generate_cover_frame_with_len(&mut self, len: usize, padding_size: usize) -> Result<Vec<u8>, TaiorError> {
+        let mut dummy_payload = vec![0u8; len];
+        self.entropy.fill_bytes(&mut dummy_payload);
+        let frame = frame_payload(&dummy_payload, true, 0)?;
+        Ok(pad_payload_with(&frame, padded_len(frame.len(), padding_size), &mut self.entropy))
+    }
 }
 
 impl Default for CoverTrafficGenerator {
     fn default() -> Self {
-        Self::new(false, 0.3)
+        Self::new(false, DEFAULT_COVER_RATIO)
     }
 }
@@ -1,8 +1,8 @@
+use crate::circuit::current_timestamp_ms;
 use crate::packet::TaiorPacket;
 use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
-#[cfg(all(target_arch = "wasm32", feature = "wasm"))]
-use js_sys;
 
 #[derive(Error, Debug)]
 pub enum CoverTrafficError {
@@ -12,7 +12,8 @@ pub enum CoverTrafficError {
     NoCircuit,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct CoverTrafficConfig {
     pub enabled: bool,
     pub packets_per_second: f64,
@@ -80,7 +81,7 @@ impl CoverTrafficGenerator {
 
     pub fn wrap_in_packet(&self, payload: Vec<u8>) -> Result<TaiorPacket, CoverTrafficError> {
         TaiorPacket::new(&payload, 3, 0, true)
-            .map_err(|e| CoverTrafficError::GenerationError(e))
+            .map_err(|e| CoverTrafficError::GenerationError(e.to_string()))
     }
 }
 
@@ -159,26 +160,6 @@ impl AdaptiveCoverTraffic {
     }
 }
 
-fn current_timestamp_ms() -> u64 {
-    #[cfg(not(target_arch = "wasm32"))]
-    {
-        return std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_millis() as u64;
-    }
-    
-    #[cfg(all(target_arch = "wasm32", feature = "wasm"))]
-    {
-        return js_sys::Date::now() as u64;
-    }
-    
-    #[cfg(all(target_arch = "wasm32", not(feature = "wasm")))]
-    {
-        0 // Fallback para WASM sin feature
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -0,0 +1,406 @@
+//! Self-signed node descriptors. A descriptor binds a node's address to the X25519
+//! onion key it is derived from, and publishes under an Ed25519 signature where the
+//! node is reached, what it offers and until when. Discovery only stores descriptors
+//! whose signature verifies and which have not expired.
+//!
+//! The signing key is certified by the onion key itself, XEdDSA-style, so only the
+//! holder of the onion secret can publish descriptors for its address.
+//!
+//! Wire layout (big-endian):
+//! `[label][version: 4][expires_at: 8][onion key: 32][signing key: 32][certificate: 64]`
+//! `[capabilities: 1][endpoint count: 1]{[kind: 1][ip family: 1][ip: 4|16][port: 2]}*`
+//! `[signature: 64]`, where the signature covers everything before it.
+
+use crate::circuit::current_timestamp;
+use crate::identity::TaiorAddress;
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use curve25519_dalek::scalar::{clamp_integer, Scalar};
+use curve25519_dalek::EdwardsPoint;
+use ed25519_dalek::hazmat::{self, ExpandedSecretKey};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey, SIGNATURE_LENGTH};
+use sha2::Sha512;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::ops::BitOr;
+use thiserror::Error;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// Domain separation label the signature starts from.
+const DESCRIPTOR_LABEL: &[u8] = b"taior-descriptor-v2";
+/// Domain separation label the onion key's certificate of the signing key starts from.
+const CERTIFICATE_LABEL: &[u8] = b"taior-descriptor-certificate-v1";
+/// Endpoints a descriptor may list.
+pub const MAX_ENDPOINTS: usize = 8;
+
+const ENDPOINT_QUIC: u8 = 0;
+const ENDPOINT_UDP: u8 = 1;
+const ENDPOINT_RELAY: u8 = 2;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum DescriptorError {
+    #[error("Firma del descriptor inválida")]
+    InvalidSignature,
+    #[error("La clave onion no certifica la clave de firma")]
+    InvalidCertificate,
+    #[error("Descriptor expirado en {0}")]
+    Expired(u64),
+    #[error("Descriptor sin endpoints")]
+    NoEndpoints,
+    #[error("Descriptor obsoleto: versión {0} frente a {1}")]
+    Stale(u32, u32),
+    #[error("Descriptor en conflicto: otra versión {0} con distinto contenido")]
+    Conflict(u32),
+    #[error("Descriptor mal formado: {0}")]
+    Malformed(String),
+}
+
+/// Where a node is reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Endpoint {
+    Quic(SocketAddr),
+    Udp(SocketAddr),
+    /// Reached through the relay server at this address, e.g. from behind a NAT.
+    Relay(SocketAddr),
+}
+
+impl Endpoint {
+    pub fn socket_addr(&self) -> SocketAddr {
+        match *self {
+            Self::Quic(addr) | Self::Udp(addr) | Self::Relay(addr) => addr,
+        }
+    }
+
+    /// Whether the node itself listens at the endpoint.
+    pub fn is_direct(&self) -> bool {
+        !matches!(self, Self::Relay(_))
+    }
+}
+
+/// What a node offers to others.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Capabilities(pub u8);
+
+impl Capabilities {
+    pub const NONE: Self = Self(0);
+    /// Peels onion and Sphinx layers, so it can become a circuit hop.
+    pub const RELAY: Self = Self(1);
+    /// Holds packets for mixing delays before forwarding them.
+    pub const MIX: Self = Self(1 << 1);
+    /// Runs a relay server for nodes behind NATs.
+    pub const BRIDGE: Self = Self(1 << 2);
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeDescriptor {
+    pub onion_key: PublicKey,
+    pub signing_key: VerifyingKey,
+    pub endpoints: Vec<Endpoint>,
+    pub capabilities: Capabilities,
+    /// Revision of the descriptor; a node bumps it whenever it republishes, and a
+    /// newer revision replaces an older one.
+    pub version: u32,
+    /// Unix time, in seconds, after which the descriptor is no longer valid.
+    pub expires_at: u64,
+    /// Signature of the onion key over the signing key.
+    certificate: Signature,
+    signature: Signature,
+}
+
+impl NodeDescriptor {
+    /// Signs a descriptor for the node holding `onion_secret`, which certifies
+    /// `signing_key`.
+    pub fn sign(
+        signing_key: &SigningKey,
+        onion_secret: &StaticSecret,
+        endpoints: Vec<Endpoint>,
+        capabilities: Capabilities,
+        version: u32,
+        expires_at: u64,
+    ) -> Self {
+        let mut descriptor = Self {
+            onion_key: PublicKey::from(onion_secret),
+            signing_key: signing_key.verifying_key(),
+            endpoints,
+            capabilities,
+            version,
+            expires_at,
+            certificate: certify(onion_secret, &signing_key.verifying_key()),
+            signature: Signature::from_bytes(&[0u8; SIGNATURE_LENGTH]),
+        };
+        descriptor.signature = signing_key.sign(&descriptor.signed_bytes());
+        descriptor
+    }
+
+    /// Address of the node, derived from its onion key.
+    pub fn address(&self) -> TaiorAddress {
+        TaiorAddress::from_public_key(&self.onion_key)
+    }
+
+    /// Name the node is known by in discovery: its first direct endpoint, as other
+    /// nodes are named, or its address when it is only reached through a relay.
+    pub fn node_name(&self) -> String {
+        self.endpoints
+            .iter()
+            .find(|endpoint| endpoint.is_direct())
+            .map(|endpoint| endpoint.socket_addr().to_string())
+            .unwrap_or_else(|| self.address().0)
+    }
+
+    pub fn is_expired_at(&self, now: u64) -> bool {
+        now > self.expires_at
+    }
+
+    pub fn verify(&self) -> Result<(), DescriptorError> {
+        self.verify_at(current_timestamp())
+    }
+
+    /// Checks the certificate and the signature, that the descriptor lists an
+    /// endpoint and that it has not expired at `now`.
+    pub fn verify_at(&self, now: u64) -> Result<(), DescriptorError> {
+        onion_verifying_key(&self.onion_key)
+            .ok_or(DescriptorError::InvalidCertificate)?
+            .verify_strict(&certificate_bytes(&self.signing_key), &self.certificate)
+            .map_err(|_| DescriptorError::InvalidCertificate)?;
+        self.signing_key
+            .verify(&self.signed_bytes(), &self.signature)
+            .map_err(|_| DescriptorError::InvalidSignature)?;
+        if self.endpoints.is_empty() {
+            return Err(DescriptorError::NoEndpoints);
+        }
+        if self.is_expired_at(now) {
+            return Err(DescriptorError::Expired(self.expires_at));
+        }
+        Ok(())
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = self.signed_bytes();
+        bytes.extend_from_slice(&self.signature.to_bytes());
+        bytes
+    }
+
+    /// Parses an encoded descriptor. The signature is not checked; see `verify`.
+    pub fn decode(bytes: &[u8]) -> Result<Self, DescriptorError> {
+        let mut reader = Reader(bytes);
+        if reader.take(DESCRIPTOR_LABEL.len())? != DESCRIPTOR_LABEL {
+            return Err(DescriptorError::Malformed("unknown label".to_string()));
+        }
+        let version = u32::from_be_bytes(reader.array()?);
+        let expires_at = u64::from_be_bytes(reader.array()?);
+        let onion_key = PublicKey::from(reader.array::<32>()?);
+        let signing_key = VerifyingKey::from_bytes(&reader.array()?)
+            .map_err(|_| DescriptorError::Malformed("invalid signing key".to_string()))?;
+        let certificate = Signature::from_bytes(&reader.array()?);
+        let capabilities = Capabilities(reader.array::<1>()?[0]);
+
+        let count = reader.array::<1>()?[0] as usize;
+        if count > MAX_ENDPOINTS {
+            return Err(DescriptorError::Malformed(format!("{} endpoints", count)));
+        }
+        let endpoints = (0..count)
+            .map(|_| reader.endpoint())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let signature = Signature::from_bytes(&reader.array()?);
+        if !reader.0.is_empty() {
+            return Err(DescriptorError::Malformed("trailing bytes".to_string()));
+        }
+
+        Ok(Self {
+            onion_key,
+            signing_key,
+            endpoints,
+            capabilities,
+            version,
+            expires_at,
+            certificate,
+            signature,
+        })
+    }
+
+    fn signed_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(192 + self.endpoints.len() * 20);
+        bytes.extend_from_slice(DESCRIPTOR_LABEL);
+        bytes.extend_from_slice(&self.version.to_be_bytes());
+        bytes.extend_from_slice(&self.expires_at.to_be_bytes());
+        bytes.extend_from_slice(self.onion_key.as_bytes());
+        bytes.extend_from_slice(self.signing_key.as_bytes());
+        bytes.extend_from_slice(&self.certificate.to_bytes());
+        bytes.push(self.capabilities.0);
+        // More than MAX_ENDPOINTS are signed but never decode, so they cannot travel
+        bytes.push(self.endpoints.len().min(u8::MAX as usize) as u8);
+        for endpoint in &self.endpoints {
+            let kind = match endpoint {
+                Endpoint::Quic(_) => ENDPOINT_QUIC,
+                Endpoint::Udp(_) => ENDPOINT_UDP,
+                Endpoint::Relay(_) => ENDPOINT_RELAY,
+            };
+            bytes.push(kind);
+            let addr = endpoint.socket_addr();
+            match addr.ip() {
+                IpAddr::V4(ip) => {
+                    bytes.push(4);
+                    bytes.extend_from_slice(&ip.octets());
+                }
+                IpAddr::V6(ip) => {
+                    bytes.push(6);
+                    bytes.extend_from_slice(&ip.octets());
+                }
+            }
+            bytes.extend_from_slice(&addr.port().to_be_bytes());
+        }
+        bytes
+    }
+}
+
+fn certificate_bytes(signing_key: &VerifyingKey) -> Vec<u8> {
+    [CERTIFICATE_LABEL, signing_key.as_bytes()].concat()
+}
+
+/// Signs that `signing_key` speaks for the onion key, using the X25519 secret as an
+/// Ed25519 one as XEdDSA does: of the two Edwards points behind the onion key, the
+/// one with sign bit 0 is the public key, so the scalar is negated if needed.
+fn certify(onion_secret: &StaticSecret, signing_key: &VerifyingKey) -> Signature {
+    let secret = onion_secret.to_bytes();
+    let scalar = Scalar::from_bytes_mod_order(clamp_integer(secret));
+    let point = EdwardsPoint::mul_base(&scalar);
+    let (scalar, point) = if point.compress().as_bytes()[31] >> 7 == 0 {
+        (scalar, point)
+    } else {
+        (-scalar, -point)
+    };
+    let hash_prefix = blake3::derive_key("taior descriptor certificate nonce", &secret);
+    let expanded = ExpandedSecretKey { scalar, hash_prefix };
+    hazmat::raw_sign::<Sha512>(&expanded, &certificate_bytes(signing_key), &VerifyingKey::from(point))
+}
+
+/// Ed25519 key the onion key certifies with, or `None` if it is not on the curve.
+fn onion_verifying_key(onion_key: &PublicKey) -> Option<VerifyingKey> {
+    MontgomeryPoint(onion_key.to_bytes()).to_edwards(0).map(VerifyingKey::from)
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], DescriptorError> {
+        if self.0.len() < len {
+            return Err(DescriptorError::Malformed("truncated".to_string()));
+        }
+        let (head, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], DescriptorError> {
+        let mut out = [0u8; N];
+        out.copy_from_slice(self.take(N)?);
+        Ok(out)
+    }
+
+    fn endpoint(&mut self) -> Result<Endpoint, DescriptorError> {
+        let [kind, family] = self.array()?;
+        let ip = match family {
+            4 => IpAddr::V4(Ipv4Addr::from(self.array::<4>()?)),
+            6 => IpAddr::V6(Ipv6Addr::from(self.array::<16>()?)),
+            _ => return Err(DescriptorError::Malformed(format!("ip family {}", family))),
+        };
+        let addr = SocketAddr::new(ip, u16::from_be_bytes(self.array()?));
+        match kind {
+            ENDPOINT_QUIC => Ok(Endpoint::Quic(addr)),
+            ENDPOINT_UDP => Ok(Endpoint::Udp(addr)),
+            ENDPOINT_RELAY => Ok(Endpoint::Relay(addr)),
+            _ => Err(DescriptorError::Malformed(format!("endpoint kind {}", kind))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand_core::OsRng;
+    use x25519_dalek::StaticSecret;
+
+    fn descriptor(endpoint: &str, version: u32) -> (SigningKey, StaticSecret, NodeDescriptor) {
+        let signing_key = SigningKey::generate(&mut OsRng);
+        let onion_secret = StaticSecret::random_from_rng(OsRng);
+        let descriptor = NodeDescriptor::sign(
+            &signing_key,
+            &onion_secret,
+            vec![Endpoint::Udp(endpoint.parse().unwrap()), Endpoint::Relay("[2001:db8::1]:443".parse().unwrap())],
+            Capabilities::RELAY | Capabilities::MIX,
+            version,
+            current_timestamp() + 3600,
+        );
+        (signing_key, onion_secret, descriptor)
+    }
+
+    #[test]
+    fn test_descriptors_roundtrip_and_verify() {
+        let (_, _, descriptor) = descriptor("127.0.0.1:9000", 1);
+        descriptor.verify().unwrap();
+        assert_eq!(descriptor.node_name(), "127.0.0.1:9000");
+        assert!(descriptor.address().matches_public_key(&descriptor.onion_key));
+        assert!(descriptor.capabilities.contains(Capabilities::RELAY));
+        assert!(!descriptor.capabilities.contains(Capabilities::BRIDGE));
+
+        let decoded = NodeDescriptor::decode(&descriptor.encode()).unwrap();
+        assert_eq!(decoded, descriptor);
+        decoded.verify().unwrap();
+
+        assert!(matches!(
+            NodeDescriptor::decode(&descriptor.encode()[..40]),
+            Err(DescriptorError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn test_tampered_and_expired_descriptors_are_rejected() {
+        let (signing_key, onion_secret, descriptor) = descriptor("127.0.0.1:9000", 1);
+
+        let mut moved = descriptor.clone();
+        moved.endpoints[0] = Endpoint::Udp("10.0.0.1:9000".parse().unwrap());
+        assert_eq!(moved.verify(), Err(DescriptorError::InvalidSignature));
+
+        let mut hijacked = descriptor.clone();
+        hijacked.onion_key = PublicKey::from(&StaticSecret::random_from_rng(OsRng));
+        assert_eq!(hijacked.verify(), Err(DescriptorError::InvalidCertificate));
+
+        assert_eq!(
+            descriptor.verify_at(descriptor.expires_at + 1),
+            Err(DescriptorError::Expired(descriptor.expires_at))
+        );
+
+        let empty = NodeDescriptor::sign(&signing_key, &onion_secret, Vec::new(), Capabilities::NONE, 1, u64::MAX);
+        assert_eq!(empty.verify(), Err(DescriptorError::NoEndpoints));
+    }
+
+    #[test]
+    fn test_signing_keys_the_onion_key_did_not_certify_are_rejected() {
+        let (_, _, victim) = descriptor("127.0.0.1:9000", 1);
+        let (attacker_key, _, mut forged) = descriptor("10.0.0.1:9000", u32::MAX);
+
+        // Claiming the victim's onion key, with or without its certificate, is caught
+        forged.onion_key = victim.onion_key;
+        forged.signature = attacker_key.sign(&forged.signed_bytes());
+        assert_eq!(forged.verify(), Err(DescriptorError::InvalidCertificate));
+        forged.certificate = victim.certificate;
+        forged.signature = attacker_key.sign(&forged.signed_bytes());
+        assert_eq!(forged.verify(), Err(DescriptorError::InvalidCertificate));
+
+        // Every onion secret certifies, whichever Edwards sign its point has
+        for _ in 0..8 {
+            descriptor("127.0.0.1:9000", 1).2.verify().unwrap();
+        }
+    }
+}
@@ -0,0 +1,878 @@
+//! Volatile Kademlia-style DHT for node discovery. Nodes are identified by the
+//! BLAKE3 hash behind their `TaiorAddress` and keep the contacts they hear from in
+//! k-buckets by XOR distance. Lookups query the `alpha` closest contacts not yet
+//! asked until the `k` closest known have answered. Descriptors are stored at the
+//! `k` nodes closest to their address for a short TTL and must be republished
+//! before it runs out; nothing is ever written to disk.
+//!
+//! Messages travel over any `Transport` as `PacketFormat::Control` packets whose
+//! circuit id slot carries the id of the request a response answers:
+//!
+//! ```text
+//! [CONTROL_DHT][kind: 1][sender id: 32][body]
+//! ```
+//!
+//! Sender ids are not authenticated, so a node can place itself anywhere in the
+//! key space. Stored descriptors are verified, which keeps them from being forged,
+//! not from being withheld.
+
+use crate::descriptor::NodeDescriptor;
+use crate::error::TaiorError;
+use crate::identity::TaiorAddress;
+use crate::packet::{PacketFormat, TaiorPacket, CIRCUIT_ID_LEN, CONTROL_DHT};
+use crate::transport::Transport;
+use rand_core::{OsRng, RngCore};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+/// Contacts per k-bucket, and nodes a descriptor is stored at.
+pub const DEFAULT_K: usize = 20;
+/// Queries a lookup keeps in flight.
+pub const DEFAULT_ALPHA: usize = 3;
+/// Time a contact has to answer a request before it is dropped.
+pub const DEFAULT_RPC_TIMEOUT: Duration = Duration::from_secs(2);
+/// Time a stored descriptor is served for.
+pub const DEFAULT_RECORD_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// Requests the application may have queued before the DHT task picks them up.
+const COMMAND_QUEUE: usize = 64;
+
+const KIND_PING: u8 = 0;
+const KIND_PONG: u8 = 1;
+const KIND_FIND_NODE: u8 = 2;
+const KIND_NODES: u8 = 3;
+const KIND_FIND_VALUE: u8 = 4;
+const KIND_VALUE: u8 = 5;
+const KIND_STORE: u8 = 6;
+
+#[derive(Debug, Clone, Copy)]
+pub struct DhtConfig {
+    pub k: usize,
+    pub alpha: usize,
+    pub rpc_timeout: Duration,
+    pub record_ttl: Duration,
+}
+
+impl Default for DhtConfig {
+    fn default() -> Self {
+        Self {
+            k: DEFAULT_K,
+            alpha: DEFAULT_ALPHA,
+            rpc_timeout: DEFAULT_RPC_TIMEOUT,
+            record_ttl: DEFAULT_RECORD_TTL,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(pub [u8; 32]);
+
+impl NodeId {
+    /// Id of the node behind `address`, or `None` if the address is malformed.
+    pub fn from_address(address: &TaiorAddress) -> Option<Self> {
+        address.to_bytes().map(Self)
+    }
+
+    pub fn random() -> Self {
+        let mut id = [0u8; 32];
+        OsRng.fill_bytes(&mut id);
+        Self(id)
+    }
+
+    /// A random id whose distance from this one falls in k-bucket `bucket`, i.e.
+    /// whose highest differing bit is bit `bucket`.
+    fn random_in_bucket(&self, bucket: usize) -> Self {
+        let mut distance = Self::random().0;
+        let byte = 31 - bucket / 8;
+        let bit = 1u8 << (bucket % 8);
+        distance[..byte].fill(0);
+        distance[byte] = (distance[byte] & (bit - 1)) | bit;
+        Self(std::array::from_fn(|i| self.0[i] ^ distance[i]))
+    }
+
+    /// XOR distance, ordered as a big-endian number.
+    pub fn distance(&self, other: &NodeId) -> [u8; 32] {
+        std::array::from_fn(|i| self.0[i] ^ other.0[i])
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Contact {
+    pub id: NodeId,
+    pub endpoint: SocketAddr,
+}
+
+/// Known contacts, in one bucket per bit of distance from our own id. Buckets keep
+/// their longest-lived contacts: a full bucket ignores newcomers, and contacts make
+/// room once they fail to answer.
+struct RoutingTable {
+    own: NodeId,
+    k: usize,
+    buckets: Vec<Vec<Contact>>,
+}
+
+impl RoutingTable {
+    fn new(own: NodeId, k: usize) -> Self {
+        Self { own, k, buckets: vec![Vec::new(); 256] }
+    }
+
+    fn bucket_index(&self, id: &NodeId) -> Option<usize> {
+        let distance = self.own.distance(id);
+        let first = distance.iter().position(|&byte| byte != 0)?;
+        Some(255 - (first * 8 + distance[first].leading_zeros() as usize))
+    }
+
+    /// Records that `contact` was heard from, moving it to the back of its bucket.
+    fn insert(&mut self, contact: Contact) {
+        let Some(index) = self.bucket_index(&contact.id) else {
+            return;
+        };
+        let bucket = &mut self.buckets[index];
+        if let Some(position) = bucket.iter().position(|known| known.id == contact.id) {
+            bucket.remove(position);
+            bucket.push(contact);
+        } else if bucket.len() < self.k {
+            bucket.push(contact);
+        }
+    }
+
+    fn remove(&mut self, id: &NodeId) {
+        if let Some(index) = self.bucket_index(id) {
+            self.buckets[index].retain(|known| known.id != *id);
+        }
+    }
+
+    fn closest(&self, target: &NodeId, count: usize) -> Vec<Contact> {
+        let mut contacts: Vec<Contact> = self.buckets.iter().flatten().copied().collect();
+        contacts.sort_by_key(|contact| contact.id.distance(target));
+        contacts.truncate(count);
+        contacts
+    }
+
+    fn contacts(&self) -> Vec<Contact> {
+        self.buckets.iter().flatten().copied().collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Message {
+    Ping,
+    Pong,
+    FindNode(NodeId),
+    Nodes(Vec<Contact>),
+    FindValue(NodeId),
+    Value(Box<NodeDescriptor>),
+    Store(Box<NodeDescriptor>),
+}
+
+impl Message {
+    fn is_response(&self) -> bool {
+        matches!(self, Self::Pong | Self::Nodes(_) | Self::Value(_))
+    }
+
+    fn encode(&self, sender: &NodeId) -> Vec<u8> {
+        let mut bytes = vec![CONTROL_DHT];
+        let kind = match self {
+            Self::Ping => KIND_PING,
+            Self::Pong => KIND_PONG,
+            Self::FindNode(_) => KIND_FIND_NODE,
+            Self::Nodes(_) => KIND_NODES,
+            Self::FindValue(_) => KIND_FIND_VALUE,
+            Self::Value(_) => KIND_VALUE,
+            Self::Store(_) => KIND_STORE,
+        };
+        bytes.push(kind);
+        bytes.extend_from_slice(&sender.0);
+
+        match self {
+            Self::Ping | Self::Pong => {}
+            Self::FindNode(target) | Self::FindValue(target) => bytes.extend_from_slice(&target.0),
+            Self::Nodes(contacts) => {
+                bytes.push(contacts.len().min(u8::MAX as usize) as u8);
+                for contact in contacts.iter().take(u8::MAX as usize) {
+                    bytes.extend_from_slice(&contact.id.0);
+                    match contact.endpoint.ip() {
+                        IpAddr::V4(ip) => {
+                            bytes.push(4);
+                            bytes.extend_from_slice(&ip.octets());
+                        }
+                        IpAddr::V6(ip) => {
+                            bytes.push(6);
+                            bytes.extend_from_slice(&ip.octets());
+                        }
+                    }
+                    bytes.extend_from_slice(&contact.endpoint.port().to_be_bytes());
+                }
+            }
+            Self::Value(descriptor) | Self::Store(descriptor) => bytes.extend_from_slice(&descriptor.encode()),
+        }
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Result<(NodeId, Self), TaiorError> {
+        let malformed = || TaiorError::PacketFormat("malformed DHT message".to_string());
+        if bytes.len() < 2 + 32 || bytes[0] != CONTROL_DHT {
+            return Err(malformed());
+        }
+        let kind = bytes[1];
+        let sender = NodeId(bytes[2..34].try_into().map_err(|_| malformed())?);
+        let body = &bytes[34..];
+        let target = || -> Result<NodeId, TaiorError> {
+            Ok(NodeId(body.try_into().map_err(|_| malformed())?))
+        };
+
+        let message = match kind {
+            KIND_PING if body.is_empty() => Self::Ping,
+            KIND_PONG if body.is_empty() => Self::Pong,
+            KIND_FIND_NODE => Self::FindNode(target()?),
+            KIND_FIND_VALUE => Self::FindValue(target()?),
+            KIND_NODES => {
+                let (&count, mut rest) = body.split_first().ok_or_else(malformed)?;
+                let mut contacts = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    let ip_len = match rest.get(32) {
+                        Some(4) => 4,
+                        Some(6) => 16,
+                        _ => return Err(malformed()),
+                    };
+                    let entry_len = 32 + 1 + ip_len + 2;
+                    if rest.len() < entry_len {
+                        return Err(malformed());
+                    }
+                    let (entry, tail) = rest.split_at(entry_len);
+                    let ip = if ip_len == 4 {
+                        IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(&entry[33..37]).map_err(|_| malformed())?))
+                    } else {
+                        IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&entry[33..49]).map_err(|_| malformed())?))
+                    };
+                    let port = u16::from_be_bytes([entry[entry_len - 2], entry[entry_len - 1]]);
+                    contacts.push(Contact {
+                        id: NodeId(entry[..32].try_into().map_err(|_| malformed())?),
+                        endpoint: SocketAddr::new(ip, port),
+                    });
+                    rest = tail;
+                }
+                if !rest.is_empty() {
+                    return Err(malformed());
+                }
+                Self::Nodes(contacts)
+            }
+            KIND_VALUE | KIND_STORE => {
+                let descriptor = Box::new(NodeDescriptor::decode(body)?);
+                if kind == KIND_VALUE {
+                    Self::Value(descriptor)
+                } else {
+                    Self::Store(descriptor)
+                }
+            }
+            _ => return Err(malformed()),
+        };
+        Ok((sender, message))
+    }
+}
+
+enum DhtCommand {
+    Join {
+        bootstrap: SocketAddr,
+        respond: oneshot::Sender<Result<(), TaiorError>>,
+    },
+    FindNode {
+        target: NodeId,
+        respond: oneshot::Sender<Result<Vec<Contact>, TaiorError>>,
+    },
+    Publish {
+        descriptor: Box<NodeDescriptor>,
+        respond: oneshot::Sender<Result<usize, TaiorError>>,
+    },
+    Lookup {
+        target: NodeId,
+        respond: oneshot::Sender<Result<Option<NodeDescriptor>, TaiorError>>,
+    },
+    Contacts {
+        respond: oneshot::Sender<Vec<Contact>>,
+    },
+}
+
+/// Handle to a DHT node running over its own transport. Dropping it stops the node.
+pub struct DhtNode {
+    id: NodeId,
+    commands: mpsc::Sender<DhtCommand>,
+    shutdown: Option<oneshot::Sender<()>>,
+    task: JoinHandle<()>,
+}
+
+impl DhtNode {
+    /// Starts a DHT node identified by `id` on `transport`.
+    pub fn spawn<T: Transport + 'static>(id: NodeId, transport: T, config: DhtConfig) -> Self {
+        let (commands, command_rx) = mpsc::channel(COMMAND_QUEUE);
+        let (shutdown, shutdown_rx) = oneshot::channel();
+
+        let runtime = DhtLoop {
+            id,
+            config,
+            transport,
+            commands: command_rx,
+            table: RoutingTable::new(id, config.k),
+            records: HashMap::new(),
+            rpcs: HashMap::new(),
+            lookups: HashMap::new(),
+            next_lookup: 0,
+            queued: Vec::new(),
+        };
+        let task = tokio::spawn(runtime.run(shutdown_rx));
+
+        Self {
+            id,
+            commands,
+            shutdown: Some(shutdown),
+            task,
+        }
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    /// Joins the network through the node at `bootstrap`: looks up our own id to
+    /// fill the buckets around it, then a random id in each bucket farther away so
+    /// that the rest of the key space knows of us too.
+    pub async fn join(&self, bootstrap: SocketAddr) -> Result<(), TaiorError> {
+        let (respond, answer) = oneshot::channel();
+        self.command(DhtCommand::Join { bootstrap, respond }).await?;
+        answer.await.map_err(|_| TaiorError::NodeStopped)?
+    }
+
+    /// The `k` live nodes closest to `target`.
+    pub async fn find_node(&self, target: NodeId) -> Result<Vec<Contact>, TaiorError> {
+        let (respond, answer) = oneshot::channel();
+        self.command(DhtCommand::FindNode { target, respond }).await?;
+        answer.await.map_err(|_| TaiorError::NodeStopped)?
+    }
+
+    /// Stores `descriptor` at the `k` nodes closest to its address and returns how
+    /// many it was sent to. It is served for `DhtConfig::record_ttl`.
+    pub async fn publish(&self, descriptor: NodeDescriptor) -> Result<usize, TaiorError> {
+        descriptor.verify()?;
+        let (respond, answer) = oneshot::channel();
+        self.command(DhtCommand::Publish { descriptor: Box::new(descriptor), respond }).await?;
+        answer.await.map_err(|_| TaiorError::NodeStopped)?
+    }
+
+    /// Finds the verified descriptor published for `address`, if any.
+    pub async fn lookup(&self, address: &TaiorAddress) -> Result<Option<NodeDescriptor>, TaiorError> {
+        let target = NodeId::from_address(address)
+            .ok_or_else(|| TaiorError::Discovery(format!("malformed address {}", address.as_str())))?;
+        let (respond, answer) = oneshot::channel();
+        self.command(DhtCommand::Lookup { target, respond }).await?;
+        answer.await.map_err(|_| TaiorError::NodeStopped)?
+    }
+
+    /// Contacts currently in the routing table.
+    pub async fn contacts(&self) -> Result<Vec<Contact>, TaiorError> {
+        let (respond, answer) = oneshot::channel();
+        self.command(DhtCommand::Contacts { respond }).await?;
+        answer.await.map_err(|_| TaiorError::NodeStopped)
+    }
+
+    /// Stops the node, forgetting everything it stored.
+    pub async fn shutdown(mut self) -> Result<(), TaiorError> {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        (&mut self.task).await.map_err(|_| TaiorError::NodeStopped)
+    }
+
+    async fn command(&self, command: DhtCommand) -> Result<(), TaiorError> {
+        self.commands.send(command).await.map_err(|_| TaiorError::NodeStopped)
+    }
+}
+
+impl Drop for DhtNode {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+struct Record {
+    descriptor: NodeDescriptor,
+    expires: Instant,
+}
+
+/// What a request in flight is for.
+enum Purpose {
+    Join(oneshot::Sender<Result<(), TaiorError>>),
+    /// Query of the given contact on behalf of a lookup.
+    Query(u64, NodeId),
+}
+
+struct Rpc {
+    endpoint: SocketAddr,
+    deadline: Instant,
+    purpose: Purpose,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CandidateState {
+    Fresh,
+    Waiting,
+    Answered,
+    Failed,
+}
+
+struct Candidate {
+    contact: Contact,
+    state: CandidateState,
+}
+
+enum LookupDone {
+    /// Lookup of our own id after joining, followed by a refresh of the buckets
+    /// farther away than our closest neighbour.
+    Join(oneshot::Sender<Result<(), TaiorError>>),
+    /// Lookups of a random id in each bucket left to refresh.
+    Refresh(oneshot::Sender<Result<(), TaiorError>>, Vec<NodeId>),
+    Nodes(oneshot::Sender<Result<Vec<Contact>, TaiorError>>),
+    Publish(Box<NodeDescriptor>, oneshot::Sender<Result<usize, TaiorError>>),
+    Value(oneshot::Sender<Result<Option<NodeDescriptor>, TaiorError>>),
+}
+
+/// An iterative lookup: candidates by distance to the target, asked `alpha` at a time.
+struct Lookup {
+    target: NodeId,
+    candidates: Vec<Candidate>,
+    /// Newest verified descriptor seen so far, for value lookups.
+    value: Option<NodeDescriptor>,
+    done: LookupDone,
+}
+
+impl Lookup {
+    fn merge(&mut self, contacts: impl IntoIterator<Item = Contact>, own: &NodeId) {
+        for contact in contacts {
+            if contact.id != *own && !self.candidates.iter().any(|c| c.contact.id == contact.id) {
+                self.candidates.push(Candidate { contact, state: CandidateState::Fresh });
+            }
+        }
+        let target = self.target;
+        self.candidates.sort_by_key(|c| c.contact.id.distance(&target));
+    }
+
+    fn mark(&mut self, id: &NodeId, state: CandidateState) {
+        if let Some(candidate) = self.candidates.iter_mut().find(|c| c.contact.id == *id) {
+            candidate.state = state;
+        }
+    }
+}
+
+struct DhtLoop<T> {
+    id: NodeId,
+    config: DhtConfig,
+    transport: T,
+    commands: mpsc::Receiver<DhtCommand>,
+    table: RoutingTable,
+    records: HashMap<NodeId, Record>,
+    rpcs: HashMap<[u8; CIRCUIT_ID_LEN], Rpc>,
+    lookups: HashMap<u64, Lookup>,
+    next_lookup: u64,
+    /// Lookups that follow on from a finished one, started once the current event is handled.
+    queued: Vec<(NodeId, LookupDone)>,
+}
+
+impl<T: Transport> DhtLoop<T> {
+    async fn run(mut self, mut shutdown: oneshot::Receiver<()>) {
+        loop {
+            let deadline = self.rpcs.values().map(|rpc| rpc.deadline).min();
+            tokio::select! {
+                _ = &mut shutdown => break,
+                command = self.commands.recv() => match command {
+                    Some(command) => self.handle_command(command).await,
+                    None => break,
+                },
+                received = self.transport.receive() => match received {
+                    Ok((packet, from)) => self.handle_packet(packet, from).await,
+                    Err(_) => break,
+                },
+                _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    self.expire_rpcs().await;
+                }
+            }
+
+            while let Some((target, done)) = self.queued.pop() {
+                self.start_lookup(target, done).await;
+            }
+        }
+
+        let _ = self.transport.close().await;
+    }
+
+    async fn handle_command(&mut self, command: DhtCommand) {
+        match command {
+            DhtCommand::Join { bootstrap, respond } => {
+                let sent = self.request(bootstrap, &Message::Ping, Purpose::Join(respond)).await;
+                if let Err(Purpose::Join(respond)) = sent {
+                    let _ = respond.send(Err(TaiorError::Discovery(format!("cannot reach {}", bootstrap))));
+                }
+            }
+            DhtCommand::FindNode { target, respond } => self.start_lookup(target, LookupDone::Nodes(respond)).await,
+            DhtCommand::Publish { descriptor, respond } => {
+                let target = NodeId::from_address(&descriptor.address()).expect("derived addresses are well formed");
+                self.start_lookup(target, LookupDone::Publish(descriptor, respond)).await;
+            }
+            DhtCommand::Lookup { target, respond } => self.start_lookup(target, LookupDone::Value(respond)).await,
+            DhtCommand::Contacts { respond } => {
+                let _ = respond.send(self.table.contacts());
+            }
+        }
+    }
+
+    async fn handle_packet(&mut self, packet: TaiorPacket, from: SocketAddr) {
+        let PacketFormat::Control { circuit_id } = packet.format else {
+            return;
+        };
+        let Ok((sender, message)) = Message::decode(&packet.encrypted_payload) else {
+            return;
+        };
+        if sender == self.id {
+            return;
+        }
+        self.table.insert(Contact { id: sender, endpoint: from });
+
+        if message.is_response() {
+            self.handle_response(circuit_id, from, message).await;
+            return;
+        }
+
+        let reply = match message {
+            Message::Ping => Message::Pong,
+            Message::Store(descriptor) => {
+                self.store(*descriptor);
+                Message::Pong
+            }
+            Message::FindNode(target) => Message::Nodes(self.table.closest(&target, self.config.k)),
+            Message::FindValue(target) => match self.record(&target) {
+                Some(descriptor) => Message::Value(Box::new(descriptor)),
+                None => Message::Nodes(self.table.closest(&target, self.config.k)),
+            },
+            Message::Pong | Message::Nodes(_) | Message::Value(_) => return,
+        };
+        let _ = self.send(circuit_id, from, &reply).await;
+    }
+
+    async fn handle_response(&mut self, rpc_id: [u8; CIRCUIT_ID_LEN], from: SocketAddr, message: Message) {
+        if self.rpcs.get(&rpc_id).is_none_or(|rpc| rpc.endpoint != from) {
+            return;
+        }
+        let Some(rpc) = self.rpcs.remove(&rpc_id) else {
+            return;
+        };
+
+        match rpc.purpose {
+            Purpose::Join(respond) => self.start_lookup(self.id, LookupDone::Join(respond)).await,
+            Purpose::Query(lookup_id, queried) => {
+                let Some(lookup) = self.lookups.get_mut(&lookup_id) else {
+                    return;
+                };
+                lookup.mark(&queried, CandidateState::Answered);
+                match message {
+                    Message::Nodes(contacts) => lookup.merge(contacts, &self.id),
+                    // Older revisions may linger at nodes that have since stopped being
+                    // among the closest, so the lookup keeps going and keeps the newest
+                    Message::Value(descriptor) => {
+                        let valid = descriptor.verify().is_ok()
+                            && NodeId::from_address(&descriptor.address()) == Some(lookup.target);
+                        if valid && lookup.value.as_ref().is_none_or(|known| known.version < descriptor.version) {
+                            lookup.value = Some(*descriptor);
+                        }
+                    }
+                    _ => {}
+                }
+                self.advance(lookup_id).await;
+            }
+        }
+    }
+
+    async fn expire_rpcs(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<[u8; CIRCUIT_ID_LEN]> = self.rpcs
+            .iter()
+            .filter(|(_, rpc)| rpc.deadline <= now)
+            .map(|(id, _)| *id)
+            .collect();
+
+        for rpc_id in expired {
+            let Some(rpc) = self.rpcs.remove(&rpc_id) else {
+                continue;
+            };
+            match rpc.purpose {
+                Purpose::Join(respond) => {
+                    let _ = respond.send(Err(TaiorError::Discovery(format!("{} did not answer", rpc.endpoint))));
+                }
+                Purpose::Query(lookup_id, queried) => {
+                    if let Some(lookup) = self.lookups.get_mut(&lookup_id) {
+                        lookup.mark(&queried, CandidateState::Failed);
+                    }
+                    self.table.remove(&queried);
+                    self.advance(lookup_id).await;
+                }
+            }
+        }
+    }
+
+    async fn start_lookup(&mut self, target: NodeId, done: LookupDone) {
+        let lookup_id = self.next_lookup;
+        self.next_lookup += 1;
+
+        let value = match done {
+            LookupDone::Value(_) => self.record(&target),
+            _ => None,
+        };
+        let mut lookup = Lookup { target, candidates: Vec::new(), value, done };
+        lookup.merge(self.table.closest(&target, self.config.k), &self.id);
+        self.lookups.insert(lookup_id, lookup);
+        self.advance(lookup_id).await;
+    }
+
+    /// Asks further candidates while fewer than `alpha` queries are in flight, and
+    /// completes the lookup once the `k` closest candidates left have all answered.
+    async fn advance(&mut self, lookup_id: u64) {
+        let Some(mut lookup) = self.lookups.remove(&lookup_id) else {
+            return;
+        };
+
+        loop {
+            let live = || lookup.candidates.iter().filter(|c| c.state != CandidateState::Failed).take(self.config.k);
+            let waiting = live().filter(|c| c.state == CandidateState::Waiting).count();
+            let next = live().find(|c| c.state == CandidateState::Fresh).map(|c| c.contact);
+            let Some(contact) = next.filter(|_| waiting < self.config.alpha) else {
+                break;
+            };
+
+            let message = match lookup.done {
+                LookupDone::Value(_) => Message::FindValue(lookup.target),
+                _ => Message::FindNode(lookup.target),
+            };
+            let sent = self.request(contact.endpoint, &message, Purpose::Query(lookup_id, contact.id)).await;
+            let state = if sent.is_ok() { CandidateState::Waiting } else { CandidateState::Failed };
+            lookup.mark(&contact.id, state);
+        }
+
+        if lookup.candidates.iter().any(|c| c.state == CandidateState::Waiting) {
+            self.lookups.insert(lookup_id, lookup);
+            return;
+        }
+
+        let closest: Vec<Contact> = lookup.candidates
+            .iter()
+            .filter(|c| c.state == CandidateState::Answered)
+            .take(self.config.k)
+            .map(|c| c.contact)
+            .collect();
+        let unreachable = || TaiorError::Discovery("no DHT node answered".to_string());
+
+        match lookup.done {
+            LookupDone::Join(respond) => match closest.first() {
+                Some(nearest) => {
+                    let nearest = self.table.bucket_index(&nearest.id).unwrap_or(0);
+                    let refresh = (nearest + 1..256).map(|bucket| self.id.random_in_bucket(bucket)).collect();
+                    self.refresh_next(respond, refresh);
+                }
+                None => {
+                    let _ = respond.send(Err(unreachable()));
+                }
+            },
+            LookupDone::Refresh(respond, refresh) => self.refresh_next(respond, refresh),
+            LookupDone::Nodes(respond) => {
+                let _ = respond.send(if closest.is_empty() { Err(unreachable()) } else { Ok(closest) });
+            }
+            LookupDone::Value(respond) => {
+                let _ = respond.send(Ok(lookup.value));
+            }
+            LookupDone::Publish(descriptor, respond) => {
+                let message = Message::Store(descriptor.clone());
+                let mut stored = 0;
+                for contact in &closest {
+                    if self.send(random_rpc_id(), contact.endpoint, &message).await.is_ok() {
+                        stored += 1;
+                    }
+                }
+                let _ = respond.send(if closest.is_empty() { Err(unreachable()) } else { Ok(stored) });
+            }
+        }
+    }
+
+    fn refresh_next(&mut self, respond: oneshot::Sender<Result<(), TaiorError>>, mut refresh: Vec<NodeId>) {
+        match refresh.pop() {
+            Some(target) => self.queued.push((target, LookupDone::Refresh(respond, refresh))),
+            None => {
+                let _ = respond.send(Ok(()));
+            }
+        }
+    }
+
+    /// Sends a request and tracks it until it is answered or times out. Hands
+    /// `purpose` back if the request could not be sent.
+    async fn request(&mut self, endpoint: SocketAddr, message: &Message, purpose: Purpose) -> Result<(), Purpose> {
+        let rpc_id = random_rpc_id();
+        if self.send(rpc_id, endpoint, message).await.is_err() {
+            return Err(purpose);
+        }
+        self.rpcs.insert(rpc_id, Rpc { endpoint, deadline: Instant::now() + self.config.rpc_timeout, purpose });
+        Ok(())
+    }
+
+    async fn send(&mut self, rpc_id: [u8; CIRCUIT_ID_LEN], dest: SocketAddr, message: &Message) -> Result<(), TaiorError> {
+        let packet = TaiorPacket::control(rpc_id, message.encode(&self.id));
+        Ok(self.transport.send(&packet, dest).await?)
+    }
+
+    /// Keeps a verified descriptor stored under its address, unless a newer one is or
+    /// another one with the same revision.
+    fn store(&mut self, descriptor: NodeDescriptor) {
+        if descriptor.verify().is_err() {
+            return;
+        }
+        let Some(key) = NodeId::from_address(&descriptor.address()) else {
+            return;
+        };
+
+        let now = Instant::now();
+        self.records.retain(|_, record| record.expires > now);
+        if let Some(record) = self.records.get(&key) {
+            let current = &record.descriptor;
+            if current.version > descriptor.version || (current.version == descriptor.version && *current != descriptor) {
+                return;
+            }
+        }
+        let expires = now + self.config.record_ttl;
+        self.records.insert(key, Record { descriptor, expires });
+    }
+
+    fn record(&self, key: &NodeId) -> Option<NodeDescriptor> {
+        self.records
+            .get(key)
+            .filter(|record| record.expires > Instant::now() && record.descriptor.verify().is_ok())
+            .map(|record| record.descriptor.clone())
+    }
+}
+
+fn random_rpc_id() -> [u8; CIRCUIT_ID_LEN] {
+    let mut rpc_id = [0u8; CIRCUIT_ID_LEN];
+    OsRng.fill_bytes(&mut rpc_id);
+    rpc_id
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::circuit::current_timestamp;
+    use crate::descriptor::{Capabilities, Endpoint};
+    use crate::transport::UdpTransport;
+    use ed25519_dalek::SigningKey;
+    use x25519_dalek::StaticSecret;
+
+    const NODES: usize = 32;
+
+    fn config() -> DhtConfig {
+        // Small buckets, so that lookups have to walk towards their target
+        DhtConfig {
+            k: 4,
+            alpha: 2,
+            rpc_timeout: Duration::from_millis(500),
+            ..DhtConfig::default()
+        }
+    }
+
+    async fn spawn_node() -> (DhtNode, SocketAddr) {
+        let transport = UdpTransport::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let endpoint = transport.local_addr().unwrap();
+        (DhtNode::spawn(NodeId::random(), transport, config()), endpoint)
+    }
+
+    async fn network() -> Vec<(DhtNode, SocketAddr)> {
+        let mut nodes = vec![spawn_node().await];
+        for _ in 1..NODES {
+            let node = spawn_node().await;
+            node.0.join(nodes[0].1).await.unwrap();
+            nodes.push(node);
+        }
+        nodes
+    }
+
+    fn descriptor(port: u16, version: u32) -> NodeDescriptor {
+        let signing_key = SigningKey::from_bytes(&[port as u8; 32]);
+        let onion_secret = StaticSecret::from([port as u8; 32]);
+        let endpoint = Endpoint::Udp(SocketAddr::from(([10, 0, 0, 1], port)));
+        NodeDescriptor::sign(&signing_key, &onion_secret, vec![endpoint], Capabilities::RELAY, version, current_timestamp() + 600)
+    }
+
+    #[test]
+    fn test_messages_roundtrip() {
+        let sender = NodeId::random();
+        let contacts = vec![
+            Contact { id: NodeId::random(), endpoint: "127.0.0.1:9000".parse().unwrap() },
+            Contact { id: NodeId::random(), endpoint: "[::1]:9001".parse().unwrap() },
+        ];
+        for message in [
+            Message::Ping,
+            Message::FindValue(NodeId::random()),
+            Message::Nodes(contacts),
+            Message::Store(Box::new(descriptor(7, 1))),
+        ] {
+            assert_eq!(Message::decode(&message.encode(&sender)).unwrap(), (sender, message));
+        }
+        assert!(Message::decode(&[CONTROL_DHT, KIND_FIND_NODE]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_lookups_find_closest_nodes_and_descriptors() {
+        let nodes = network().await;
+        let ids: Vec<NodeId> = nodes.iter().map(|(node, _)| node.id()).collect();
+
+        for (node, _) in &nodes {
+            assert!(!node.contacts().await.unwrap().is_empty());
+        }
+
+        // Lookups from anywhere converge on the nodes actually closest to the target
+        let target = NodeId::random();
+        let mut expected = ids.clone();
+        expected.sort_by_key(|id| id.distance(&target));
+        for (node, _) in nodes.iter().step_by(7) {
+            let found = node.find_node(target).await.unwrap();
+            let closest = found.iter().map(|contact| contact.id).filter(|id| *id != node.id());
+            let expected = expected.iter().copied().filter(|id| *id != node.id());
+            assert_eq!(closest.take(2).collect::<Vec<_>>(), expected.take(2).collect::<Vec<_>>());
+        }
+
+        let published = descriptor(9000, 1);
+        let stored = nodes[5].0.publish(published.clone()).await.unwrap();
+        assert_eq!(stored, config().k);
+        for (node, _) in nodes.iter().skip(1).step_by(6) {
+            assert_eq!(node.lookup(&published.address()).await.unwrap(), Some(published.clone()));
+        }
+
+        // A newer revision replaces the stored one
+        let republished = descriptor(9000, 2);
+        nodes[20].0.publish(republished.clone()).await.unwrap();
+        assert_eq!(nodes[11].0.lookup(&published.address()).await.unwrap(), Some(republished));
+
+        let (_, unknown) = TaiorAddress::generate();
+        assert_eq!(nodes[3].0.lookup(&unknown).await.unwrap(), None);
+
+        for (node, _) in nodes {
+            node.shutdown().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_nodes_time_out() {
+        let (node, _) = spawn_node().await;
+        let (silent, silent_endpoint) = spawn_node().await;
+        silent.shutdown().await.unwrap();
+
+        let err = node.join(silent_endpoint).await.unwrap_err();
+        assert!(matches!(err, TaiorError::Discovery(_)));
+        assert!(matches!(node.find_node(NodeId::random()).await, Err(TaiorError::Discovery(_))));
+    }
+}
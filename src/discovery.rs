@@ -1,13 +1,69 @@
-use std::collections::HashSet;
+use crate::circuit::{current_timestamp, current_timestamp_ms};
+use crate::descriptor::{DescriptorError, NodeDescriptor};
+use crate::identity::TaiorAddress;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use x25519_dalek::PublicKey;
+
+/// Consecutive failures after which a node is left out of the neighbours for a while.
+pub const QUARANTINE_AFTER_FAILURES: u32 = 2;
+/// First quarantine; each further failure doubles it up to `MAX_QUARANTINE`.
+pub const BASE_QUARANTINE: Duration = Duration::from_secs(5);
+pub const MAX_QUARANTINE: Duration = Duration::from_secs(5 * 60);
+/// Consecutive failures after which a node is forgotten.
+pub const EVICT_AFTER_FAILURES: u32 = 8;
+/// Time after which a node that was seen once and has been silent since is forgotten.
+pub const STALE_AFTER: Duration = Duration::from_secs(30 * 60);
+
+/// Weight of a new round-trip sample in the smoothed estimate.
+const RTT_SMOOTHING: f64 = 0.125;
+
+/// What is known about a node reachable as a destination.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KnownNode {
+    /// Where the node is reached: its bootstrap entry or endpoint.
+    pub node: String,
+    /// Identity key that packets to the node are sealed to, when known.
+    pub public_key: Option<PublicKey>,
+    /// Verified descriptor the node published, when it was learned from one.
+    pub descriptor: Option<NodeDescriptor>,
+}
+
+/// How a known node has been behaving, fed from transport results and keepalives.
+/// Times are milliseconds since the Unix epoch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct NodeHealth {
+    /// When the node was last heard from.
+    pub last_seen: Option<u64>,
+    /// Failures since it was last heard from.
+    pub failures: u32,
+    /// Smoothed round-trip time.
+    pub rtt: Option<Duration>,
+    /// Until when the node is left out of the neighbours.
+    pub quarantined_until: Option<u64>,
+}
+
+impl NodeHealth {
+    pub fn is_live_at(&self, now: u64) -> bool {
+        self.quarantined_until.is_none_or(|until| now >= until)
+    }
+}
 
 pub struct NodeDiscovery {
     known_nodes: HashSet<String>,
+    addresses: HashMap<TaiorAddress, KnownNode>,
+    health: HashMap<String, NodeHealth>,
+    /// Nodes found on the local network.
+    local: HashSet<String>,
 }
 
 impl NodeDiscovery {
     pub fn new() -> Self {
         Self {
             known_nodes: HashSet::new(),
+            addresses: HashMap::new(),
+            health: HashMap::new(),
+            local: HashSet::new(),
         }
     }
 
@@ -19,16 +75,211 @@ impl NodeDiscovery {
         discovery
     }
 
-    pub fn add_node(&mut self, node: String) {
+    /// Adds a node whose key is unknown and returns the address it resolves from.
+    /// The address is made up locally, so packets to it cannot be sealed.
+    pub fn add_node(&mut self, node: String) -> TaiorAddress {
+        if let Some(address) = self.address_of(&node) {
+            return address.clone();
+        }
+
+        let (_, address) = TaiorAddress::generate();
+        self.addresses.insert(address.clone(), KnownNode { node: node.clone(), public_key: None, descriptor: None });
         self.known_nodes.insert(node);
+        address
+    }
+
+    /// Adds a node together with its identity key. It resolves from the address
+    /// derived from the key, replacing any address made up for it before.
+    pub fn add_node_with_key(&mut self, node: String, public_key: PublicKey) -> TaiorAddress {
+        self.addresses.retain(|_, known| known.node != node);
+
+        let address = TaiorAddress::from_public_key(&public_key);
+        self.addresses.insert(address.clone(), KnownNode { node: node.clone(), public_key: Some(public_key), descriptor: None });
+        self.known_nodes.insert(node);
+        address
+    }
+
+    /// Adds a node from its descriptor once the descriptor verifies. It resolves from
+    /// the address derived from its onion key; a newer revision replaces the one
+    /// stored, wherever the node moved, while an older one, or another descriptor
+    /// with the same revision, is refused.
+    pub fn add_descriptor(&mut self, descriptor: NodeDescriptor) -> Result<TaiorAddress, DescriptorError> {
+        descriptor.verify()?;
+
+        let address = descriptor.address();
+        if let Some(known) = self.addresses.get(&address) {
+            if let Some(current) = &known.descriptor {
+                if *current == descriptor {
+                    return Ok(address);
+                }
+                if current.version > descriptor.version {
+                    return Err(DescriptorError::Stale(descriptor.version, current.version));
+                }
+                if current.version == descriptor.version {
+                    return Err(DescriptorError::Conflict(descriptor.version));
+                }
+            }
+            let previous = known.node.clone();
+            self.remove_node(&previous);
+        }
+
+        let node = descriptor.node_name();
+        self.add_node_with_key(node.clone(), descriptor.onion_key);
+        if let Some(known) = self.addresses.get_mut(&address) {
+            known.descriptor = Some(descriptor);
+        }
+        Ok(address)
+    }
+
+    /// Like `add_descriptor`, tagging the node as found on the local network. The tag
+    /// goes when the node is removed or learned again from elsewhere.
+    pub fn add_local_descriptor(&mut self, descriptor: NodeDescriptor) -> Result<TaiorAddress, DescriptorError> {
+        let address = self.add_descriptor(descriptor)?;
+        if let Some(known) = self.addresses.get(&address) {
+            self.local.insert(known.node.clone());
+        }
+        Ok(address)
+    }
+
+    pub fn is_local(&self, node: &str) -> bool {
+        self.local.contains(node)
+    }
+
+    pub fn descriptor(&self, address: &TaiorAddress) -> Option<&NodeDescriptor> {
+        self.addresses.get(address)?.descriptor.as_ref()
+    }
+
+    pub fn descriptors(&self) -> impl Iterator<Item = &NodeDescriptor> {
+        self.addresses.values().filter_map(|known| known.descriptor.as_ref())
+    }
+
+    /// Forgets the nodes whose descriptor has expired and returns their names.
+    pub fn remove_expired(&mut self) -> Vec<String> {
+        let now = current_timestamp();
+        let expired: Vec<String> = self.addresses
+            .values()
+            .filter(|known| known.descriptor.as_ref().is_some_and(|d| d.is_expired_at(now)))
+            .map(|known| known.node.clone())
+            .collect();
+        for node in &expired {
+            self.remove_node(node);
+        }
+        expired
+    }
+
+    /// Finds the node behind a destination address.
+    pub fn resolve(&self, address: &TaiorAddress) -> Option<&KnownNode> {
+        self.addresses.get(address)
+    }
+
+    pub fn address_of(&self, node: &str) -> Option<&TaiorAddress> {
+        self.addresses
+            .iter()
+            .find(|(_, known)| known.node == node)
+            .map(|(address, _)| address)
     }
 
+    /// Known nodes that are not quarantined.
     pub fn get_neighbors(&self) -> Vec<String> {
-        self.known_nodes.iter().cloned().collect()
+        self.neighbors_at(current_timestamp_ms())
+    }
+
+    fn neighbors_at(&self, now: u64) -> Vec<String> {
+        self.known_nodes
+            .iter()
+            .filter(|node| self.health.get(*node).is_none_or(|health| health.is_live_at(now)))
+            .cloned()
+            .collect()
+    }
+
+    pub fn health(&self, node: &str) -> Option<&NodeHealth> {
+        self.health.get(node)
+    }
+
+    /// Records that `node` was heard from, lifting any quarantine, and how long it
+    /// took to answer when it answered a request.
+    pub fn record_seen(&mut self, node: &str, rtt: Option<Duration>) {
+        self.record_seen_at(node, rtt, current_timestamp_ms());
+    }
+
+    fn record_seen_at(&mut self, node: &str, rtt: Option<Duration>, now: u64) {
+        if !self.known_nodes.contains(node) {
+            return;
+        }
+        let health = self.health.entry(node.to_string()).or_default();
+        health.last_seen = Some(now);
+        health.failures = 0;
+        health.quarantined_until = None;
+        if let Some(sample) = rtt {
+            health.rtt = Some(match health.rtt {
+                Some(rtt) => rtt.mul_f64(1.0 - RTT_SMOOTHING) + sample.mul_f64(RTT_SMOOTHING),
+                None => sample,
+            });
+        }
+    }
+
+    /// Records that `node` failed to take a packet or to answer. Repeated failures
+    /// quarantine it, for twice as long with each further one.
+    pub fn record_failure(&mut self, node: &str) {
+        self.record_failure_at(node, current_timestamp_ms());
+    }
+
+    fn record_failure_at(&mut self, node: &str, now: u64) {
+        if !self.known_nodes.contains(node) {
+            return;
+        }
+        let health = self.health.entry(node.to_string()).or_default();
+        health.failures += 1;
+        if health.failures >= QUARANTINE_AFTER_FAILURES {
+            let doublings = (health.failures - QUARANTINE_AFTER_FAILURES).min(16);
+            let quarantine = BASE_QUARANTINE.saturating_mul(1 << doublings).min(MAX_QUARANTINE);
+            health.quarantined_until = Some(now + quarantine.as_millis() as u64);
+        }
+    }
+
+    /// Nodes to probe: those out of quarantine that have not been heard from for
+    /// `interval`.
+    pub fn due_for_keepalive(&self, interval: Duration) -> Vec<String> {
+        let now = current_timestamp_ms();
+        let interval = interval.as_millis() as u64;
+        self.known_nodes
+            .iter()
+            .filter(|node| {
+                let health = self.health.get(*node).copied().unwrap_or_default();
+                health.is_live_at(now) && health.last_seen.is_none_or(|seen| now.saturating_sub(seen) >= interval)
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Forgets the nodes that kept failing, and those that were seen once but have
+    /// been silent for `STALE_AFTER`, and returns their names. Nodes never heard
+    /// from, such as bootstrap entries, stay until they fail.
+    pub fn evict_stale(&mut self) -> Vec<String> {
+        self.evict_stale_at(current_timestamp_ms())
+    }
+
+    fn evict_stale_at(&mut self, now: u64) -> Vec<String> {
+        let stale_after = STALE_AFTER.as_millis() as u64;
+        let stale: Vec<String> = self.health
+            .iter()
+            .filter(|(_, health)| {
+                health.failures >= EVICT_AFTER_FAILURES
+                    || health.last_seen.is_some_and(|seen| now.saturating_sub(seen) >= stale_after)
+            })
+            .map(|(node, _)| node.clone())
+            .collect();
+        for node in &stale {
+            self.remove_node(node);
+        }
+        stale
     }
 
     pub fn remove_node(&mut self, node: &str) {
         self.known_nodes.remove(node);
+        self.addresses.retain(|_, known| known.node != node);
+        self.health.remove(node);
+        self.local.remove(node);
     }
 
     pub fn count(&self) -> usize {
@@ -41,3 +292,60 @@ impl Default for NodeDiscovery {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::descriptor::{Capabilities, Endpoint};
+    use ed25519_dalek::SigningKey;
+    use std::net::SocketAddr;
+    use x25519_dalek::StaticSecret;
+
+    #[test]
+    fn test_failing_nodes_are_quarantined_then_evicted() {
+        let mut discovery = NodeDiscovery::with_bootstrap(vec!["a:1".to_string(), "b:1".to_string()]);
+        let now = 1_000_000;
+
+        discovery.record_seen_at("a:1", Some(Duration::from_millis(80)), now);
+        discovery.record_seen_at("a:1", Some(Duration::from_millis(160)), now);
+        assert_eq!(discovery.health("a:1").unwrap().rtt, Some(Duration::from_millis(90)));
+
+        // One failure is tolerated, the next ones quarantine for longer each time
+        discovery.record_failure_at("b:1", now);
+        assert_eq!(discovery.neighbors_at(now).len(), 2);
+        discovery.record_failure_at("b:1", now);
+        assert_eq!(discovery.neighbors_at(now), vec!["a:1".to_string()]);
+        let base = BASE_QUARANTINE.as_millis() as u64;
+        assert_eq!(discovery.neighbors_at(now + base).len(), 2);
+        discovery.record_failure_at("b:1", now + base);
+        assert_eq!(discovery.health("b:1").unwrap().quarantined_until, Some(now + 3 * base));
+
+        // Hearing from the node lifts the quarantine
+        discovery.record_seen_at("b:1", None, now + base);
+        assert_eq!(discovery.neighbors_at(now + base).len(), 2);
+
+        for _ in 0..EVICT_AFTER_FAILURES {
+            discovery.record_failure_at("b:1", now);
+        }
+        let stale = STALE_AFTER.as_millis() as u64;
+        assert_eq!(discovery.evict_stale_at(now), vec!["b:1".to_string()]);
+        assert_eq!(discovery.evict_stale_at(now + stale), vec!["a:1".to_string()]);
+        assert_eq!(discovery.count(), 0);
+    }
+
+    #[test]
+    fn test_local_tag_follows_where_the_node_was_learned() {
+        let descriptor = |version| {
+            let endpoint = Endpoint::Udp(SocketAddr::from(([192, 168, 1, 20], 7000)));
+            let onion_secret = StaticSecret::from([7; 32]);
+            NodeDescriptor::sign(&SigningKey::from_bytes(&[7; 32]), &onion_secret, vec![endpoint], Capabilities::RELAY, version, current_timestamp() + 600)
+        };
+        let mut discovery = NodeDiscovery::new();
+
+        discovery.add_local_descriptor(descriptor(1)).unwrap();
+        assert!(discovery.is_local("192.168.1.20:7000"));
+        discovery.add_descriptor(descriptor(2)).unwrap();
+        assert!(!discovery.is_local("192.168.1.20:7000"));
+        assert_eq!(discovery.count(), 1);
+    }
+}
@@ -0,0 +1,117 @@
+//! Randomness behind routing, circuit construction, padding and cover decisions.
+//! It always comes from the OS, unless the `insecure-research` feature is enabled
+//! and a seed is given: a seeded instance makes the same decisions on every run,
+//! so that simulations can be replayed exactly. Keys are always drawn from the OS.
+//!
+//! Seeded decisions are predictable by anyone who knows the seed, which makes
+//! routes and cover traffic trivially linkable. Never seed a deployed node.
+
+use aorp::EntropySource;
+#[cfg(feature = "insecure-research")]
+use rand::{rngs::StdRng, SeedableRng};
+use rand_core::{OsRng, RngCore};
+
+#[derive(Debug, Clone, Default)]
+pub struct Entropy {
+    #[cfg(feature = "insecure-research")]
+    seeded: Option<StdRng>,
+}
+
+impl Entropy {
+    pub fn secure() -> Self {
+        Self::default()
+    }
+
+    /// Deterministic decisions derived from `seed`.
+    #[cfg(feature = "insecure-research")]
+    pub fn from_seed(seed: u64) -> Self {
+        Self {
+            seeded: Some(StdRng::seed_from_u64(seed)),
+        }
+    }
+
+    pub fn is_seeded(&self) -> bool {
+        #[cfg(feature = "insecure-research")]
+        {
+            self.seeded.is_some()
+        }
+        #[cfg(not(feature = "insecure-research"))]
+        {
+            false
+        }
+    }
+
+    /// Splits off the entropy of a component. A seeded instance hands out a seed of
+    /// its own, so components draw from separate streams and one of them making more
+    /// decisions does not shift the others.
+    pub fn fork(&mut self) -> Self {
+        #[cfg(feature = "insecure-research")]
+        if self.is_seeded() {
+            return Self::from_seed(self.next_u64());
+        }
+        Self::secure()
+    }
+
+    /// Entropy for one decision of the AORP engine.
+    pub fn source(&mut self) -> EntropySource {
+        #[cfg(feature = "insecure-research")]
+        if self.is_seeded() {
+            return EntropySource::from_seed(self.next_u64());
+        }
+        EntropySource::secure_random()
+    }
+}
+
+impl RngCore for Entropy {
+    fn next_u32(&mut self) -> u32 {
+        #[cfg(feature = "insecure-research")]
+        if let Some(rng) = &mut self.seeded {
+            return rng.next_u32();
+        }
+        OsRng.next_u32()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        #[cfg(feature = "insecure-research")]
+        if let Some(rng) = &mut self.seeded {
+            return rng.next_u64();
+        }
+        OsRng.next_u64()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        #[cfg(feature = "insecure-research")]
+        if let Some(rng) = &mut self.seeded {
+            return rng.fill_bytes(dest);
+        }
+        OsRng.fill_bytes(dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        #[cfg(feature = "insecure-research")]
+        if let Some(rng) = &mut self.seeded {
+            return rng.try_fill_bytes(dest);
+        }
+        OsRng.try_fill_bytes(dest)
+    }
+}
+
+#[cfg(all(test, feature = "insecure-research"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seeded_entropy_replays() {
+        let draw = |seed| {
+            let mut entropy = Entropy::from_seed(seed);
+            let mut fork = entropy.fork();
+            (entropy.next_u64(), fork.next_u64())
+        };
+        assert_eq!(draw(7), draw(7));
+        assert_ne!(draw(7), draw(8));
+
+        let (parent, forked) = draw(7);
+        assert_ne!(parent, forked);
+        assert!(!Entropy::secure().fork().is_seeded());
+    }
+}
@@ -0,0 +1,59 @@
+//! Crate-wide error type returned by the public API. Errors from the individual
+//! layers convert into it, so callers can tell e.g. a lack of relays
+//! (`TaiorError::Circuit(CircuitError::InsufficientNodes(_))`) from a packet that
+//! failed to decrypt (`TaiorError::DecryptionFailed`) without parsing messages.
+
+use crate::circuit::CircuitError;
+use crate::descriptor::DescriptorError;
+#[cfg(feature = "mix-mode")]
+use crate::cover_traffic::CoverTrafficError;
+use crate::identity::TaiorAddress;
+use crate::onion_relay::RelayError;
+use crate::profile::ProfileError;
+use crate::sphinx::SphinxError;
+use thiserror::Error;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::transport::TransportError;
+
+#[derive(Error, Debug)]
+pub enum TaiorError {
+    /// Encryption or key derivation failed, or a peer supplied an unusable key.
+    #[error("Crypto error: {0}")]
+    Crypto(String),
+    /// The packet did not authenticate under the key it was opened with.
+    #[error("Decryption failed")]
+    DecryptionFailed,
+    /// Bytes that do not form a valid packet, frame or reply block.
+    #[error("Invalid packet format: {0}")]
+    PacketFormat(String),
+    /// The destination does not resolve through the known nodes.
+    #[error("Unknown destination {}", .0.as_str())]
+    UnknownDestination(TaiorAddress),
+    /// A hop or endpoint could not be resolved through the known nodes.
+    #[error("Discovery error: {0}")]
+    Discovery(String),
+    /// The request cannot be carried out with the configured routing guarantees.
+    #[error("Policy violation: {0}")]
+    Policy(String),
+    /// The node runtime has stopped and no longer takes requests.
+    #[error("Node stopped")]
+    NodeStopped,
+    #[error(transparent)]
+    Circuit(#[from] CircuitError),
+    #[error(transparent)]
+    Sphinx(#[from] SphinxError),
+    #[error(transparent)]
+    Relay(#[from] RelayError),
+    #[error(transparent)]
+    Profile(#[from] ProfileError),
+    #[error(transparent)]
+    Descriptor(#[from] DescriptorError),
+    #[cfg(feature = "mix-mode")]
+    #[error(transparent)]
+    CoverTraffic(#[from] CoverTrafficError),
+    #[cfg(not(target_arch = "wasm32"))]
+    #[error(transparent)]
+    Transport(#[from] TransportError),
+}
+
+pub type TaiorResult<T> = Result<T, TaiorError>;
@@ -0,0 +1,286 @@
+//! Peer exchange: every round a node offers a random sample of the fresh, verified
+//! descriptors it knows to a few random neighbours, and each of them answers with a
+//! sample of its own. What is learned goes through `NodeDiscovery::add_descriptor`
+//! and expired descriptors are dropped every round, so the neighbour pool keeps
+//! growing and rotating without a central directory.
+//!
+//! Offers are taken from a neighbour at most once per `peer_interval`, and answers
+//! only from neighbours that were offered to, so no peer can flood the pool. Nothing
+//! received is trusted beyond its signature: a neighbour still chooses which
+//! descriptors it passes on.
+//!
+//! Messages are `PacketFormat::Control` packets:
+//!
+//! ```text
+//! [CONTROL_GOSSIP][kind: 1][count: 1]([length: u16 BE][descriptor])*
+//! ```
+
+use crate::circuit::current_timestamp;
+use crate::descriptor::{DescriptorError, NodeDescriptor};
+use crate::discovery::NodeDiscovery;
+use crate::entropy::Entropy;
+use crate::packet::CONTROL_GOSSIP;
+use rand::seq::SliceRandom;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Time between gossip rounds.
+pub const DEFAULT_GOSSIP_INTERVAL: Duration = Duration::from_secs(30);
+/// Neighbours offered to per round.
+pub const DEFAULT_FANOUT: usize = 3;
+/// Descriptors per offer or answer.
+pub const DEFAULT_SAMPLE_SIZE: usize = 8;
+/// Time that has to pass before another offer from the same neighbour is taken.
+pub const DEFAULT_PEER_INTERVAL: Duration = Duration::from_secs(10);
+/// Descriptors a message may carry, whatever the sender's configuration.
+pub const MAX_SAMPLE_SIZE: usize = 32;
+
+const KIND_OFFER: u8 = 0;
+const KIND_ANSWER: u8 = 1;
+
+#[derive(Debug, Clone, Copy)]
+pub struct GossipConfig {
+    pub interval: Duration,
+    pub fanout: usize,
+    pub sample_size: usize,
+    pub peer_interval: Duration,
+}
+
+impl Default for GossipConfig {
+    fn default() -> Self {
+        Self {
+            interval: DEFAULT_GOSSIP_INTERVAL,
+            fanout: DEFAULT_FANOUT,
+            sample_size: DEFAULT_SAMPLE_SIZE,
+            peer_interval: DEFAULT_PEER_INTERVAL,
+        }
+    }
+}
+
+/// What a gossip message taken from a neighbour yields.
+#[derive(Debug, Default)]
+pub struct Exchange {
+    /// Verified, unexpired descriptors to learn.
+    pub descriptors: Vec<NodeDescriptor>,
+    /// Answer to send back, when the message was an offer.
+    pub answer: Option<Vec<u8>>,
+}
+
+pub struct Gossip {
+    config: GossipConfig,
+    entropy: Entropy,
+    /// Our own descriptor, included in every sample.
+    own: Option<NodeDescriptor>,
+    /// When each neighbour's last offer was taken.
+    offers_taken: HashMap<SocketAddr, Instant>,
+    /// Neighbours offered to whose answer is still awaited, and when.
+    offered: HashMap<SocketAddr, Instant>,
+}
+
+impl Gossip {
+    pub fn new(config: GossipConfig) -> Self {
+        Self {
+            config,
+            entropy: Entropy::secure(),
+            own: None,
+            offers_taken: HashMap::new(),
+            offered: HashMap::new(),
+        }
+    }
+
+    /// Draws neighbours and samples from `entropy`.
+    pub fn with_entropy(mut self, entropy: Entropy) -> Self {
+        self.entropy = entropy;
+        self
+    }
+
+    pub fn config(&self) -> &GossipConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: GossipConfig) {
+        self.config = config;
+    }
+
+    /// Spreads `descriptor` in every sample from now on; it should be re-announced
+    /// with a newer version before it expires.
+    pub fn announce(&mut self, descriptor: NodeDescriptor) {
+        self.own = Some(descriptor);
+    }
+
+    /// Offers for this round: up to `fanout` random neighbours reachable at a socket
+    /// address, each with a sample of its own.
+    pub fn round(&mut self, discovery: &NodeDiscovery, now: Instant) -> Vec<(SocketAddr, Vec<u8>)> {
+        self.offers_taken.retain(|_, taken| now.duration_since(*taken) < self.config.peer_interval);
+        self.offered.retain(|_, sent| now.duration_since(*sent) < self.config.interval);
+
+        // Sorted first, so that seeded entropy draws the same neighbours every run
+        let mut neighbors: Vec<SocketAddr> = discovery
+            .get_neighbors()
+            .iter()
+            .filter_map(|node| node.parse().ok())
+            .collect();
+        neighbors.sort();
+        let chosen: Vec<SocketAddr> = neighbors
+            .choose_multiple(&mut self.entropy, self.config.fanout)
+            .copied()
+            .collect();
+
+        chosen
+            .into_iter()
+            .map(|peer| {
+                self.offered.insert(peer, now);
+                (peer, self.message(KIND_OFFER, discovery))
+            })
+            .collect()
+    }
+
+    /// Takes a gossip message from `from`, or returns `None` if it is malformed, an
+    /// offer that comes too soon or an answer nobody asked for.
+    pub fn receive(&mut self, from: SocketAddr, message: &[u8], discovery: &NodeDiscovery, now: Instant) -> Option<Exchange> {
+        let (kind, descriptors) = decode(message).ok()?;
+        let answer = match kind {
+            KIND_OFFER => {
+                if self.offers_taken.get(&from).is_some_and(|taken| now.duration_since(*taken) < self.config.peer_interval) {
+                    return None;
+                }
+                self.offers_taken.insert(from, now);
+                Some(self.message(KIND_ANSWER, discovery))
+            }
+            _ => {
+                self.offered.remove(&from)?;
+                None
+            }
+        };
+
+        // Descriptors already known as they are need not be checked again
+        let descriptors = descriptors
+            .into_iter()
+            .filter(|descriptor| discovery.descriptor(&descriptor.address()) != Some(descriptor))
+            .filter(|descriptor| descriptor.verify().is_ok())
+            .collect();
+        Some(Exchange { descriptors, answer })
+    }
+
+    /// A sample of the unexpired descriptors in `discovery`, which were verified as
+    /// they were added, after our own.
+    fn message(&mut self, kind: u8, discovery: &NodeDiscovery) -> Vec<u8> {
+        let now = current_timestamp();
+        let own_address = self.own.as_ref().map(NodeDescriptor::address);
+        let mut known: Vec<&NodeDescriptor> = discovery
+            .descriptors()
+            .filter(|descriptor| !descriptor.is_expired_at(now) && Some(descriptor.address()) != own_address)
+            .collect();
+        known.sort_by_key(|descriptor| descriptor.address().0);
+
+        let size = self.config.sample_size.min(MAX_SAMPLE_SIZE);
+        let mut sample: Vec<&NodeDescriptor> = self.own.iter().filter(|own| !own.is_expired_at(now)).collect();
+        let room = size.saturating_sub(sample.len());
+        sample.extend(known.choose_multiple(&mut self.entropy, room).copied());
+        encode(kind, &sample)
+    }
+}
+
+fn encode(kind: u8, descriptors: &[&NodeDescriptor]) -> Vec<u8> {
+    let mut bytes = vec![CONTROL_GOSSIP, kind, descriptors.len() as u8];
+    for descriptor in descriptors {
+        let encoded = descriptor.encode();
+        bytes.extend_from_slice(&(encoded.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&encoded);
+    }
+    bytes
+}
+
+fn decode(bytes: &[u8]) -> Result<(u8, Vec<NodeDescriptor>), DescriptorError> {
+    let malformed = |reason: &str| DescriptorError::Malformed(format!("gossip: {}", reason));
+    let [CONTROL_GOSSIP, kind, count, rest @ ..] = bytes else {
+        return Err(malformed("no header"));
+    };
+    if *kind > KIND_ANSWER || *count as usize > MAX_SAMPLE_SIZE {
+        return Err(malformed("bad header"));
+    }
+
+    let mut rest = rest;
+    let mut descriptors = Vec::with_capacity(*count as usize);
+    for _ in 0..*count {
+        let [high, low, tail @ ..] = rest else {
+            return Err(malformed("truncated"));
+        };
+        let length = u16::from_be_bytes([*high, *low]) as usize;
+        if tail.len() < length {
+            return Err(malformed("truncated"));
+        }
+        let (encoded, tail) = tail.split_at(length);
+        descriptors.push(NodeDescriptor::decode(encoded)?);
+        rest = tail;
+    }
+    if !rest.is_empty() {
+        return Err(malformed("trailing bytes"));
+    }
+    Ok((*kind, descriptors))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::descriptor::{Capabilities, Endpoint};
+    use ed25519_dalek::SigningKey;
+    use x25519_dalek::StaticSecret;
+
+    fn descriptor(seed: u8, expires_at: u64) -> NodeDescriptor {
+        let signing_key = SigningKey::from_bytes(&[seed; 32]);
+        let onion_secret = StaticSecret::from([seed; 32]);
+        let endpoint = Endpoint::Udp(SocketAddr::from(([127, 0, 0, 1], 7000 + seed as u16)));
+        NodeDescriptor::sign(&signing_key, &onion_secret, vec![endpoint], Capabilities::RELAY, 1, expires_at)
+    }
+
+    #[test]
+    fn test_offers_are_answered_and_rate_limited() {
+        let config = GossipConfig { fanout: 2, sample_size: 4, ..GossipConfig::default() };
+        let fresh = current_timestamp() + 600;
+
+        let mut alice_pool = NodeDiscovery::new();
+        for seed in 1..=6 {
+            alice_pool.add_descriptor(descriptor(seed, fresh)).unwrap();
+        }
+        let mut alice = Gossip::new(config);
+        alice.announce(descriptor(20, fresh));
+
+        let mut bob_pool = NodeDiscovery::new();
+        bob_pool.add_descriptor(descriptor(30, fresh)).unwrap();
+        let mut bob = Gossip::new(config);
+
+        let now = Instant::now();
+        let offers = alice.round(&alice_pool, now);
+        assert_eq!(offers.len(), 2);
+        let alice_endpoint = "127.0.0.1:9000".parse().unwrap();
+        let (bob_endpoint, offer) = offers[0].clone();
+
+        // The announced descriptor always travels, next to a random sample
+        let exchange = bob.receive(alice_endpoint, &offer, &bob_pool, now).unwrap();
+        assert_eq!(exchange.descriptors.len(), 4);
+        assert_eq!(exchange.descriptors[0], descriptor(20, fresh));
+        assert!(bob.receive(alice_endpoint, &offer, &bob_pool, now).is_none());
+        assert!(bob.receive(alice_endpoint, &offer, &bob_pool, now + config.peer_interval).is_some());
+
+        let answer = exchange.answer.unwrap();
+        let learned = alice.receive(bob_endpoint, &answer, &alice_pool, now).unwrap();
+        assert_eq!(learned.descriptors, vec![descriptor(30, fresh)]);
+        assert!(learned.answer.is_none());
+        assert!(alice.receive(bob_endpoint, &answer, &alice_pool, now).is_none());
+    }
+
+    #[test]
+    fn test_expired_and_malformed_descriptors_are_dropped() {
+        let expired = descriptor(1, current_timestamp() - 1);
+        let message = encode(KIND_OFFER, &[&expired]);
+        let exchange = Gossip::new(GossipConfig::default())
+            .receive("127.0.0.1:9000".parse().unwrap(), &message, &NodeDiscovery::new(), Instant::now())
+            .unwrap();
+        assert!(exchange.descriptors.is_empty());
+
+        assert!(decode(&message[..message.len() - 1]).is_err());
+        assert!(decode(&[CONTROL_GOSSIP, KIND_OFFER, MAX_SAMPLE_SIZE as u8 + 1]).is_err());
+    }
+}
@@ -0,0 +1,398 @@
+//! Telescoping circuit construction with an ntor-style handshake.
+//!
+//! The originator extends a circuit one hop at a time. For every hop it sends a
+//! fresh X25519 key; the hop answers with its own fresh key and an authenticator
+//! that only the holder of its long-lived onion key can compute. Both sides mix
+//! the two exchanges into a key seed and expand it into forward and backward keys.
+//! Later hops are reached through the layers of the hops already established, so
+//! no hop learns more of the path than its neighbours.
+
+use crate::circuit::{
+    build_layers, remove_backward_layers, Circuit, CircuitError, CircuitNode, HopKeys, LayerCommand,
+    SequenceWindow, BACKWARD_EXTENDED,
+};
+use crate::identity::TaiorAddress;
+use crate::packet::{PacketFormat, TaiorPacket, CIRCUIT_ID_LEN, CONTROL_CREATE, CONTROL_CREATED};
+use crate::sphinx::SphinxHop;
+use rand_core::{OsRng, RngCore};
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+
+#[cfg(not(target_arch = "wasm32"))]
+use crate::transport::Transport;
+#[cfg(not(target_arch = "wasm32"))]
+use std::{net::SocketAddr, time::Duration};
+
+/// Protocol identifier bound into every transcript.
+pub const PROTOCOL_ID: &[u8] = b"taior-ntor-x25519-blake3-1";
+/// `[type][relay address: 32][client key: 32]`
+pub const CREATE_LEN: usize = 1 + 32 + 32;
+/// `[type][relay key: 32][auth: 32]`
+pub const CREATED_LEN: usize = 1 + 32 + 32;
+
+const CONTEXT_KEY_SEED: &str = "taior ntor v1 key seed";
+const CONTEXT_VERIFY: &str = "taior ntor v1 verify";
+const CONTEXT_AUTH: &str = "taior ntor v1 server auth";
+const CONTEXT_EXPAND: &str = "taior ntor v1 key expansion";
+
+/// Originator half of one hop's handshake.
+pub struct NtorClient {
+    secret: StaticSecret,
+    public: PublicKey,
+    relay_id: [u8; 32],
+    onion_key: PublicKey,
+}
+
+impl NtorClient {
+    /// Starts a handshake with the relay at `relay`, whose address must be derived from `onion_key`.
+    pub fn new(relay: &TaiorAddress, onion_key: PublicKey) -> Result<Self, CircuitError> {
+        if !relay.matches_public_key(&onion_key) {
+            return Err(CircuitError::HandshakeFailed(format!(
+                "la clave onion no corresponde a {}",
+                relay.as_str()
+            )));
+        }
+        let relay_id = relay
+            .to_bytes()
+            .ok_or_else(|| CircuitError::HandshakeFailed(format!("dirección inválida {}", relay.as_str())))?;
+        let secret = StaticSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&secret);
+
+        Ok(Self {
+            secret,
+            public,
+            relay_id,
+            onion_key,
+        })
+    }
+
+    /// The CREATE message to deliver to the relay.
+    pub fn create_message(&self) -> Vec<u8> {
+        let mut message = Vec::with_capacity(CREATE_LEN);
+        message.push(CONTROL_CREATE);
+        message.extend_from_slice(&self.relay_id);
+        message.extend_from_slice(self.public.as_bytes());
+        message
+    }
+
+    /// Checks the relay's CREATED answer and derives the hop keys.
+    pub fn finish(&self, created: &[u8]) -> Result<HopKeys, CircuitError> {
+        if created.len() != CREATED_LEN || created[0] != CONTROL_CREATED {
+            return Err(CircuitError::HandshakeFailed("respuesta CREATED malformada".into()));
+        }
+        let relay_public = PublicKey::from(read_key(&created[1..33]));
+        let auth = &created[33..CREATED_LEN];
+
+        let ephemeral_shared = self.secret.diffie_hellman(&relay_public);
+        let static_shared = self.secret.diffie_hellman(&self.onion_key);
+        if !ephemeral_shared.was_contributory() || !static_shared.was_contributory() {
+            return Err(CircuitError::HandshakeFailed("clave de grupo inválida".into()));
+        }
+
+        let transcript = Transcript {
+            ephemeral_shared: ephemeral_shared.as_bytes(),
+            static_shared: static_shared.as_bytes(),
+            relay_id: &self.relay_id,
+            onion_key: &self.onion_key,
+            client_public: &self.public,
+            relay_public: &relay_public,
+        };
+        let (keys, expected_auth) = transcript.derive();
+
+        if !constant_time_eq(auth, &expected_auth) {
+            return Err(CircuitError::HandshakeFailed("autenticación del relay inválida".into()));
+        }
+        Ok(keys)
+    }
+}
+
+/// Relay half: answers a CREATE message with the relay's onion secret.
+/// Returns the CREATED message and the keys now shared with the originator.
+pub fn server_handshake(onion_secret: &StaticSecret, create: &[u8]) -> Result<(Vec<u8>, HopKeys), CircuitError> {
+    if create.len() != CREATE_LEN || create[0] != CONTROL_CREATE {
+        return Err(CircuitError::HandshakeFailed("mensaje CREATE malformado".into()));
+    }
+    let onion_key = PublicKey::from(onion_secret);
+    let relay_id = read_key(&create[1..33]);
+    if TaiorAddress::from_bytes(&relay_id) != TaiorAddress::from_public_key(&onion_key) {
+        return Err(CircuitError::HandshakeFailed("CREATE dirigido a otro relay".into()));
+    }
+    let client_public = PublicKey::from(read_key(&create[33..CREATE_LEN]));
+
+    let ephemeral = EphemeralSecret::random_from_rng(OsRng);
+    let relay_public = PublicKey::from(&ephemeral);
+    let ephemeral_shared = ephemeral.diffie_hellman(&client_public);
+    let static_shared = onion_secret.diffie_hellman(&client_public);
+    if !ephemeral_shared.was_contributory() || !static_shared.was_contributory() {
+        return Err(CircuitError::HandshakeFailed("clave de grupo inválida".into()));
+    }
+
+    let transcript = Transcript {
+        ephemeral_shared: ephemeral_shared.as_bytes(),
+        static_shared: static_shared.as_bytes(),
+        relay_id: &relay_id,
+        onion_key: &onion_key,
+        client_public: &client_public,
+        relay_public: &relay_public,
+    };
+    let (keys, auth) = transcript.derive();
+
+    let mut created = Vec::with_capacity(CREATED_LEN);
+    created.push(CONTROL_CREATED);
+    created.extend_from_slice(relay_public.as_bytes());
+    created.extend_from_slice(&auth);
+    Ok((created, keys))
+}
+
+/// Inputs both sides hash identically once they hold the two shared secrets.
+struct Transcript<'a> {
+    ephemeral_shared: &'a [u8; 32],
+    static_shared: &'a [u8; 32],
+    relay_id: &'a [u8; 32],
+    onion_key: &'a PublicKey,
+    client_public: &'a PublicKey,
+    relay_public: &'a PublicKey,
+}
+
+impl Transcript<'_> {
+    fn derive(&self) -> (HopKeys, [u8; 32]) {
+        let mut secret_input = Vec::with_capacity(32 * 6 + PROTOCOL_ID.len());
+        secret_input.extend_from_slice(self.ephemeral_shared);
+        secret_input.extend_from_slice(self.static_shared);
+        secret_input.extend_from_slice(self.relay_id);
+        secret_input.extend_from_slice(self.onion_key.as_bytes());
+        secret_input.extend_from_slice(self.client_public.as_bytes());
+        secret_input.extend_from_slice(self.relay_public.as_bytes());
+        secret_input.extend_from_slice(PROTOCOL_ID);
+
+        let key_seed = blake3::derive_key(CONTEXT_KEY_SEED, &secret_input);
+        let verify = blake3::derive_key(CONTEXT_VERIFY, &secret_input);
+
+        let mut auth_input = Vec::with_capacity(32 * 5 + PROTOCOL_ID.len() + 6);
+        auth_input.extend_from_slice(&verify);
+        auth_input.extend_from_slice(self.relay_id);
+        auth_input.extend_from_slice(self.onion_key.as_bytes());
+        auth_input.extend_from_slice(self.relay_public.as_bytes());
+        auth_input.extend_from_slice(self.client_public.as_bytes());
+        auth_input.extend_from_slice(PROTOCOL_ID);
+        auth_input.extend_from_slice(b"Server");
+        let auth = blake3::derive_key(CONTEXT_AUTH, &auth_input);
+
+        let mut okm = [0u8; 32 + 12 + 32 + 12];
+        blake3::Hasher::new_derive_key(CONTEXT_EXPAND)
+            .update(&key_seed)
+            .finalize_xof()
+            .fill(&mut okm);
+
+        let mut keys = HopKeys {
+            forward_key: [0u8; 32],
+            forward_nonce: [0u8; 12],
+            backward_key: [0u8; 32],
+            backward_nonce: [0u8; 12],
+        };
+        keys.forward_key.copy_from_slice(&okm[..32]);
+        keys.forward_nonce.copy_from_slice(&okm[32..44]);
+        keys.backward_key.copy_from_slice(&okm[44..76]);
+        keys.backward_nonce.copy_from_slice(&okm[76..]);
+        (keys, auth)
+    }
+}
+
+/// What to do after feeding a reply into `CircuitHandshake`.
+#[derive(Debug)]
+pub enum HandshakeStep {
+    /// Send this packet to the entry hop to extend the circuit by one more hop.
+    Extend(TaiorPacket),
+    /// Every hop answered; the circuit is ready.
+    Complete(Circuit),
+}
+
+/// Originator-side state machine for building a circuit hop by hop. It does no
+/// I/O: the caller sends the packets it produces to the entry hop and feeds the
+/// entry hop's replies back in.
+pub struct CircuitHandshake {
+    path: Vec<SphinxHop>,
+    established: Vec<CircuitNode>,
+    pending: NtorClient,
+    pending_circuit_id: [u8; CIRCUIT_ID_LEN],
+    ttl_seconds: u64,
+}
+
+impl CircuitHandshake {
+    /// Starts building a circuit along `path`. The returned packet is the CREATE
+    /// message for the first hop.
+    pub fn start(path: Vec<SphinxHop>, ttl_seconds: u64) -> Result<(Self, TaiorPacket), CircuitError> {
+        let first = path.first().ok_or(CircuitError::InsufficientNodes(1))?;
+        let pending = NtorClient::new(&first.address, first.onion_key)?;
+        let pending_circuit_id = random_circuit_id();
+        let packet = TaiorPacket::control(pending_circuit_id, pending.create_message());
+
+        Ok((
+            Self {
+                path,
+                established: Vec::new(),
+                pending,
+                pending_circuit_id,
+                ttl_seconds,
+            },
+            packet,
+        ))
+    }
+
+    /// Index in the path of the hop whose answer is awaited.
+    pub fn hop_index(&self) -> usize {
+        self.established.len()
+    }
+
+    /// Whether `packet` looks like the answer to the pending extension.
+    pub fn is_reply(&self, packet: &TaiorPacket) -> bool {
+        match (self.established.first(), packet.format) {
+            (None, PacketFormat::Control { circuit_id }) => circuit_id == self.pending_circuit_id,
+            (Some(entry), PacketFormat::Onion { circuit_id }) => circuit_id == entry.circuit_id,
+            _ => false,
+        }
+    }
+
+    /// Consumes the answer to the pending extension.
+    pub fn handle_reply(&mut self, packet: &TaiorPacket) -> Result<HandshakeStep, CircuitError> {
+        if !self.is_reply(packet) {
+            return Err(CircuitError::HandshakeFailed("respuesta de otro circuito".into()));
+        }
+
+        // Answers to extensions come back through every established hop's backward layer
+        let keys = if self.established.is_empty() {
+            self.pending.finish(&packet.encrypted_payload)?
+        } else {
+            let plaintext = remove_backward_layers(&mut self.established, &packet.encrypted_payload)?;
+            match plaintext.split_first() {
+                Some((&BACKWARD_EXTENDED, created)) => self.pending.finish(created)?,
+                _ => return Err(CircuitError::HandshakeFailed("respuesta EXTENDED malformada".into())),
+            }
+        };
+
+        let hop = &self.path[self.established.len()];
+        self.established.push(CircuitNode {
+            address: hop.address.clone(),
+            circuit_id: self.pending_circuit_id,
+            keys,
+            forward_seq: 0,
+            backward_window: SequenceWindow::default(),
+            onion_key: hop.onion_key,
+        });
+
+        let Some(next) = self.path.get(self.established.len()) else {
+            let nodes = std::mem::take(&mut self.established);
+            return Ok(HandshakeStep::Complete(Circuit::new(nodes, self.ttl_seconds)));
+        };
+
+        self.pending = NtorClient::new(&next.address, next.onion_key)?;
+        self.pending_circuit_id = random_circuit_id();
+        let command = LayerCommand::Extend {
+            next_hop: next.address.clone(),
+            circuit_id: self.pending_circuit_id,
+        };
+        let onion = build_layers(&mut self.established, command, &self.pending.create_message())?;
+
+        Ok(HandshakeStep::Extend(TaiorPacket {
+            encrypted_payload: onion,
+            ikm: Vec::new(),
+            ephemeral_public: None,
+            ttl: self.established.len() as u8,
+            format: PacketFormat::Onion {
+                circuit_id: self.established[0].circuit_id,
+            },
+        }))
+    }
+}
+
+/// Drives `CircuitHandshake` over `transport`, talking only to the entry hop at
+/// `entry`. Unrelated packets received meanwhile are discarded. Fails with
+/// `HopTimeout(i)` when hop `i` does not answer within `hop_timeout`.
+#[cfg(not(target_arch = "wasm32"))]
+pub async fn establish_circuit<T: Transport + ?Sized>(
+    transport: &mut T,
+    entry: SocketAddr,
+    path: Vec<SphinxHop>,
+    ttl_seconds: u64,
+    hop_timeout: Duration,
+) -> Result<Circuit, CircuitError> {
+    let (mut handshake, mut request) = CircuitHandshake::start(path, ttl_seconds)?;
+
+    loop {
+        transport
+            .send(&request, entry)
+            .await
+            .map_err(|e| CircuitError::Transport(e.to_string()))?;
+
+        let hop = handshake.hop_index();
+        let deadline = tokio::time::Instant::now() + hop_timeout;
+        let reply = loop {
+            let (packet, from) = tokio::time::timeout_at(deadline, transport.receive())
+                .await
+                .map_err(|_| CircuitError::HopTimeout(hop))?
+                .map_err(|e| CircuitError::Transport(e.to_string()))?;
+            if from == entry && handshake.is_reply(&packet) {
+                break packet;
+            }
+        };
+
+        match handshake.handle_reply(&reply)? {
+            HandshakeStep::Extend(next) => request = next,
+            HandshakeStep::Complete(circuit) => return Ok(circuit),
+        }
+    }
+}
+
+fn random_circuit_id() -> [u8; CIRCUIT_ID_LEN] {
+    let mut circuit_id = [0u8; CIRCUIT_ID_LEN];
+    OsRng.fill_bytes(&mut circuit_id);
+    circuit_id
+}
+
+fn read_key(bytes: &[u8]) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    key.copy_from_slice(bytes);
+    key
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn relay() -> (StaticSecret, TaiorAddress) {
+        let secret = StaticSecret::random_from_rng(OsRng);
+        let address = TaiorAddress::from_public_key(&PublicKey::from(&secret));
+        (secret, address)
+    }
+
+    #[test]
+    fn test_both_sides_derive_the_same_keys() {
+        let (secret, address) = relay();
+        let client = NtorClient::new(&address, PublicKey::from(&secret)).unwrap();
+
+        let (created, relay_keys) = server_handshake(&secret, &client.create_message()).unwrap();
+        let client_keys = client.finish(&created).unwrap();
+
+        assert_eq!(client_keys, relay_keys);
+        assert_ne!(client_keys.forward_key, client_keys.backward_key);
+    }
+
+    #[test]
+    fn test_impostor_without_onion_key_is_rejected() {
+        let (secret, address) = relay();
+        let (impostor, _) = relay();
+        let client = NtorClient::new(&address, PublicKey::from(&secret)).unwrap();
+
+        // The impostor answers in the relay's name but cannot compute the static exchange
+        let mut create = client.create_message();
+        let impostor_address = TaiorAddress::from_public_key(&PublicKey::from(&impostor));
+        create[1..33].copy_from_slice(&impostor_address.to_bytes().unwrap());
+        let (created, _) = server_handshake(&impostor, &create).unwrap();
+
+        assert!(matches!(client.finish(&created), Err(CircuitError::HandshakeFailed(_))));
+        assert!(server_handshake(&impostor, &client.create_message()).is_err());
+    }
+}
@@ -1,8 +1,10 @@
 use blake3::Hasher;
 use rand_core::OsRng;
-use x25519_dalek::{EphemeralSecret, PublicKey};
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
 
-#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+const ADDRESS_PREFIX: &str = "taior://";
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
 pub struct TaiorAddress(pub String);
 
 impl TaiorAddress {
@@ -17,8 +19,26 @@ impl TaiorAddress {
         let mut hasher = Hasher::new();
         hasher.update(pk.as_bytes());
         let hash = hasher.finalize();
-        let addr = format!("taior://{}", hex::encode(&hash.as_bytes()[..32]));
-        TaiorAddress(addr)
+        Self::from_bytes(hash.as_bytes())
+    }
+
+    /// Builds an address from the raw 32-byte hash, as carried in binary headers.
+    pub fn from_bytes(hash: &[u8; 32]) -> Self {
+        TaiorAddress(format!("{}{}", ADDRESS_PREFIX, hex::encode(hash)))
+    }
+
+    /// Raw 32-byte hash behind the address, or `None` if the string is not a
+    /// well-formed `taior://<hex>` address.
+    pub fn to_bytes(&self) -> Option<[u8; 32]> {
+        let hex_part = self.0.strip_prefix(ADDRESS_PREFIX)?;
+        let mut out = [0u8; 32];
+        hex::decode_to_slice(hex_part, &mut out).ok()?;
+        Some(out)
+    }
+
+    /// Checks that `pk` is the key this address was derived from.
+    pub fn matches_public_key(&self, pk: &PublicKey) -> bool {
+        Self::from_public_key(pk) == *self
     }
 
     pub fn as_str(&self) -> &str {
@@ -26,15 +46,20 @@ impl TaiorAddress {
     }
 }
 
+/// Session identity. The secret is reusable (so the holder can open any number of
+/// packets sealed to its address) but lives only in RAM and dies with the instance.
 pub struct EphemeralIdentity {
-    pub secret: EphemeralSecret,
+    pub secret: StaticSecret,
+    pub public_key: PublicKey,
     pub address: TaiorAddress,
 }
 
 impl EphemeralIdentity {
     pub fn new() -> Self {
-        let (secret, address) = TaiorAddress::generate();
-        Self { secret, address }
+        let secret = StaticSecret::random_from_rng(OsRng);
+        let public_key = PublicKey::from(&secret);
+        let address = TaiorAddress::from_public_key(&public_key);
+        Self { secret, public_key, address }
     }
 }
 
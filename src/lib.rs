@@ -1,24 +1,71 @@
+#[cfg(not(any(feature = "fast-mode", feature = "mix-mode", feature = "adaptive-mode")))]
+compile_error!("at least one of the fast-mode, mix-mode and adaptive-mode features must be enabled");
+
+pub mod entropy;
+pub mod error;
 pub mod identity;
+pub mod metrics;
 pub mod modes;
+pub mod profile;
 pub mod packet;
 pub mod routing;
+#[cfg(feature = "mix-mode")]
 pub mod cover;
+pub mod descriptor;
 pub mod discovery;
 pub mod api;
 pub mod circuit;
+pub mod handshake;
+#[cfg(feature = "mix-mode")]
 pub mod cover_traffic;
+// Every node relays and opens Sphinx packets, and answers the reply blocks it is
+// given; only mixing builds create them
+pub mod sphinx;
+#[cfg(feature = "adaptive-mode")]
+pub mod adaptive;
+pub mod onion_relay;
 
 #[cfg(not(target_arch = "wasm32"))]
 pub mod transport;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod node;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod dht;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod gossip;
+#[cfg(all(not(target_arch = "wasm32"), feature = "local-discovery"))]
+pub mod local_discovery;
+#[cfg(all(not(target_arch = "wasm32"), feature = "mix-mode"))]
+pub mod scheduler;
 
 #[cfg(all(target_arch = "wasm32", feature = "wasm"))]
 pub mod wasm;
 
-pub use api::{Taior, SendOptions};
+pub use entropy::Entropy;
+pub use error::{TaiorError, TaiorResult};
+pub use api::{AnonymityReport, IncomingMessage, MessageOrigin, OutgoingPacket, SendOptions, SendOutcome, SendPolicy, Taior};
 pub use identity::TaiorAddress;
-pub use modes::RoutingMode;
-pub use circuit::{Circuit, CircuitBuilder, CircuitNode, OnionEncryptor};
+pub use descriptor::{Capabilities, DescriptorError, Endpoint, NodeDescriptor};
+pub use metrics::{LinkMetrics, LinkStats};
+pub use modes::{DelayDistribution, RoutingMode};
+pub use routing::LocalPolicy;
+pub use profile::{PrivacyProfile, ProfileError};
+pub use circuit::{Circuit, CircuitBuilder, CircuitNode, HopKeys, OnionEncryptor};
+pub use handshake::CircuitHandshake;
+#[cfg(feature = "mix-mode")]
 pub use cover_traffic::{CoverTrafficConfig, CoverTrafficGenerator, AdaptiveCoverTraffic};
+pub use sphinx::{SphinxHop, SphinxPacket};
+pub use onion_relay::{OnionRelay, RelayAction};
 
 #[cfg(not(target_arch = "wasm32"))]
-pub use transport::{QuicTransport, QuicConfig, NatTraversal, RelayClient, RelayServer, RelayAuth};
+pub use node::TaiorNode;
+#[cfg(not(target_arch = "wasm32"))]
+pub use dht::{DhtConfig, DhtNode, NodeId};
+#[cfg(not(target_arch = "wasm32"))]
+pub use gossip::{Gossip, GossipConfig};
+#[cfg(all(not(target_arch = "wasm32"), feature = "local-discovery"))]
+pub use local_discovery::{LocalDiscovery, LocalDiscoveryConfig};
+#[cfg(all(not(target_arch = "wasm32"), feature = "mix-mode"))]
+pub use scheduler::{Clock, ManualClock, SendScheduler, TokioClock};
+#[cfg(not(target_arch = "wasm32"))]
+pub use transport::{QuicTransport, QuicConfig, NatTraversal, RelayClient, RelayServer, RelayAuth, UdpTransport};
@@ -0,0 +1,266 @@
+//! Discovery of nodes on the local network. Each node announces its signed
+//! descriptor on a UDP multicast group at randomised intervals and listens for the
+//! announcements of others; those that verify are handed to the application, which
+//! registers them with `TaiorNode::add_local_descriptor` so that the `LocalPolicy`
+//! can keep first hops on, or off, the LAN.
+//!
+//! Announcing tells everyone on the network segment that a Taior node runs at this
+//! address, so it is opt-in. Announcements are only signed, not encrypted, and a
+//! sender gets at most one through per `sender_interval`.
+//!
+//! Datagrams carry `LABEL` followed by the encoded descriptor.
+
+use crate::descriptor::NodeDescriptor;
+use crate::error::TaiorError;
+use crate::transport::TransportError;
+use rand_core::{OsRng, RngCore};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tokio_stream::wrappers::ReceiverStream;
+
+/// Administratively scoped group, which routers keep within the site.
+pub const DEFAULT_GROUP: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 84, 73), 47479);
+/// Mean time between announcements; each wait is drawn from half to one and a half times it.
+pub const DEFAULT_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(20);
+/// Time that has to pass before another announcement from the same sender is taken.
+pub const DEFAULT_SENDER_INTERVAL: Duration = Duration::from_secs(5);
+
+const LABEL: &[u8] = b"taior-lan-v1";
+/// Senders tracked for rate limiting; announcements from further ones are dropped.
+const MAX_SENDERS: usize = 256;
+/// Discovered descriptors buffered for the application; further ones are dropped.
+const DISCOVERED_QUEUE: usize = 64;
+/// Largest datagram read; descriptors are far smaller.
+const MAX_DATAGRAM: usize = 2048;
+
+#[derive(Debug, Clone, Copy)]
+pub struct LocalDiscoveryConfig {
+    pub group: SocketAddrV4,
+    /// Interface to announce and listen on; unspecified lets the system choose.
+    pub interface: Ipv4Addr,
+    pub announce_interval: Duration,
+    pub sender_interval: Duration,
+}
+
+impl Default for LocalDiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            group: DEFAULT_GROUP,
+            interface: Ipv4Addr::UNSPECIFIED,
+            announce_interval: DEFAULT_ANNOUNCE_INTERVAL,
+            sender_interval: DEFAULT_SENDER_INTERVAL,
+        }
+    }
+}
+
+/// Handle to the multicast announcer and listener. Dropping it stops both.
+pub struct LocalDiscovery {
+    shutdown: Option<oneshot::Sender<()>>,
+    task: JoinHandle<()>,
+}
+
+impl LocalDiscovery {
+    /// Joins the multicast group and starts listening, announcing `descriptor` if
+    /// given. Verified descriptors of other nodes come out of the returned stream.
+    pub fn spawn(
+        config: LocalDiscoveryConfig,
+        descriptor: Option<NodeDescriptor>,
+    ) -> Result<(Self, ReceiverStream<NodeDescriptor>), TaiorError> {
+        if let Some(descriptor) = &descriptor {
+            descriptor.verify()?;
+        }
+        let socket = join_group(&config).map_err(TransportError::from)?;
+        let sender = group_sender(&config).map_err(TransportError::from)?;
+        let (discovered_tx, discovered) = mpsc::channel(DISCOVERED_QUEUE);
+        let (shutdown, shutdown_rx) = oneshot::channel();
+
+        let runtime = LocalLoop {
+            config,
+            socket,
+            sender,
+            announcement: descriptor.as_ref().map(announcement),
+            own: descriptor,
+            discovered: discovered_tx,
+            senders: HashMap::new(),
+        };
+        let task = tokio::spawn(runtime.run(shutdown_rx));
+
+        Ok((Self { shutdown: Some(shutdown), task }, ReceiverStream::new(discovered)))
+    }
+
+    pub async fn shutdown(mut self) -> Result<(), TaiorError> {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        (&mut self.task).await.map_err(|_| TaiorError::NodeStopped)
+    }
+}
+
+impl Drop for LocalDiscovery {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// A socket bound to the group port, shared with the other nodes on this host, that
+/// has joined the group on the configured interface.
+fn join_group(config: &LocalDiscoveryConfig) -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SockAddr::from(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, config.group.port())))?;
+    socket.join_multicast_v4(config.group.ip(), &config.interface)?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// A socket of its own to announce from, so that nodes sharing a host still come
+/// from different ports and are rate limited apart. Announcements stay on the link.
+fn group_sender(config: &LocalDiscoveryConfig) -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.bind(&SockAddr::from(SocketAddrV4::new(config.interface, 0)))?;
+    socket.set_multicast_if_v4(&config.interface)?;
+    socket.set_multicast_loop_v4(true)?;
+    socket.set_multicast_ttl_v4(1)?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+fn announcement(descriptor: &NodeDescriptor) -> Vec<u8> {
+    let mut bytes = LABEL.to_vec();
+    bytes.extend_from_slice(&descriptor.encode());
+    bytes
+}
+
+struct LocalLoop {
+    config: LocalDiscoveryConfig,
+    socket: UdpSocket,
+    sender: UdpSocket,
+    own: Option<NodeDescriptor>,
+    announcement: Option<Vec<u8>>,
+    discovered: mpsc::Sender<NodeDescriptor>,
+    /// When each sender's last announcement was taken.
+    senders: HashMap<SocketAddr, Instant>,
+}
+
+impl LocalLoop {
+    async fn run(mut self, mut shutdown: oneshot::Receiver<()>) {
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+        let mut next_announce = Instant::now() + self.announce_wait();
+        loop {
+            tokio::select! {
+                _ = &mut shutdown => break,
+                received = self.socket.recv_from(&mut buf) => match received {
+                    Ok((len, from)) => self.handle_announcement(&buf[..len], from),
+                    Err(_) => break,
+                },
+                _ = tokio::time::sleep_until(next_announce), if self.announcement.is_some() => {
+                    next_announce = Instant::now() + self.announce_wait();
+                    if let Some(announcement) = &self.announcement {
+                        let _ = self.sender.send_to(announcement, SocketAddr::V4(self.config.group)).await;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Uniformly between half and one and a half announce intervals, so that nodes
+    /// started together do not announce in step.
+    fn announce_wait(&self) -> Duration {
+        let factor = 0.5 + OsRng.next_u32() as f64 / u32::MAX as f64;
+        self.config.announce_interval.mul_f64(factor)
+    }
+
+    fn handle_announcement(&mut self, datagram: &[u8], from: SocketAddr) {
+        let Some(encoded) = datagram.strip_prefix(LABEL) else {
+            return;
+        };
+
+        let now = Instant::now();
+        let sender_interval = self.config.sender_interval;
+        if self.senders.get(&from).is_some_and(|taken| now.duration_since(*taken) < sender_interval) {
+            return;
+        }
+        if self.senders.len() >= MAX_SENDERS {
+            self.senders.retain(|_, taken| now.duration_since(*taken) < sender_interval);
+            if self.senders.len() >= MAX_SENDERS {
+                return;
+            }
+        }
+        self.senders.insert(from, now);
+
+        let Ok(descriptor) = NodeDescriptor::decode(encoded) else {
+            return;
+        };
+        // Our own announcements loop back to us
+        if self.own.as_ref().is_some_and(|own| own.address() == descriptor.address()) {
+            return;
+        }
+        if descriptor.verify().is_ok() {
+            let _ = self.discovered.try_send(descriptor);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::circuit::current_timestamp;
+    use crate::descriptor::{Capabilities, Endpoint};
+    use ed25519_dalek::SigningKey;
+    use std::collections::HashSet;
+    use tokio_stream::StreamExt;
+    use x25519_dalek::StaticSecret;
+
+    fn descriptor(seed: u8) -> NodeDescriptor {
+        let signing_key = SigningKey::from_bytes(&[seed; 32]);
+        let onion_secret = StaticSecret::from([seed; 32]);
+        let endpoint = Endpoint::Udp(SocketAddr::from(([192, 168, 1, seed], 7000)));
+        NodeDescriptor::sign(&signing_key, &onion_secret, vec![endpoint], Capabilities::RELAY, 1, current_timestamp() + 600)
+    }
+
+    #[tokio::test]
+    async fn test_nodes_find_each_other_over_loopback_multicast() {
+        let config = LocalDiscoveryConfig {
+            group: SocketAddrV4::new(Ipv4Addr::new(239, 255, 84, 74), 47480),
+            interface: Ipv4Addr::LOCALHOST,
+            announce_interval: Duration::from_millis(50),
+            sender_interval: Duration::from_millis(10),
+        };
+        let mut nodes = Vec::new();
+        for seed in 1..=3 {
+            nodes.push(LocalDiscovery::spawn(config, Some(descriptor(seed))).unwrap());
+        }
+        // A listener that does not announce still finds everyone
+        let (listener, mut found) = LocalDiscovery::spawn(config, None).unwrap();
+
+        // Announcers find the other two, the listener all three
+        let addresses: HashSet<_> = (1..=3).map(|seed| descriptor(seed).address()).collect();
+        let streams = nodes.iter_mut().map(|(_, stream)| stream).zip(1..=3).chain([(&mut found, 0)]);
+        for (stream, own) in streams {
+            let mut expected = addresses.clone();
+            expected.remove(&descriptor(own).address());
+            let mut seen = HashSet::new();
+            tokio::time::timeout(Duration::from_secs(5), async {
+                while seen.len() < expected.len() {
+                    seen.insert(stream.next().await.unwrap().address());
+                }
+            })
+            .await
+            .expect("announcements in time");
+            assert_eq!(seen, expected);
+        }
+
+        for (node, _) in nodes {
+            node.shutdown().await.unwrap();
+        }
+        listener.shutdown().await.unwrap();
+    }
+}
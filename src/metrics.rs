@@ -0,0 +1,220 @@
+//! Per-neighbour link measurements for the AORP decision engine. RTT and loss come
+//! from requests a neighbour answered or left unanswered, throughput from how long
+//! the transport took to send to it; all are smoothed, and only leave this module as the opaque
+//! `LatencyBucket` and `BandwidthRank` ranges the AORP spec lets the engine see,
+//! never as raw values.
+
+use aorp::interfaces::types::{BandwidthRank, LatencyBucket, NeighborId};
+use aorp::MetricView;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Weight of a new sample in the smoothed values.
+const SMOOTHING: f64 = 0.2;
+/// Round trips below this are `LatencyBucket::Low`.
+pub const LOW_LATENCY_RTT: Duration = Duration::from_millis(50);
+/// Round trips from this on are `LatencyBucket::High`.
+pub const HIGH_LATENCY_RTT: Duration = Duration::from_millis(200);
+/// Loss rate from which a link is `LatencyBucket::High` whatever its round trip.
+pub const LOSSY_LINK: f32 = 0.2;
+/// Throughput, in bytes per second, from which a link is `BandwidthRank::Medium`.
+pub const MEDIUM_BANDWIDTH: f64 = 100_000.0;
+/// Throughput, in bytes per second, from which a link is `BandwidthRank::High`.
+pub const HIGH_BANDWIDTH: f64 = 1_000_000.0;
+
+/// Smoothed measurements of the link to one neighbour.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct LinkStats {
+    pub rtt: Option<Duration>,
+    /// Fraction of requests left unanswered, from 0.0 to 1.0.
+    pub loss: f32,
+    /// Bytes per second of transfers to the neighbour, when measured.
+    pub throughput: Option<f64>,
+}
+
+impl LinkStats {
+    pub fn is_lossy(&self) -> bool {
+        self.loss >= LOSSY_LINK
+    }
+
+    /// Round trip stretched by the retransmissions its loss rate implies.
+    pub fn latency_bucket(&self) -> Option<LatencyBucket> {
+        if self.is_lossy() {
+            return Some(LatencyBucket::High);
+        }
+        let rtt = self.rtt?.div_f32(1.0 - self.loss);
+        Some(if rtt < LOW_LATENCY_RTT {
+            LatencyBucket::Low
+        } else if rtt < HIGH_LATENCY_RTT {
+            LatencyBucket::Medium
+        } else {
+            LatencyBucket::High
+        })
+    }
+
+    pub fn bandwidth_rank(&self) -> Option<BandwidthRank> {
+        let throughput = self.throughput?;
+        Some(if throughput >= HIGH_BANDWIDTH {
+            BandwidthRank::High
+        } else if throughput >= MEDIUM_BANDWIDTH {
+            BandwidthRank::Medium
+        } else {
+            BandwidthRank::Low
+        })
+    }
+}
+
+/// Collects link measurements by neighbour, named as in `NodeDiscovery`.
+#[derive(Debug, Clone, Default)]
+pub struct LinkMetrics {
+    links: HashMap<String, LinkStats>,
+}
+
+impl LinkMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_rtt(&mut self, neighbor: &str, rtt: Duration) {
+        let link = self.link(neighbor);
+        link.rtt = Some(match link.rtt {
+            Some(smoothed) => Duration::from_secs_f64(smooth(smoothed.as_secs_f64(), rtt.as_secs_f64())),
+            None => rtt,
+        });
+    }
+
+    /// Records whether a request to `neighbor`, such as a keepalive or a handshake,
+    /// was answered or lost.
+    pub fn record_delivery(&mut self, neighbor: &str, delivered: bool) {
+        let link = self.link(neighbor);
+        let lost = if delivered { 0.0 } else { 1.0 };
+        link.loss = smooth(link.loss as f64, lost) as f32;
+    }
+
+    /// Records that `bytes` sent to `neighbor` took `elapsed` to go out, which includes
+    /// waiting on the flow control of transports that have one.
+    pub fn record_transfer(&mut self, neighbor: &str, bytes: usize, elapsed: Duration) {
+        let rate = bytes as f64 / elapsed.max(Duration::from_micros(1)).as_secs_f64();
+        let link = self.link(neighbor);
+        link.throughput = Some(match link.throughput {
+            Some(smoothed) => smooth(smoothed, rate),
+            None => rate,
+        });
+    }
+
+    pub fn stats(&self, neighbor: &str) -> Option<&LinkStats> {
+        self.links.get(neighbor)
+    }
+
+    pub fn is_lossy(&self, neighbor: &str) -> bool {
+        self.links.get(neighbor).is_some_and(LinkStats::is_lossy)
+    }
+
+    /// Median smoothed round trip among `neighbors`, if any of them was measured.
+    pub fn median_rtt(&self, neighbors: &[String]) -> Option<Duration> {
+        let mut rtts: Vec<Duration> = neighbors.iter().filter_map(|n| self.links.get(n)?.rtt).collect();
+        rtts.sort();
+        let middle = rtts.len() / 2;
+        match rtts.len() {
+            0 => None,
+            len if len % 2 == 0 => Some((rtts[middle - 1] + rtts[middle]) / 2),
+            _ => Some(rtts[middle]),
+        }
+    }
+
+    /// Mean loss rate among the measured `neighbors`, or 0.0 if none was measured.
+    pub fn mean_loss(&self, neighbors: &[String]) -> f32 {
+        let losses: Vec<f32> = neighbors.iter().filter_map(|n| self.links.get(n)).map(|link| link.loss).collect();
+        if losses.is_empty() {
+            return 0.0;
+        }
+        losses.iter().sum::<f32>() / losses.len() as f32
+    }
+
+    /// Forgets a neighbour, e.g. once it left `NodeDiscovery`.
+    pub fn remove(&mut self, neighbor: &str) {
+        self.links.remove(neighbor);
+    }
+
+    /// View of the measured links among `neighbors`, for one decision. Neighbours
+    /// without measurements are left out rather than given a made-up bucket.
+    pub fn view(&self, neighbors: &[String]) -> MetricView {
+        let mut builder = MetricView::builder();
+        for neighbor in neighbors {
+            let Some(link) = self.links.get(neighbor) else {
+                continue;
+            };
+            if let Some(bucket) = link.latency_bucket() {
+                builder = builder.add_latency_bucket(NeighborId(neighbor.clone()), bucket);
+            }
+            if let Some(rank) = link.bandwidth_rank() {
+                builder = builder.add_bandwidth_rank(NeighborId(neighbor.clone()), rank);
+            }
+        }
+        builder.build()
+    }
+
+    fn link(&mut self, neighbor: &str) -> &mut LinkStats {
+        self.links.entry(neighbor.to_string()).or_default()
+    }
+}
+
+fn smooth(smoothed: f64, sample: f64) -> f64 {
+    smoothed + SMOOTHING * (sample - smoothed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rtt_and_loss_fall_into_latency_buckets() {
+        let mut metrics = LinkMetrics::new();
+        assert!(metrics.stats("a").is_none());
+
+        metrics.record_rtt("a", Duration::from_millis(20));
+        metrics.record_rtt("b", Duration::from_millis(120));
+        metrics.record_rtt("c", Duration::from_millis(400));
+        assert!(matches!(metrics.stats("a").unwrap().latency_bucket(), Some(LatencyBucket::Low)));
+        assert!(matches!(metrics.stats("b").unwrap().latency_bucket(), Some(LatencyBucket::Medium)));
+        assert!(matches!(metrics.stats("c").unwrap().latency_bucket(), Some(LatencyBucket::High)));
+
+        // One slow round trip is smoothed out
+        metrics.record_rtt("a", Duration::from_millis(100));
+        let rtt = metrics.stats("a").unwrap().rtt.unwrap();
+        assert!(rtt.abs_diff(Duration::from_millis(36)) < Duration::from_micros(1));
+
+        // A fast link that keeps losing packets is no better than a slow one
+        for _ in 0..3 {
+            metrics.record_delivery("a", false);
+        }
+        let a = metrics.stats("a").unwrap();
+        assert!(a.loss >= LOSSY_LINK);
+        assert!(matches!(a.latency_bucket(), Some(LatencyBucket::High)));
+        for _ in 0..20 {
+            metrics.record_delivery("a", true);
+        }
+        assert!(matches!(metrics.stats("a").unwrap().latency_bucket(), Some(LatencyBucket::Low)));
+
+        // Loss alone says nothing about the round trip
+        metrics.record_delivery("d", true);
+        assert!(metrics.stats("d").unwrap().latency_bucket().is_none());
+    }
+
+    #[test]
+    fn test_throughput_ranks_bandwidth() {
+        let mut metrics = LinkMetrics::new();
+        metrics.record_transfer("a", 2_000_000, Duration::from_secs(1));
+        metrics.record_transfer("b", 50_000, Duration::from_millis(100));
+        metrics.record_transfer("c", 1_000, Duration::from_millis(100));
+        metrics.record_transfer("d", 1_000, Duration::ZERO);
+
+        assert!(matches!(metrics.stats("a").unwrap().bandwidth_rank(), Some(BandwidthRank::High)));
+        assert!(matches!(metrics.stats("b").unwrap().bandwidth_rank(), Some(BandwidthRank::Medium)));
+        assert!(matches!(metrics.stats("c").unwrap().bandwidth_rank(), Some(BandwidthRank::Low)));
+        assert!(metrics.stats("d").unwrap().throughput.unwrap().is_finite());
+
+        metrics.remove("a");
+        assert!(metrics.stats("a").is_none());
+    }
+}
@@ -1,11 +1,33 @@
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+use rand_core::RngCore;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Upper bound of exponential delays, as a multiple of their mean. Cuts off the
+/// tail (e^-10 of the packets) so that no message is held back indefinitely.
+pub const MAX_DELAY_FACTOR: u32 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum RoutingMode {
     Fast,
     Mix,
     Adaptive,
 }
 
-#[derive(Debug, Clone)]
+impl RoutingMode {
+    /// Whether the mode was compiled in through its `fast-mode`, `mix-mode` or
+    /// `adaptive-mode` feature.
+    pub fn is_enabled(self) -> bool {
+        match self {
+            Self::Fast => cfg!(feature = "fast-mode"),
+            Self::Mix => cfg!(feature = "mix-mode"),
+            Self::Adaptive => cfg!(feature = "adaptive-mode"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct ModeConfig {
     pub mode: RoutingMode,
     pub hops: u8,
@@ -68,4 +90,45 @@ impl ModeConfig {
         self.jitter_ms = ms;
         self
     }
+
+    /// Distribution the delay before each packet leaves is drawn from. Mix mode
+    /// uses exponential delays with `jitter_ms` as mean, the other modes add
+    /// uniform jitter of up to `jitter_ms`.
+    pub fn delay(&self) -> DelayDistribution {
+        match (self.mode, self.jitter_ms) {
+            (_, None) | (_, Some(0)) => DelayDistribution::None,
+            (RoutingMode::Mix, Some(ms)) => DelayDistribution::Exponential(Duration::from_millis(ms)),
+            (_, Some(ms)) => DelayDistribution::Uniform(Duration::from_millis(ms)),
+        }
+    }
+}
+
+/// How long a packet is held before it is transmitted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DelayDistribution {
+    None,
+    /// Uniform between zero and the given bound.
+    Uniform(Duration),
+    /// Exponential with the given mean, as in Poisson mixes: how long a packet has
+    /// already waited says nothing about when it leaves, so packets sent in a burst
+    /// leave in an order unrelated to the one they were sent in.
+    Exponential(Duration),
+}
+
+impl DelayDistribution {
+    pub fn sample<R: RngCore + ?Sized>(&self, rng: &mut R) -> Duration {
+        match *self {
+            Self::None => Duration::ZERO,
+            Self::Uniform(max) => {
+                let micros = max.as_micros() as u64;
+                Duration::from_micros(rng.next_u64() % (micros + 1))
+            }
+            Self::Exponential(mean) => {
+                // Uniform in [0, 1) from the top 53 bits, inverted through the exponential CDF
+                let uniform = (rng.next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+                let factor = -(1.0 - uniform).ln();
+                mean.mul_f64(factor.min(MAX_DELAY_FACTOR as f64))
+            }
+        }
+    }
 }
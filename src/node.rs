@@ -0,0 +1,922 @@
+//! Networked node runtime: a `Taior` instance, its relay engine and a `Transport`
+//! driven by one background task. The task sends what the application asks for,
+//! opens what is addressed to this node, forwards everything else it relays, and
+//! runs circuit handshakes without holding up the traffic around them. Its own
+//! packets leave through a `SendScheduler` after their mixing delay. Every gossip
+//! interval it swaps descriptor samples with a few neighbours (see `gossip`), and
+//! every keepalive interval it probes the neighbours it has not heard from, so that
+//! dead ones are quarantined and eventually forgotten. A profile applied to the
+//! `Taior` beforehand may add cover packets on a schedule of their own.
+
+use crate::api::{AnonymityReport, IncomingMessage, MessageOrigin, OutgoingPacket, SendOptions, Taior};
+use crate::circuit::{CircuitError, DEFAULT_HOP_TIMEOUT};
+use crate::descriptor::NodeDescriptor;
+use crate::error::TaiorError;
+use crate::gossip::{Gossip, GossipConfig};
+use crate::handshake::{CircuitHandshake, HandshakeStep};
+use crate::identity::TaiorAddress;
+use crate::modes::{DelayDistribution, ModeConfig};
+use crate::onion_relay::{OnionRelay, RelayAction, RelayError};
+use crate::packet::{PacketFormat, TaiorPacket, CIRCUIT_ID_LEN, CONTROL_GOSSIP, CONTROL_KEEPALIVE};
+#[cfg(feature = "mix-mode")]
+use crate::scheduler::{Clock, SendScheduler, TokioClock};
+use crate::sphinx::Surb;
+use crate::transport::Transport;
+use rand_core::{OsRng, RngCore};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+#[cfg(feature = "mix-mode")]
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tokio_stream::wrappers::ReceiverStream;
+use x25519_dalek::PublicKey;
+
+/// Requests the application may have queued before the node task picks them up.
+const COMMAND_QUEUE: usize = 64;
+/// Opened messages buffered for the application; further ones are dropped until it catches up.
+const INBOUND_QUEUE: usize = 256;
+/// Time between keepalive rounds. Neighbours not heard from for as long are probed,
+/// and have until the next round to answer.
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+const KEEPALIVE_PING: u8 = 0;
+const KEEPALIVE_PONG: u8 = 1;
+
+enum Command {
+    AddRelay {
+        endpoint: SocketAddr,
+        onion_key: PublicKey,
+    },
+    AddPeer {
+        endpoint: SocketAddr,
+        public_key: PublicKey,
+        respond: oneshot::Sender<TaiorAddress>,
+    },
+    AddDescriptor {
+        descriptor: Box<NodeDescriptor>,
+        local: bool,
+        respond: oneshot::Sender<Result<TaiorAddress, TaiorError>>,
+    },
+    Announce {
+        descriptor: Box<NodeDescriptor>,
+    },
+    ConfigureGossip {
+        config: GossipConfig,
+    },
+    BuildCircuit {
+        hops: u8,
+        respond: oneshot::Sender<Result<(), TaiorError>>,
+    },
+    SendTo {
+        dest: TaiorAddress,
+        data: Vec<u8>,
+        options: SendOptions,
+        respond: oneshot::Sender<Result<Option<AnonymityReport>, TaiorError>>,
+    },
+    Reply {
+        surb: Box<Surb>,
+        data: Vec<u8>,
+        respond: oneshot::Sender<Result<(), TaiorError>>,
+    },
+    /// Sent by the node task itself once a name looked up in the background resolved.
+    Resolved {
+        node: String,
+        endpoint: Option<SocketAddr>,
+    },
+}
+
+/// Handle to a running node. Dropping it stops the node as well, but only
+/// `shutdown` waits for the transport to be closed.
+pub struct TaiorNode {
+    address: TaiorAddress,
+    public_key: PublicKey,
+    commands: mpsc::Sender<Command>,
+    incoming: Option<mpsc::Receiver<IncomingMessage>>,
+    shutdown: Option<oneshot::Sender<()>>,
+    task: JoinHandle<()>,
+}
+
+impl TaiorNode {
+    /// Starts a node for `taior` on `transport`. The node relays for others under the
+    /// instance's own identity (see `Taior::relay`).
+    pub fn spawn<T: Transport + 'static>(taior: Taior, transport: T) -> Self {
+        #[cfg(feature = "mix-mode")]
+        let scheduler = SendScheduler::new(Arc::new(TokioClock));
+        #[cfg(not(feature = "mix-mode"))]
+        let scheduler = SendScheduler;
+        Self::start(taior, transport, scheduler)
+    }
+
+    /// Like `spawn`, with mixing delays timed by `clock`.
+    #[cfg(feature = "mix-mode")]
+    pub fn spawn_with_clock<T: Transport + 'static>(taior: Taior, transport: T, clock: Arc<dyn Clock>) -> Self {
+        Self::start(taior, transport, SendScheduler::new(clock))
+    }
+
+    fn start<T: Transport + 'static>(taior: Taior, transport: T, scheduler: SendScheduler) -> Self {
+        let address = TaiorAddress(taior.address().to_string());
+        let public_key = taior.public_key();
+        let (commands, command_rx) = mpsc::channel(COMMAND_QUEUE);
+        let (inbound_tx, incoming) = mpsc::channel(INBOUND_QUEUE);
+        let (shutdown, shutdown_rx) = oneshot::channel();
+
+        let runtime = NodeLoop::new(taior, transport, scheduler, command_rx, commands.downgrade(), inbound_tx);
+        let task = tokio::spawn(runtime.run(shutdown_rx));
+
+        Self {
+            address,
+            public_key,
+            commands,
+            incoming: Some(incoming),
+            shutdown: Some(shutdown),
+            task,
+        }
+    }
+
+    pub fn address(&self) -> &TaiorAddress {
+        &self.address
+    }
+
+    /// Identity key, which is also the onion key the node relays with.
+    pub fn public_key(&self) -> PublicKey {
+        self.public_key
+    }
+
+    /// Messages opened by the node, cover traffic excluded. The stream can be taken
+    /// once and ends when the node stops.
+    pub fn incoming(&mut self) -> Option<ReceiverStream<IncomingMessage>> {
+        self.incoming.take().map(ReceiverStream::new)
+    }
+
+    /// Registers a relay reached at `endpoint`, both as a circuit hop and as a
+    /// neighbour this node forwards to.
+    pub async fn add_relay(&self, endpoint: SocketAddr, onion_key: PublicKey) -> Result<(), TaiorError> {
+        self.command(Command::AddRelay { endpoint, onion_key }).await
+    }
+
+    /// Registers a peer reached at `endpoint` and returns its destination address.
+    pub async fn add_peer(&self, endpoint: SocketAddr, public_key: PublicKey) -> Result<TaiorAddress, TaiorError> {
+        let (respond, answer) = oneshot::channel();
+        self.command(Command::AddPeer { endpoint, public_key, respond }).await?;
+        answer.await.map_err(|_| TaiorError::NodeStopped)
+    }
+
+    /// Learns a node from its self-signed descriptor; see `Taior::add_descriptor`.
+    pub async fn add_descriptor(&self, descriptor: NodeDescriptor) -> Result<TaiorAddress, TaiorError> {
+        let (respond, answer) = oneshot::channel();
+        self.command(Command::AddDescriptor { descriptor: Box::new(descriptor), local: false, respond }).await?;
+        answer.await.map_err(|_| TaiorError::NodeStopped)?
+    }
+
+    /// Learns a node found on the local network, e.g. through `LocalDiscovery`; see
+    /// `Taior::add_local_descriptor`.
+    pub async fn add_local_descriptor(&self, descriptor: NodeDescriptor) -> Result<TaiorAddress, TaiorError> {
+        let (respond, answer) = oneshot::channel();
+        self.command(Command::AddDescriptor { descriptor: Box::new(descriptor), local: true, respond }).await?;
+        answer.await.map_err(|_| TaiorError::NodeStopped)?
+    }
+
+    /// Spreads the node's own descriptor to its neighbours from the next gossip round
+    /// on. It has to carry the node's public key as onion key, and a newer version
+    /// has to be announced before it expires.
+    pub async fn announce(&self, descriptor: NodeDescriptor) -> Result<(), TaiorError> {
+        descriptor.verify()?;
+        if !self.address.matches_public_key(&descriptor.onion_key) {
+            return Err(TaiorError::Policy("the descriptor is not this node's".to_string()));
+        }
+        self.command(Command::Announce { descriptor: Box::new(descriptor) }).await
+    }
+
+    /// Replaces the gossip configuration; the next round starts one `interval` from now.
+    pub async fn configure_gossip(&self, config: GossipConfig) -> Result<(), TaiorError> {
+        self.command(Command::ConfigureGossip { config }).await
+    }
+
+    /// Builds a circuit of `hops` relays for subsequent sends. Each hop has
+    /// `DEFAULT_HOP_TIMEOUT` to answer.
+    pub async fn build_circuit(&self, hops: u8) -> Result<(), TaiorError> {
+        let (respond, answer) = oneshot::channel();
+        self.command(Command::BuildCircuit { hops, respond }).await?;
+        answer.await.map_err(|_| TaiorError::NodeStopped)?
+    }
+
+    /// Sends `data` to `dest` (see `Taior::send_to`) and returns the anonymity it got once
+    /// the packet is scheduled; it reaches the transport after its mixing delay. Returns `None` when the instance's
+    /// `SendPolicy::QueueUntilReady` held it back; it then goes out as soon as
+    /// relays or a circuit provide enough hops.
+    pub async fn send_to(
+        &self,
+        dest: &TaiorAddress,
+        data: &[u8],
+        options: SendOptions,
+    ) -> Result<Option<AnonymityReport>, TaiorError> {
+        let (respond, answer) = oneshot::channel();
+        let command = Command::SendTo {
+            dest: dest.clone(),
+            data: data.to_vec(),
+            options,
+            respond,
+        };
+        self.command(command).await?;
+        answer.await.map_err(|_| TaiorError::NodeStopped)?
+    }
+
+    /// Answers a message through the reply block it carried, after a Mix mode delay.
+    pub async fn reply(&self, surb: Surb, data: &[u8]) -> Result<(), TaiorError> {
+        let (respond, answer) = oneshot::channel();
+        self.command(Command::Reply { surb: Box::new(surb), data: data.to_vec(), respond }).await?;
+        answer.await.map_err(|_| TaiorError::NodeStopped)?
+    }
+
+    /// Stops the background task after the request it is handling, closes the
+    /// transport and waits for both. Packets still held for their mixing delay are
+    /// dropped.
+    pub async fn shutdown(mut self) -> Result<(), TaiorError> {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        (&mut self.task).await.map_err(|_| TaiorError::NodeStopped)
+    }
+
+    async fn command(&self, command: Command) -> Result<(), TaiorError> {
+        self.commands
+            .send(command)
+            .await
+            .map_err(|_| TaiorError::NodeStopped)
+    }
+}
+
+/// Without the mixing stack packets go straight to the transport, so none ever
+/// becomes due later.
+#[cfg(not(feature = "mix-mode"))]
+struct SendScheduler;
+
+#[cfg(not(feature = "mix-mode"))]
+impl SendScheduler {
+    async fn next_due(&mut self) -> (SocketAddr, TaiorPacket) {
+        std::future::pending().await
+    }
+}
+
+/// A circuit handshake in progress, advanced as the entry hop answers.
+struct PendingCircuit {
+    entry: SocketAddr,
+    handshake: CircuitHandshake,
+    /// When the request awaiting an answer was sent.
+    sent: Instant,
+    deadline: Instant,
+    respond: oneshot::Sender<Result<(), TaiorError>>,
+}
+
+struct NodeLoop<T> {
+    taior: Taior,
+    relay: OnionRelay,
+    transport: T,
+    commands: mpsc::Receiver<Command>,
+    inbound: mpsc::Sender<IncomingMessage>,
+    pending: Option<PendingCircuit>,
+    scheduler: SendScheduler,
+    gossip: Gossip,
+    next_gossip: Instant,
+    /// Keepalives awaiting an answer, by the nonce carried in their circuit id.
+    probes: HashMap<[u8; CIRCUIT_ID_LEN], Probe>,
+    next_keepalive: Instant,
+    /// When the cover schedule of the profile applied to the facade is next due.
+    next_cover: Option<Instant>,
+    /// Reports name lookups back to the task, without keeping it alive on its own.
+    resolver: mpsc::WeakSender<Command>,
+    /// Packets waiting for the endpoint of the node they go to, by node name.
+    unresolved: HashMap<String, Vec<(TaiorPacket, DelayDistribution)>>,
+}
+
+struct Probe {
+    endpoint: SocketAddr,
+    sent: Instant,
+}
+
+impl<T: Transport> NodeLoop<T> {
+    fn new(
+        mut taior: Taior,
+        transport: T,
+        scheduler: SendScheduler,
+        commands: mpsc::Receiver<Command>,
+        resolver: mpsc::WeakSender<Command>,
+        inbound: mpsc::Sender<IncomingMessage>,
+    ) -> Self {
+        let gossip = Gossip::new(GossipConfig::default()).with_entropy(taior.fork_entropy());
+        Self {
+            relay: taior.relay(),
+            next_gossip: Instant::now() + gossip.config().interval,
+            gossip,
+            probes: HashMap::new(),
+            next_keepalive: Instant::now() + KEEPALIVE_INTERVAL,
+            next_cover: next_cover(&mut taior),
+            taior,
+            transport,
+            commands,
+            inbound,
+            pending: None,
+            scheduler,
+            resolver,
+            unresolved: HashMap::new(),
+        }
+    }
+
+    async fn run(mut self, mut shutdown: oneshot::Receiver<()>) {
+        loop {
+            let deadline = self.pending.as_ref().map(|p| p.deadline);
+            tokio::select! {
+                _ = &mut shutdown => break,
+                command = self.commands.recv() => match command {
+                    Some(command) => self.handle_command(command).await,
+                    None => break,
+                },
+                received = self.transport.receive() => match received {
+                    Ok((packet, from)) => self.handle_packet(packet, from).await,
+                    // A packet that cannot be read is dropped like any other bad packet
+                    Err(e) if !e.is_fatal() => {}
+                    Err(_) => break,
+                },
+                (dest, packet) = self.scheduler.next_due() => {
+                    let _ = self.transmit(&packet, dest).await;
+                }
+                _ = tokio::time::sleep_until(self.next_gossip) => self.gossip_round().await,
+                _ = tokio::time::sleep_until(self.next_keepalive) => self.keepalive_round().await,
+                _ = tokio::time::sleep_until(self.next_cover.unwrap_or(self.next_keepalive)), if self.next_cover.is_some() => {
+                    self.cover_round().await
+                }
+                _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    if let Some(pending) = self.pending.take() {
+                        let entry = pending.entry.to_string();
+                        self.taior.link_metrics_mut().record_delivery(&entry, false);
+                        self.taior.record_node_failure(&entry);
+                        let hop = pending.handshake.hop_index();
+                        let _ = pending.respond.send(Err(CircuitError::HopTimeout(hop).into()));
+                    }
+                }
+            }
+        }
+
+        let _ = self.transport.close().await;
+    }
+
+    async fn handle_command(&mut self, command: Command) {
+        match command {
+            Command::AddRelay { endpoint, onion_key } => {
+                self.taior.add_relay(endpoint.to_string(), onion_key);
+                self.relay.add_peer(TaiorAddress::from_public_key(&onion_key), endpoint);
+                self.flush_queued().await;
+            }
+            Command::AddPeer { endpoint, public_key, respond } => {
+                let address = self.taior.add_peer(endpoint.to_string(), public_key);
+                self.relay.add_peer(address.clone(), endpoint);
+                let _ = respond.send(address);
+                self.flush_queued().await;
+            }
+            Command::AddDescriptor { descriptor, local, respond } => {
+                let result = self.learn(*descriptor, local);
+                let _ = respond.send(result);
+                self.flush_queued().await;
+            }
+            Command::Announce { descriptor } => self.gossip.announce(*descriptor),
+            Command::ConfigureGossip { config } => {
+                self.gossip.set_config(config);
+                self.next_gossip = Instant::now() + config.interval;
+            }
+            Command::BuildCircuit { hops, respond } => {
+                if self.pending.is_some() {
+                    let _ = respond.send(Err(TaiorError::Policy("a circuit is already being built".to_string())));
+                    return;
+                }
+                let started = self.taior.start_circuit(hops);
+                let result = match started {
+                    Ok((entry, handshake, request)) => match self.transmit(&request, entry).await {
+                        Ok(()) => {
+                            let sent = Instant::now();
+                            let deadline = sent + DEFAULT_HOP_TIMEOUT;
+                            self.pending = Some(PendingCircuit { entry, handshake, sent, deadline, respond });
+                            return;
+                        }
+                        Err(e) => Err(e),
+                    },
+                    Err(e) => Err(e),
+                };
+                let _ = respond.send(result);
+            }
+            Command::SendTo { dest, data, options, respond } => {
+                let result = self.send_to(&dest, &data, options).await;
+                let _ = respond.send(result);
+            }
+            Command::Reply { surb, data, respond } => {
+                let result = self.reply(*surb, &data).await;
+                let _ = respond.send(result);
+            }
+            Command::Resolved { node, endpoint } => {
+                let waiting = self.unresolved.remove(&node).unwrap_or_default();
+                match endpoint {
+                    Some(endpoint) => {
+                        for (packet, delay) in waiting {
+                            let _ = self.send_after_delay(endpoint, packet, &delay).await;
+                        }
+                    }
+                    // Nothing reaches the node, so what waited for it is dropped
+                    None => self.taior.record_node_failure(&node),
+                }
+            }
+        }
+    }
+
+    /// Registers a node from its descriptor, reaching it at its first direct endpoint.
+    fn learn(&mut self, descriptor: NodeDescriptor, local: bool) -> Result<TaiorAddress, TaiorError> {
+        let endpoint = descriptor.endpoints.iter().find(|endpoint| endpoint.is_direct()).copied();
+        let address = if local {
+            self.taior.add_local_descriptor(descriptor)?
+        } else {
+            self.taior.add_descriptor(descriptor)?
+        };
+        if let Some(endpoint) = endpoint {
+            self.relay.add_peer(address.clone(), endpoint.socket_addr());
+        }
+        Ok(address)
+    }
+
+    /// Drops the nodes whose descriptor expired and offers samples to a few neighbours.
+    async fn gossip_round(&mut self) {
+        self.next_gossip = Instant::now() + self.gossip.config().interval;
+        self.taior.remove_expired_nodes();
+
+        let offers = self.gossip.round(self.taior.discovery(), std::time::Instant::now());
+        for (peer, message) in offers {
+            let _ = self.transmit(&TaiorPacket::control([0; CIRCUIT_ID_LEN], message), peer).await;
+        }
+    }
+
+    /// Counts the probes of the last round that went unanswered as failures, forgets
+    /// the nodes that went stale and probes those not heard from since.
+    async fn keepalive_round(&mut self) {
+        self.next_keepalive = Instant::now() + KEEPALIVE_INTERVAL;
+        for (_, probe) in self.probes.drain() {
+            let node = probe.endpoint.to_string();
+            self.taior.link_metrics_mut().record_delivery(&node, false);
+            self.taior.record_node_failure(&node);
+        }
+        self.taior.evict_stale_nodes();
+
+        let due = self.taior.discovery().due_for_keepalive(KEEPALIVE_INTERVAL);
+        for endpoint in due.iter().filter_map(|node| node.parse::<SocketAddr>().ok()) {
+            let mut nonce = [0u8; CIRCUIT_ID_LEN];
+            OsRng.fill_bytes(&mut nonce);
+            let ping = TaiorPacket::control(nonce, vec![CONTROL_KEEPALIVE, KEEPALIVE_PING]);
+            if self.transmit(&ping, endpoint).await.is_ok() {
+                self.probes.insert(nonce, Probe { endpoint, sent: Instant::now() });
+            }
+        }
+    }
+
+    async fn handle_keepalive(&mut self, nonce: [u8; CIRCUIT_ID_LEN], message: &[u8], from: SocketAddr) {
+        match message {
+            [CONTROL_KEEPALIVE, KEEPALIVE_PING] => {
+                let pong = TaiorPacket::control(nonce, vec![CONTROL_KEEPALIVE, KEEPALIVE_PONG]);
+                let _ = self.transmit(&pong, from).await;
+            }
+            [CONTROL_KEEPALIVE, KEEPALIVE_PONG] if self.probes.get(&nonce).is_some_and(|probe| probe.endpoint == from) => {
+                if let Some(probe) = self.probes.remove(&nonce) {
+                    self.taior.record_node_seen(&from.to_string(), Some(probe.sent.elapsed()));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    async fn handle_gossip(&mut self, message: &[u8], from: SocketAddr) {
+        let Some(exchange) = self.gossip.receive(from, message, self.taior.discovery(), std::time::Instant::now()) else {
+            return;
+        };
+
+        // Our own descriptor comes back from neighbours we gave it to
+        let own = TaiorAddress(self.taior.address().to_string());
+        for descriptor in exchange.descriptors {
+            if descriptor.address() != own {
+                // Revisions older than the one known are refused, which is fine here
+                let _ = self.learn(descriptor, false);
+            }
+        }
+        match exchange.answer {
+            Some(answer) => {
+                let _ = self.transmit(&TaiorPacket::control([0; CIRCUIT_ID_LEN], answer), from).await;
+            }
+            // Answers only come back for offers we sent, so the neighbour is alive
+            None => self.taior.record_node_seen(&from.to_string(), None),
+        }
+        self.flush_queued().await;
+    }
+
+    async fn send_to(
+        &mut self,
+        dest: &TaiorAddress,
+        data: &[u8],
+        options: SendOptions,
+    ) -> Result<Option<AnonymityReport>, TaiorError> {
+        let Some(outgoing) = self.taior.send_to(dest, data, options)?.ready() else {
+            return Ok(None);
+        };
+        let anonymity = outgoing.anonymity;
+        self.send_outgoing(outgoing).await?;
+        Ok(Some(anonymity))
+    }
+
+    async fn send_outgoing(&mut self, outgoing: OutgoingPacket) -> Result<(), TaiorError> {
+        // Cover generated alongside the packet follows it to the same first hop, each
+        // with a delay of its own, and is dropped with it when it cannot go anywhere
+        let packets = std::iter::once(outgoing.packet);
+        #[cfg(feature = "mix-mode")]
+        let packets = packets.chain(self.taior.take_cover_packets());
+        let packets = packets.collect();
+
+        let first_hop = outgoing.first_hop
+            .ok_or_else(|| TaiorError::Discovery("first hop of the packet is unknown".to_string()))?;
+        self.send_to_node(&first_hop, packets, outgoing.delay).await
+    }
+
+    /// Sends `packets` to `node` after `delay`. Nodes are named by their endpoint,
+    /// either as `ip:port` or as `host:port`; host names are looked up by a task of
+    /// their own, so the loop keeps serving meanwhile, and the packets wait for it.
+    async fn send_to_node(
+        &mut self,
+        node: &str,
+        packets: Vec<TaiorPacket>,
+        delay: DelayDistribution,
+    ) -> Result<(), TaiorError> {
+        if let Ok(endpoint) = node.parse() {
+            for packet in packets {
+                self.send_after_delay(endpoint, packet, &delay).await?;
+            }
+            return Ok(());
+        }
+
+        let waiting = self.unresolved.entry(node.to_string()).or_default();
+        let lookup = waiting.is_empty();
+        waiting.extend(packets.into_iter().map(|packet| (packet, delay)));
+        if lookup {
+            let Some(commands) = self.resolver.upgrade() else {
+                self.unresolved.remove(node);
+                return Err(TaiorError::NodeStopped);
+            };
+            let node = node.to_string();
+            tokio::spawn(async move {
+                let endpoint = tokio::time::timeout(DEFAULT_HOP_TIMEOUT, tokio::net::lookup_host(&node))
+                    .await
+                    .ok()
+                    .and_then(Result::ok)
+                    .and_then(|mut endpoints| endpoints.next());
+                let _ = commands.send(Command::Resolved { node, endpoint }).await;
+            });
+        }
+        Ok(())
+    }
+
+    async fn send_after_delay(
+        &mut self,
+        dest: SocketAddr,
+        packet: TaiorPacket,
+        delay: &DelayDistribution,
+    ) -> Result<(), TaiorError> {
+        #[cfg(feature = "mix-mode")]
+        {
+            self.scheduler.schedule(dest, packet, delay);
+            Ok(())
+        }
+        #[cfg(not(feature = "mix-mode"))]
+        {
+            let _ = delay;
+            self.transmit(&packet, dest).await
+        }
+    }
+
+    /// Sends what the policy held back once new relays or a circuit may provide the
+    /// missing hops. Failures are dropped: the application was answered long ago.
+    async fn flush_queued(&mut self) {
+        while let Some(result) = self.taior.next_ready() {
+            if let Ok(outgoing) = result {
+                let _ = self.send_outgoing(outgoing).await;
+            }
+        }
+    }
+
+    async fn reply(&mut self, surb: Surb, data: &[u8]) -> Result<(), TaiorError> {
+        let (first_hop, packet) = self.taior.reply_with_surb(surb, data)?;
+        let node = self.taior
+            .resolve(&first_hop)
+            .map(|known| known.node.clone())
+            .ok_or_else(|| TaiorError::Discovery(format!("unknown first hop {}", first_hop.as_str())))?;
+        self.send_to_node(&node, vec![packet], ModeConfig::mix().delay()).await
+    }
+
+    async fn handle_packet(&mut self, packet: TaiorPacket, from: SocketAddr) {
+        if let Some(pending) = &self.pending {
+            if from == pending.entry && pending.handshake.is_reply(&packet) {
+                self.advance_circuit(&packet).await;
+                return;
+            }
+        }
+
+        if let PacketFormat::Control { circuit_id } = packet.format {
+            match packet.encrypted_payload.first() {
+                Some(&CONTROL_GOSSIP) => return self.handle_gossip(&packet.encrypted_payload, from).await,
+                Some(&CONTROL_KEEPALIVE) => return self.handle_keepalive(circuit_id, &packet.encrypted_payload, from).await,
+                _ => {}
+            }
+        }
+
+        if packet.format == PacketFormat::Direct {
+            self.deliver(&packet);
+            return;
+        }
+
+        // Replies through our reply blocks only open with the state the facade holds,
+        // which also makes sure each opens once
+        #[cfg(feature = "mix-mode")]
+        if self.taior.is_surb_reply(&packet) {
+            self.deliver(&packet);
+            return;
+        }
+
+        match self.relay.process(packet.clone(), from) {
+            Ok(RelayAction::Forward { next_hop, packet }) | Ok(RelayAction::Backward { prev_hop: next_hop, packet }) => {
+                let _ = self.transmit(&packet, next_hop).await;
+            }
+            Ok(RelayAction::Deliver(delivery)) => {
+                self.deliver(&delivery.packet);
+            }
+            // Not a circuit this node relays: a reply over our own circuit, which shows
+            // its entry is alive once it opens
+            Err(RelayError::UnknownCircuit) if matches!(packet.format, PacketFormat::Onion { .. }) => {
+                if self.deliver(&packet) == Some(MessageOrigin::CircuitReply) {
+                    self.taior.record_node_seen(&from.to_string(), None);
+                }
+            }
+            // Replays and anything else the relay refuses are dropped
+            Err(_) => {}
+        }
+    }
+
+    async fn advance_circuit(&mut self, reply: &TaiorPacket) {
+        let Some(mut pending) = self.pending.take() else {
+            return;
+        };
+
+        // The first answer comes straight from the entry; later ones cross the
+        // circuit built so far
+        if pending.handshake.hop_index() == 0 {
+            let entry = pending.entry.to_string();
+            self.taior.record_node_seen(&entry, Some(pending.sent.elapsed()));
+        }
+
+        let step = pending.handshake.handle_reply(reply).map_err(TaiorError::from);
+        match step {
+            Ok(HandshakeStep::Extend(request)) => match self.transmit(&request, pending.entry).await {
+                Ok(()) => {
+                    pending.sent = Instant::now();
+                    pending.deadline = pending.sent + DEFAULT_HOP_TIMEOUT;
+                    self.pending = Some(pending);
+                }
+                Err(e) => {
+                    let _ = pending.respond.send(Err(e));
+                }
+            },
+            Ok(HandshakeStep::Complete(circuit)) => {
+                self.taior.set_circuit(circuit);
+                let _ = pending.respond.send(Ok(()));
+                self.flush_queued().await;
+            }
+            Err(e) => {
+                let _ = pending.respond.send(Err(e));
+            }
+        }
+    }
+
+    /// Hands `packet` to the application if it opens, returning how it reached us.
+    fn deliver(&mut self, packet: &TaiorPacket) -> Option<MessageOrigin> {
+        // Packets that do not open are dropped like any other undeliverable traffic
+        let message = self.taior.receive(packet).ok()??;
+        let origin = message.origin;
+        let _ = self.inbound.try_send(message);
+        Some(origin)
+    }
+
+    /// Sends `packet` to `dest`, recording how long the transport took to take it, or
+    /// a failure of the node if it could not. Nodes are named by their endpoint, as in
+    /// `send_to_node`. Whether the link delivers is measured by the keepalives and
+    /// handshakes it answers, not here.
+    async fn transmit(&mut self, packet: &TaiorPacket, dest: SocketAddr) -> Result<(), TaiorError> {
+        let started = Instant::now();
+        let result = self.transport.send(packet, dest).await;
+        match result {
+            Ok(()) => self.taior.record_node_transfer(&dest.to_string(), packet.size(), started.elapsed()),
+            Err(_) => self.taior.record_node_failure(&dest.to_string()),
+        }
+        Ok(result?)
+    }
+
+    /// Sends the cover packet the schedule is due for, and sets when the next one is.
+    /// Cover that cannot be routed yet is skipped.
+    async fn cover_round(&mut self) {
+        #[cfg(feature = "mix-mode")]
+        if let Ok(outgoing) = self.taior.scheduled_cover() {
+            let _ = self.send_outgoing(outgoing).await;
+        }
+        self.next_cover = next_cover(&mut self.taior);
+    }
+}
+
+/// When the cover schedule set by `Taior::apply_profile` is next due, if there is one.
+fn next_cover(taior: &mut Taior) -> Option<Instant> {
+    #[cfg(feature = "mix-mode")]
+    {
+        taior.next_cover_delay().map(|delay| Instant::now() + delay)
+    }
+    #[cfg(not(feature = "mix-mode"))]
+    {
+        let _ = taior;
+        None
+    }
+}
+
+// Nodes relay Sphinx packets whatever their features, but only mixing ones send them
+#[cfg(all(test, feature = "mix-mode"))]
+mod tests {
+    use super::*;
+    use crate::circuit::current_timestamp;
+    use crate::descriptor::{Capabilities, Endpoint};
+    use crate::modes::RoutingMode;
+    use crate::sphinx::{SphinxHop, SphinxPacket, BODY_OVERHEAD};
+    use crate::transport::UdpTransport;
+    use ed25519_dalek::SigningKey;
+    use tokio_stream::StreamExt;
+    use x25519_dalek::StaticSecret;
+
+    async fn spawn_node() -> (TaiorNode, SocketAddr) {
+        let (node, endpoint, _) = spawn_announcing_node().await;
+        (node, endpoint)
+    }
+
+    /// A node together with a descriptor of it, signed before the node took its key.
+    async fn spawn_announcing_node() -> (TaiorNode, SocketAddr, NodeDescriptor) {
+        let transport = UdpTransport::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let endpoint = transport.local_addr().unwrap();
+        let taior = Taior::new();
+        let signing_key = SigningKey::generate(&mut OsRng);
+        let endpoints = vec![Endpoint::Udp(endpoint)];
+        let descriptor = taior.sign_descriptor(&signing_key, endpoints, Capabilities::RELAY, 1, current_timestamp() + 600);
+        (TaiorNode::spawn(taior, transport), endpoint, descriptor)
+    }
+
+    async fn next_message(stream: &mut ReceiverStream<IncomingMessage>) -> IncomingMessage {
+        tokio::time::timeout(Duration::from_secs(5), stream.next())
+            .await
+            .expect("message in time")
+            .expect("node still running")
+    }
+
+    #[tokio::test]
+    async fn test_nodes_talk_through_relaying_nodes() {
+        let mut relays = Vec::new();
+        for _ in 0..3 {
+            relays.push(spawn_node().await);
+        }
+        let (mut alice, alice_endpoint) = spawn_node().await;
+        let (mut bob, bob_endpoint) = spawn_node().await;
+
+        for (relay, _) in &relays {
+            for (other, other_endpoint) in &relays {
+                if other.address() != relay.address() {
+                    relay.add_relay(*other_endpoint, other.public_key()).await.unwrap();
+                }
+            }
+            relay.add_peer(alice_endpoint, alice.public_key()).await.unwrap();
+            relay.add_peer(bob_endpoint, bob.public_key()).await.unwrap();
+        }
+        for (relay, endpoint) in &relays {
+            alice.add_relay(*endpoint, relay.public_key()).await.unwrap();
+            bob.add_relay(*endpoint, relay.public_key()).await.unwrap();
+        }
+        let bob_address = alice.add_peer(bob_endpoint, bob.public_key()).await.unwrap();
+        assert_eq!(&bob_address, bob.address());
+
+        let mut alice_inbox = alice.incoming().unwrap();
+        let mut bob_inbox = bob.incoming().unwrap();
+        assert!(bob.incoming().is_none());
+
+        let options = SendOptions::custom(RoutingMode::Mix, 3).with_reply(3);
+        let anonymity = alice.send_to(&bob_address, b"hola bob", options).await.unwrap().unwrap();
+        assert_eq!(anonymity.hops, 3);
+        let message = next_message(&mut bob_inbox).await;
+        assert_eq!(message.data, b"hola bob");
+
+        bob.reply(message.reply.unwrap(), b"hola alice").await.unwrap();
+        let reply = next_message(&mut alice_inbox).await;
+        assert_eq!(reply.data, b"hola alice");
+        assert_eq!(reply.origin, MessageOrigin::SurbReply);
+
+        // Circuits are built by the node loop while it keeps relaying
+        alice.build_circuit(3).await.unwrap();
+
+        alice.shutdown().await.unwrap();
+        assert!(alice_inbox.next().await.is_none());
+        for (relay, _) in relays {
+            relay.shutdown().await.unwrap();
+        }
+        bob.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sends_measure_the_bandwidth_routing_weighs() {
+        let peer = UdpTransport::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let peer_endpoint = peer.local_addr().unwrap();
+        let stranger: SocketAddr = "127.0.0.1:9".parse().unwrap();
+        let mut taior = Taior::new();
+        taior.add_peer(peer_endpoint.to_string(), PublicKey::from(&StaticSecret::random_from_rng(OsRng)));
+
+        let transport = UdpTransport::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let (commands, command_rx) = mpsc::channel(1);
+        let (inbound, _incoming) = mpsc::channel(1);
+        let scheduler = SendScheduler::new(Arc::new(TokioClock));
+        let mut runtime = NodeLoop::new(taior, transport, scheduler, command_rx, commands.downgrade(), inbound);
+        let packet = TaiorPacket::new(b"hola", 3, 64, false).unwrap();
+        runtime.transmit(&packet, peer_endpoint).await.unwrap();
+        runtime.transmit(&packet, stranger).await.unwrap();
+
+        // The neighbour is measured under the name the router is handed it by
+        let node = peer_endpoint.to_string();
+        assert!(runtime.taior.discovery().get_neighbors().contains(&node));
+        let stats = runtime.taior.link_metrics().stats(&node).unwrap();
+        assert!(stats.throughput.is_some() && stats.bandwidth_rank().is_some());
+        assert!(runtime.taior.link_metrics().stats(&stranger.to_string()).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_replayed_sphinx_packets_are_delivered_once() {
+        let (mut bob, bob_endpoint) = spawn_node().await;
+        let mut inbox = bob.incoming().unwrap();
+
+        let inner = TaiorPacket::new_for_recipient(b"hola bob", &bob.public_key(), 3, 64, false).unwrap().encode().unwrap();
+        let route = [SphinxHop { address: bob.address().clone(), onion_key: bob.public_key() }];
+        let packet = SphinxPacket::build(&route, &inner, inner.len() + BODY_OVERHEAD).unwrap().into_taior_packet(3);
+        let mut sender = UdpTransport::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        sender.send(&packet, bob_endpoint).await.unwrap();
+        sender.send(&packet, bob_endpoint).await.unwrap();
+
+        assert_eq!(next_message(&mut inbox).await.data, b"hola bob");
+        assert!(tokio::time::timeout(Duration::from_millis(300), inbox.next()).await.is_err());
+        bob.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_gossip_spreads_descriptors_beyond_bootstrap() {
+        let mut nodes = Vec::new();
+        let mut descriptors = Vec::new();
+        for _ in 0..5 {
+            let (node, endpoint, descriptor) = spawn_announcing_node().await;
+            nodes.push((node, endpoint));
+            descriptors.push(descriptor);
+        }
+
+        // Each node starts out knowing the next one only
+        let config = GossipConfig {
+            interval: Duration::from_millis(50),
+            peer_interval: Duration::from_millis(20),
+            ..GossipConfig::default()
+        };
+        for (i, (node, _)) in nodes.iter().enumerate() {
+            node.announce(descriptors[i].clone()).await.unwrap();
+            node.configure_gossip(config).await.unwrap();
+            if let Some(next) = descriptors.get(i + 1) {
+                node.add_descriptor(next.clone()).await.unwrap();
+            }
+        }
+        assert!(nodes[1].0.announce(descriptors[0].clone()).await.is_err());
+
+        // Alice learns the relays and the destination at the far end of the chain
+        let mut inbox = nodes[4].0.incoming().unwrap();
+        let dest = nodes[4].0.address().clone();
+        let alice = &nodes[0].0;
+        let message = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                let _ = alice.send_to(&dest, b"hola dave", SendOptions::custom(RoutingMode::Mix, 3)).await;
+                if let Ok(Some(message)) = tokio::time::timeout(Duration::from_millis(200), inbox.next()).await {
+                    return message;
+                }
+            }
+        })
+        .await
+        .expect("descriptors gossiped in time");
+        assert_eq!(message.data, b"hola dave");
+
+        for (node, _) in nodes {
+            node.shutdown().await.unwrap();
+        }
+    }
+}
@@ -0,0 +1,705 @@
+//! Node-side packet processing: answer circuit handshakes, strip this node's
+//! layer, check the TTL and decide whether to forward to the next hop, relay a
+//! reply back towards the originator or deliver locally.
+
+use crate::circuit::{
+    add_backward_layer, current_timestamp_ms, peel_layer, CircuitError, HopKeys, LayerCommand, SequenceWindow, BACKWARD_EXTENDED,
+    BACKWARD_REPLY,
+};
+use crate::handshake::server_handshake;
+use crate::identity::TaiorAddress;
+use crate::packet::{PacketFormat, TaiorPacket, CIRCUIT_ID_LEN, CONTROL_CREATE, CONTROL_CREATED};
+use crate::sphinx::{PeeledPacket, SphinxError, SphinxPacket};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::time::Duration;
+use thiserror::Error;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+#[cfg(not(target_arch = "wasm32"))]
+use crate::transport::{Transport, TransportResult};
+
+/// Sphinx replay tags remembered before the oldest ones are forgotten.
+const REPLAY_CACHE_SIZE: usize = 65_536;
+
+/// Circuits a relay holds at once; further CREATEs are refused until some are evicted.
+pub const DEFAULT_MAX_CIRCUITS: usize = 8_192;
+
+/// A circuit that carries no traffic for this long is forgotten.
+pub const DEFAULT_CIRCUIT_IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Circuits are forgotten this long after their CREATE even if still in use. Clients
+/// rebuild theirs after ten minutes, so this only catches state nobody will ask for.
+pub const MAX_CIRCUIT_AGE: Duration = Duration::from_secs(30 * 60);
+
+/// Longest gap between two sweeps of idle circuits in `serve_one`.
+const CIRCUIT_SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Error, Debug)]
+pub enum RelayError {
+    #[error("Circuito desconocido")]
+    UnknownCircuit,
+    #[error("Circuito ya existente")]
+    DuplicateCircuit,
+    #[error("Límite de circuitos alcanzado")]
+    CircuitLimit,
+    #[error("TTL agotado")]
+    TtlExpired,
+    #[error("Sin ruta hacia el siguiente hop {0}")]
+    UnknownNextHop(String),
+    #[error("Paquete repetido")]
+    Replay,
+    #[error("Formato de paquete no soportado por este relay")]
+    UnsupportedFormat,
+    #[error("Paquete interno inválido: {0}")]
+    MalformedInner(String),
+    #[error("Mensaje de control inválido")]
+    MalformedControl,
+    #[error("Solo el último hop de un circuito puede responder")]
+    NotLastHop,
+    #[error(transparent)]
+    Circuit(#[from] CircuitError),
+    #[error(transparent)]
+    Sphinx(#[from] SphinxError),
+}
+
+/// Outcome of processing one inbound packet.
+#[derive(Debug)]
+pub enum RelayAction {
+    /// Send `packet` on to `next_hop`, away from the circuit originator.
+    Forward {
+        next_hop: SocketAddr,
+        packet: TaiorPacket,
+    },
+    /// Send `packet` back to `prev_hop`, towards the circuit originator.
+    Backward {
+        prev_hop: SocketAddr,
+        packet: TaiorPacket,
+    },
+    /// This node is the last hop of the packet.
+    Deliver(Delivery),
+}
+
+/// An end-to-end packet that reached its last hop.
+#[derive(Debug)]
+pub struct Delivery {
+    pub packet: TaiorPacket,
+    /// Circuit the packet arrived on, to answer it with `OnionRelay::reply`.
+    /// `None` for Sphinx packets, which carry no return path.
+    pub circuit_id: Option<[u8; CIRCUIT_ID_LEN]>,
+}
+
+/// State a relay holds for one circuit passing through it.
+#[derive(Debug, Clone)]
+struct HopState {
+    keys: HopKeys,
+    /// Forward cells accepted so far; replayed and out-of-order cells are rejected.
+    forward_window: SequenceWindow,
+    /// Sequence number of the next backward layer this hop adds.
+    backward_seq: u64,
+    /// Where the circuit came from; backward traffic is sent there.
+    prev: SocketAddr,
+    /// Successor endpoint and the circuit id it files the circuit under, once known.
+    next: Option<(SocketAddr, [u8; CIRCUIT_ID_LEN])>,
+    /// When the CREATE arrived and when the circuit last carried a cell, in ms.
+    created_ms: u64,
+    last_used_ms: u64,
+}
+
+pub struct OnionRelay {
+    address: TaiorAddress,
+    onion_secret: StaticSecret,
+    circuits: HashMap<[u8; CIRCUIT_ID_LEN], HopState>,
+    /// Successor circuit id -> our own circuit id, to route backward traffic.
+    outbound: HashMap<[u8; CIRCUIT_ID_LEN], [u8; CIRCUIT_ID_LEN]>,
+    peers: HashMap<TaiorAddress, SocketAddr>,
+    seen_tags: HashSet<[u8; 32]>,
+    seen_order: VecDeque<[u8; 32]>,
+    max_circuits: usize,
+    idle_timeout: Duration,
+    next_sweep_ms: u64,
+}
+
+impl OnionRelay {
+    /// Creates a relay around its long-lived onion key. The relay's address is derived from it.
+    pub fn new(onion_secret: StaticSecret) -> Self {
+        let address = TaiorAddress::from_public_key(&PublicKey::from(&onion_secret));
+        Self {
+            address,
+            onion_secret,
+            circuits: HashMap::new(),
+            outbound: HashMap::new(),
+            peers: HashMap::new(),
+            seen_tags: HashSet::new(),
+            seen_order: VecDeque::new(),
+            max_circuits: DEFAULT_MAX_CIRCUITS,
+            idle_timeout: DEFAULT_CIRCUIT_IDLE_TIMEOUT,
+            next_sweep_ms: 0,
+        }
+    }
+
+    /// Caps how many circuits the relay holds and how long an idle one is kept.
+    pub fn with_circuit_limits(mut self, max_circuits: usize, idle_timeout: Duration) -> Self {
+        self.max_circuits = max_circuits;
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    pub fn address(&self) -> &TaiorAddress {
+        &self.address
+    }
+
+    pub fn onion_key(&self) -> PublicKey {
+        PublicKey::from(&self.onion_secret)
+    }
+
+    pub fn remove_circuit(&mut self, circuit_id: &[u8; CIRCUIT_ID_LEN]) {
+        if let Some(HopState { next: Some((_, next_id)), .. }) = self.circuits.remove(circuit_id) {
+            self.outbound.remove(&next_id);
+        }
+    }
+
+    /// Number of circuits the relay currently holds.
+    pub fn circuit_count(&self) -> usize {
+        self.circuits.len()
+    }
+
+    /// Forgets circuits idle for longer than the idle timeout or older than
+    /// `MAX_CIRCUIT_AGE` at `now_ms`.
+    fn evict_stale(&mut self, now_ms: u64) {
+        let idle_ms = self.idle_timeout.as_millis() as u64;
+        let max_age_ms = MAX_CIRCUIT_AGE.as_millis() as u64;
+        let stale: Vec<_> = self
+            .circuits
+            .iter()
+            .filter(|(_, hop)| {
+                now_ms.saturating_sub(hop.last_used_ms) >= idle_ms || now_ms.saturating_sub(hop.created_ms) >= max_age_ms
+            })
+            .map(|(circuit_id, _)| *circuit_id)
+            .collect();
+        for circuit_id in stale {
+            self.remove_circuit(&circuit_id);
+        }
+        self.next_sweep_ms = now_ms + idle_ms.min(CIRCUIT_SWEEP_INTERVAL.as_millis() as u64);
+    }
+
+    /// Records where a neighbouring node can be reached.
+    pub fn add_peer(&mut self, address: TaiorAddress, endpoint: SocketAddr) {
+        self.peers.insert(address, endpoint);
+    }
+
+    /// Processes a packet received from `from`.
+    pub fn process(&mut self, packet: TaiorPacket, from: SocketAddr) -> Result<RelayAction, RelayError> {
+        match packet.format {
+            PacketFormat::Control { circuit_id } => self.process_control(circuit_id, packet, from),
+            PacketFormat::Onion { circuit_id } if self.circuits.contains_key(&circuit_id) => {
+                self.process_onion(circuit_id, packet)
+            }
+            PacketFormat::Onion { circuit_id } => self.process_backward(circuit_id, &packet.encrypted_payload, from),
+            PacketFormat::Sphinx => self.process_sphinx(packet),
+            PacketFormat::Direct => Err(RelayError::UnsupportedFormat),
+        }
+    }
+
+    fn process_control(
+        &mut self,
+        circuit_id: [u8; CIRCUIT_ID_LEN],
+        packet: TaiorPacket,
+        from: SocketAddr,
+    ) -> Result<RelayAction, RelayError> {
+        match packet.encrypted_payload.first() {
+            Some(&CONTROL_CREATE) => {
+                if self.circuits.contains_key(&circuit_id) || self.outbound.contains_key(&circuit_id) {
+                    return Err(RelayError::DuplicateCircuit);
+                }
+                let now_ms = current_timestamp_ms();
+                if self.circuits.len() >= self.max_circuits {
+                    self.evict_stale(now_ms);
+                    if self.circuits.len() >= self.max_circuits {
+                        return Err(RelayError::CircuitLimit);
+                    }
+                }
+                let (created, keys) = server_handshake(&self.onion_secret, &packet.encrypted_payload)?;
+                self.circuits.insert(
+                    circuit_id,
+                    HopState {
+                        keys,
+                        forward_window: SequenceWindow::default(),
+                        backward_seq: 0,
+                        prev: from,
+                        next: None,
+                        created_ms: now_ms,
+                        last_used_ms: now_ms,
+                    },
+                );
+                Ok(RelayAction::Backward {
+                    prev_hop: from,
+                    packet: TaiorPacket::control(circuit_id, created),
+                })
+            }
+            // A successor answered an extension we asked for: pass it back as an EXTENDED reply
+            Some(&CONTROL_CREATED) => {
+                let mut extended = vec![BACKWARD_EXTENDED];
+                extended.extend_from_slice(&packet.encrypted_payload);
+                self.process_backward(circuit_id, &extended, from)
+            }
+            _ => Err(RelayError::MalformedControl),
+        }
+    }
+
+    fn process_onion(&mut self, circuit_id: [u8; CIRCUIT_ID_LEN], packet: TaiorPacket) -> Result<RelayAction, RelayError> {
+        if packet.ttl == 0 {
+            return Err(RelayError::TtlExpired);
+        }
+
+        let hop = self.circuits.get_mut(&circuit_id).ok_or(RelayError::UnknownCircuit)?;
+        let (command, inner) = peel_layer(&hop.keys, &mut hop.forward_window, &packet.encrypted_payload)?;
+        hop.last_used_ms = current_timestamp_ms();
+
+        match command {
+            LayerCommand::Forward { next_hop, circuit_id: next_id } => {
+                if packet.ttl <= 1 {
+                    return Err(RelayError::TtlExpired);
+                }
+                let endpoint = self.link(circuit_id, &next_hop, next_id)?;
+                Ok(RelayAction::Forward {
+                    next_hop: endpoint,
+                    packet: TaiorPacket {
+                        encrypted_payload: inner,
+                        ikm: Vec::new(),
+                        ephemeral_public: None,
+                        ttl: packet.ttl - 1,
+                        format: PacketFormat::Onion { circuit_id: next_id },
+                    },
+                })
+            }
+            LayerCommand::Extend { next_hop, circuit_id: next_id } => {
+                if inner.first() != Some(&CONTROL_CREATE) {
+                    return Err(RelayError::MalformedControl);
+                }
+                let endpoint = self.link(circuit_id, &next_hop, next_id)?;
+                Ok(RelayAction::Forward {
+                    next_hop: endpoint,
+                    packet: TaiorPacket::control(next_id, inner),
+                })
+            }
+            LayerCommand::Deliver => Ok(RelayAction::Deliver(Delivery {
+                packet: decode_inner(&inner)?,
+                circuit_id: Some(circuit_id),
+            })),
+        }
+    }
+
+    /// Answers a packet delivered over `circuit_id`. The reply travels back through
+    /// every hop, each adding its backward layer, so this node never learns where
+    /// the circuit started.
+    pub fn reply(&mut self, circuit_id: &[u8; CIRCUIT_ID_LEN], reply: &TaiorPacket) -> Result<RelayAction, RelayError> {
+        let hop = self.circuits.get_mut(circuit_id).ok_or(RelayError::UnknownCircuit)?;
+        if hop.next.is_some() {
+            return Err(RelayError::NotLastHop);
+        }
+        hop.last_used_ms = current_timestamp_ms();
+
+        let mut plaintext = vec![BACKWARD_REPLY];
+        plaintext.extend_from_slice(&reply.encode().map_err(|e| RelayError::MalformedInner(e.to_string()))?);
+        Ok(RelayAction::Backward {
+            prev_hop: hop.prev,
+            packet: backward_cell(*circuit_id, add_backward_layer(&hop.keys, &mut hop.backward_seq, &plaintext)?),
+        })
+    }
+
+    /// Adds this hop's backward layer to traffic coming from the successor and
+    /// sends it on towards the originator.
+    fn process_backward(
+        &mut self,
+        next_id: [u8; CIRCUIT_ID_LEN],
+        payload: &[u8],
+        from: SocketAddr,
+    ) -> Result<RelayAction, RelayError> {
+        let circuit_id = *self.outbound.get(&next_id).ok_or(RelayError::UnknownCircuit)?;
+        let hop = self.circuits.get_mut(&circuit_id).ok_or(RelayError::UnknownCircuit)?;
+        if hop.next.map(|(endpoint, _)| endpoint) != Some(from) {
+            return Err(RelayError::UnknownCircuit);
+        }
+        hop.last_used_ms = current_timestamp_ms();
+
+        Ok(RelayAction::Backward {
+            prev_hop: hop.prev,
+            packet: backward_cell(circuit_id, add_backward_layer(&hop.keys, &mut hop.backward_seq, payload)?),
+        })
+    }
+
+    /// Resolves the successor of a circuit and remembers it for backward traffic.
+    fn link(
+        &mut self,
+        circuit_id: [u8; CIRCUIT_ID_LEN],
+        next_hop: &TaiorAddress,
+        next_id: [u8; CIRCUIT_ID_LEN],
+    ) -> Result<SocketAddr, RelayError> {
+        let endpoint = self.resolve(next_hop)?;
+        let hop = self.circuits.get(&circuit_id).ok_or(RelayError::UnknownCircuit)?;
+        match hop.next {
+            Some((known, known_id)) if known == endpoint && known_id == next_id => return Ok(endpoint),
+            Some(_) => return Err(RelayError::DuplicateCircuit),
+            None => {}
+        }
+        if self.outbound.contains_key(&next_id) || self.circuits.contains_key(&next_id) {
+            return Err(RelayError::DuplicateCircuit);
+        }
+
+        if let Some(hop) = self.circuits.get_mut(&circuit_id) {
+            hop.next = Some((endpoint, next_id));
+        }
+        self.outbound.insert(next_id, circuit_id);
+        Ok(endpoint)
+    }
+
+    fn process_sphinx(&mut self, packet: TaiorPacket) -> Result<RelayAction, RelayError> {
+        let sphinx = SphinxPacket::from_taior_packet(&packet)?;
+        let result = sphinx.peel(&self.onion_secret)?;
+        self.remember_tag(result.replay_tag)?;
+
+        match result.peeled {
+            PeeledPacket::Forward { next_hop, packet: next } => {
+                let endpoint = self.resolve(&next_hop)?;
+                Ok(RelayAction::Forward {
+                    next_hop: endpoint,
+                    packet: next.into_taior_packet(packet.ttl),
+                })
+            }
+            PeeledPacket::Deliver { payload } => Ok(RelayAction::Deliver(Delivery {
+                packet: decode_inner(&payload)?,
+                circuit_id: None,
+            })),
+        }
+    }
+
+    fn remember_tag(&mut self, tag: [u8; 32]) -> Result<(), RelayError> {
+        if !self.seen_tags.insert(tag) {
+            return Err(RelayError::Replay);
+        }
+        self.seen_order.push_back(tag);
+        if self.seen_order.len() > REPLAY_CACHE_SIZE {
+            if let Some(oldest) = self.seen_order.pop_front() {
+                self.seen_tags.remove(&oldest);
+            }
+        }
+        Ok(())
+    }
+
+    fn resolve(&self, address: &TaiorAddress) -> Result<SocketAddr, RelayError> {
+        self.peers
+            .get(address)
+            .copied()
+            .ok_or_else(|| RelayError::UnknownNextHop(address.as_str().to_string()))
+    }
+
+    /// Receives one packet from `transport` and acts on it. Forwarded and backward
+    /// packets are sent straight away; a delivered packet is returned to the caller.
+    /// Packets that cannot be read, fail processing or cannot be sent on are dropped;
+    /// only fatal transport errors are returned. Idle circuits are swept on the way.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn serve_one<T: Transport + ?Sized>(&mut self, transport: &mut T) -> TransportResult<Option<Delivery>> {
+        let (packet, from) = match transport.receive().await {
+            Ok(received) => received,
+            Err(e) if !e.is_fatal() => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let now_ms = current_timestamp_ms();
+        if now_ms >= self.next_sweep_ms {
+            self.evict_stale(now_ms);
+        }
+        let (next_hop, packet) = match self.process(packet, from) {
+            Ok(RelayAction::Forward { next_hop, packet }) | Ok(RelayAction::Backward { prev_hop: next_hop, packet }) => {
+                (next_hop, packet)
+            }
+            Ok(RelayAction::Deliver(delivery)) => return Ok(Some(delivery)),
+            Err(_) => return Ok(None),
+        };
+        // An unreachable next hop costs this packet only; the relay keeps serving
+        let _ = transport.send(&packet, next_hop).await;
+        Ok(None)
+    }
+
+    /// Sends `reply` back over the circuit a delivery arrived on. See `reply`.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn send_reply<T: Transport + ?Sized>(
+        &mut self,
+        transport: &mut T,
+        circuit_id: &[u8; CIRCUIT_ID_LEN],
+        reply: &TaiorPacket,
+    ) -> Result<(), RelayError> {
+        if let RelayAction::Backward { prev_hop, packet } = self.reply(circuit_id, reply)? {
+            transport
+                .send(&packet, prev_hop)
+                .await
+                .map_err(|e| RelayError::Circuit(CircuitError::Transport(e.to_string())))?;
+        }
+        Ok(())
+    }
+}
+
+/// Backward cells carry no TTL: their path is fixed by the circuit state of each hop.
+fn backward_cell(circuit_id: [u8; CIRCUIT_ID_LEN], payload: Vec<u8>) -> TaiorPacket {
+    TaiorPacket {
+        encrypted_payload: payload,
+        ikm: Vec::new(),
+        ephemeral_public: None,
+        ttl: 0,
+        format: PacketFormat::Onion { circuit_id },
+    }
+}
+
+fn decode_inner(bytes: &[u8]) -> Result<TaiorPacket, RelayError> {
+    TaiorPacket::decode(bytes).map_err(|e| RelayError::MalformedInner(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::circuit::{CircuitBuilder, OnionEncryptor, DEFAULT_HOP_TIMEOUT};
+    use crate::transport::UdpTransport;
+    use rand_core::OsRng;
+    use std::time::Duration;
+
+    fn relays(count: usize) -> Vec<OnionRelay> {
+        (0..count)
+            .map(|_| OnionRelay::new(StaticSecret::random_from_rng(OsRng)))
+            .collect()
+    }
+
+    /// Binds one UDP socket per relay, tells every relay where the others live
+    /// and registers them in a circuit builder under their socket addresses.
+    async fn mesh(relays: &mut [OnionRelay]) -> (Vec<UdpTransport>, Vec<SocketAddr>, CircuitBuilder) {
+        let mut transports = Vec::new();
+        for _ in 0..relays.len() {
+            transports.push(UdpTransport::bind("127.0.0.1:0".parse().unwrap()).await.unwrap());
+        }
+        let endpoints: Vec<SocketAddr> = transports.iter().map(|t| t.local_addr().unwrap()).collect();
+        let addresses: Vec<_> = relays.iter().map(|r| r.address().clone()).collect();
+
+        let mut builder = CircuitBuilder::new(1, 5, 600);
+        for (relay, endpoint) in relays.iter_mut().zip(&endpoints) {
+            builder.add_node_with_onion_key(endpoint.to_string(), relay.address().clone(), relay.onion_key());
+            for (address, endpoint) in addresses.iter().zip(&endpoints) {
+                relay.add_peer(address.clone(), *endpoint);
+            }
+        }
+        (transports, endpoints, builder)
+    }
+
+    fn endpoint_of(relays: &[TaiorAddress], endpoints: &[SocketAddr], address: &TaiorAddress) -> SocketAddr {
+        endpoints[relays.iter().position(|a| a == address).unwrap()]
+    }
+
+    /// Runs every relay on its socket. Deliveries are reported on the returned
+    /// channel and, when they came over a circuit, answered with an echo.
+    fn spawn_relays(
+        relays: Vec<OnionRelay>,
+        transports: Vec<UdpTransport>,
+    ) -> tokio::sync::mpsc::Receiver<(TaiorAddress, TaiorPacket)> {
+        let (delivered_tx, delivered_rx) = tokio::sync::mpsc::channel(1);
+        for (mut relay, mut transport) in relays.into_iter().zip(transports) {
+            let delivered_tx = delivered_tx.clone();
+            tokio::spawn(async move {
+                while let Ok(result) = relay.serve_one(&mut transport).await {
+                    let Some(delivery) = result else { continue };
+                    if let Some(circuit_id) = delivery.circuit_id {
+                        let mut echo = b"eco: ".to_vec();
+                        echo.extend_from_slice(&delivery.packet.decrypt_with_ikm().unwrap().data);
+                        let reply = TaiorPacket::new(&echo, 3, 64, false).unwrap();
+                        relay.send_reply(&mut transport, &circuit_id, &reply).await.unwrap();
+                    }
+                    let _ = delivered_tx.send((relay.address().clone(), delivery.packet)).await;
+                }
+            });
+        }
+        delivered_rx
+    }
+
+    #[test]
+    fn test_sphinx_packet_is_forwarded_then_delivered_once() {
+        let mut relays = relays(3);
+        let endpoints: Vec<SocketAddr> = (0..3)
+            .map(|i| format!("127.0.0.1:{}", 7000 + i).parse().unwrap())
+            .collect();
+        let addresses: Vec<_> = relays.iter().map(|r| (r.address().clone(), r.onion_key())).collect();
+        for relay in relays.iter_mut() {
+            for (i, (address, _)) in addresses.iter().enumerate() {
+                relay.add_peer(address.clone(), endpoints[i]);
+            }
+        }
+
+        let route: Vec<_> = addresses
+            .iter()
+            .map(|(address, onion_key)| crate::sphinx::SphinxHop {
+                address: address.clone(),
+                onion_key: *onion_key,
+            })
+            .collect();
+        let inner = TaiorPacket::new(b"hola mix", 3, 64, false).unwrap();
+        let encoded = inner.encode().unwrap();
+        let sphinx = SphinxPacket::build(&route, &encoded, encoded.len() + crate::sphinx::BODY_OVERHEAD).unwrap();
+        let first = sphinx.into_taior_packet(3);
+        let client: SocketAddr = "127.0.0.1:6999".parse().unwrap();
+
+        let mut packet = first.clone();
+        let mut from = client;
+        for (i, relay) in relays.iter_mut().enumerate() {
+            match relay.process(packet.clone(), from).unwrap() {
+                RelayAction::Forward { next_hop, packet: next } => {
+                    assert_eq!(next_hop, endpoints[i + 1]);
+                    packet = next;
+                    from = endpoints[i];
+                }
+                RelayAction::Deliver(delivered) => {
+                    assert_eq!(i, 2);
+                    assert!(delivered.circuit_id.is_none());
+                    assert_eq!(delivered.packet.decrypt_with_ikm().unwrap().data, b"hola mix");
+                }
+                RelayAction::Backward { .. } => panic!("Sphinx packets only travel forward"),
+            }
+        }
+
+        assert!(matches!(relays[0].process(first, client), Err(RelayError::Replay)));
+    }
+
+    #[test]
+    fn test_idle_circuits_are_evicted_and_the_limit_refuses_creates() {
+        let mut relay = relays(1).pop().unwrap().with_circuit_limits(2, Duration::from_secs(60));
+        let client: SocketAddr = "127.0.0.1:6999".parse().unwrap();
+        let create = |relay: &mut OnionRelay, id: u8| {
+            let ntor = crate::handshake::NtorClient::new(relay.address(), relay.onion_key()).unwrap();
+            relay.process(TaiorPacket::control([id; CIRCUIT_ID_LEN], ntor.create_message()), client)
+        };
+
+        assert!(create(&mut relay, 1).is_ok());
+        assert!(create(&mut relay, 2).is_ok());
+        assert!(matches!(create(&mut relay, 3), Err(RelayError::CircuitLimit)));
+
+        let now_ms = current_timestamp_ms();
+        relay.evict_stale(now_ms + 30_000);
+        assert_eq!(relay.circuit_count(), 2);
+
+        // A circuit still in use outlives the idle timeout but not the maximum age
+        relay.circuits.get_mut(&[1; CIRCUIT_ID_LEN]).unwrap().last_used_ms = now_ms + 50_000;
+        relay.evict_stale(now_ms + 61_000);
+        assert_eq!(relay.circuit_count(), 1);
+        relay.circuits.get_mut(&[1; CIRCUIT_ID_LEN]).unwrap().last_used_ms = now_ms + MAX_CIRCUIT_AGE.as_millis() as u64;
+        relay.evict_stale(now_ms + MAX_CIRCUIT_AGE.as_millis() as u64);
+        assert_eq!(relay.circuit_count(), 0);
+
+        assert!(create(&mut relay, 3).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_next_hop_does_not_stop_the_relay() {
+        let mut relays = relays(2);
+        let mut transport = UdpTransport::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let endpoint = transport.local_addr().unwrap();
+        // An IPv4 socket cannot send to an IPv6 endpoint
+        let next = relays[1].address().clone();
+        relays[0].add_peer(next, "[::1]:9".parse().unwrap());
+
+        let hops: Vec<_> = relays
+            .iter()
+            .map(|r| crate::sphinx::SphinxHop { address: r.address().clone(), onion_key: r.onion_key() })
+            .collect();
+        let sphinx = |route: &[crate::sphinx::SphinxHop], data: &[u8]| {
+            let encoded = TaiorPacket::new(data, 3, 64, false).unwrap().encode().unwrap();
+            SphinxPacket::build(route, &encoded, encoded.len() + crate::sphinx::BODY_OVERHEAD)
+                .unwrap()
+                .into_taior_packet(3)
+        };
+
+        let mut client = UdpTransport::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        client.send(&sphinx(&hops, b"sin salida"), endpoint).await.unwrap();
+        assert!(relays[0].serve_one(&mut transport).await.unwrap().is_none());
+
+        client.send(&sphinx(&hops[..1], b"hola"), endpoint).await.unwrap();
+        let delivery = relays[0].serve_one(&mut transport).await.unwrap().unwrap();
+        assert_eq!(delivery.packet.decrypt_with_ikm().unwrap().data, b"hola");
+    }
+
+    #[tokio::test]
+    async fn test_handshaken_circuit_crosses_udp_mesh() {
+        let mut relays = relays(3);
+        let (transports, endpoints, mut builder) = mesh(&mut relays).await;
+        let addresses: Vec<_> = relays.iter().map(|r| r.address().clone()).collect();
+        let mut delivered_rx = spawn_relays(relays, transports);
+
+        let mut client = UdpTransport::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let circuit = builder.build_circuit(&mut client, 3, DEFAULT_HOP_TIMEOUT).await.unwrap();
+        let entry = endpoint_of(&addresses, &endpoints, &circuit.nodes[0].address);
+        let exit = circuit.nodes[2].address.clone();
+
+        let inner = TaiorPacket::new(b"extremo a extremo", 3, 64, false).unwrap();
+        let mut circuit = circuit;
+        let packet = OnionEncryptor::new(&mut circuit).wrap_packet(&inner).unwrap();
+        client.send(&packet, entry).await.unwrap();
+
+        let (delivered_at, delivered) = tokio::time::timeout(Duration::from_secs(5), delivered_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(delivered_at, exit);
+        assert_eq!(delivered.decrypt_with_ikm().unwrap().data, b"extremo a extremo");
+    }
+
+    #[tokio::test]
+    async fn test_silent_hop_raises_hop_timeout() {
+        let mut relays = relays(2);
+        let (transports, endpoints, mut builder) = mesh(&mut relays).await;
+        let addresses: Vec<_> = relays.iter().map(|r| r.address().clone()).collect();
+
+        // A third node that is registered everywhere but never answers
+        let silent = UdpTransport::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let silent_endpoint = silent.local_addr().unwrap();
+        let silent_secret = StaticSecret::random_from_rng(OsRng);
+        let silent_key = PublicKey::from(&silent_secret);
+        let silent_address = TaiorAddress::from_public_key(&silent_key);
+        for relay in relays.iter_mut() {
+            relay.add_peer(silent_address.clone(), silent_endpoint);
+        }
+        spawn_relays(relays, transports);
+
+        let mut client = UdpTransport::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let live = builder.select_path(2).unwrap();
+        let path = vec![live[0].clone(), crate::sphinx::SphinxHop { address: silent_address, onion_key: silent_key }];
+        let entry = endpoint_of(&addresses, &endpoints, &live[0].address);
+
+        let result = crate::handshake::establish_circuit(&mut client, entry, path, 600, Duration::from_millis(300)).await;
+        assert!(matches!(result, Err(CircuitError::HopTimeout(1))));
+        drop(silent);
+    }
+
+    #[tokio::test]
+    async fn test_last_hop_reply_reaches_originator_through_backward_layers() {
+        let mut relays = relays(3);
+        let (transports, endpoints, mut builder) = mesh(&mut relays).await;
+        let addresses: Vec<_> = relays.iter().map(|r| r.address().clone()).collect();
+        let _delivered_rx = spawn_relays(relays, transports);
+
+        let mut client = UdpTransport::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let mut circuit = builder.build_circuit(&mut client, 3, DEFAULT_HOP_TIMEOUT).await.unwrap();
+        let entry = endpoint_of(&addresses, &endpoints, &circuit.nodes[0].address);
+
+        let request = TaiorPacket::new(b"pregunta", 3, 64, false).unwrap();
+        let packet = OnionEncryptor::new(&mut circuit).wrap_packet(&request).unwrap();
+        client.send(&packet, entry).await.unwrap();
+
+        let (backward, from) = tokio::time::timeout(Duration::from_secs(5), client.receive())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(from, entry);
+
+        let mut encryptor = OnionEncryptor::new(&mut circuit);
+        let reply = encryptor.decrypt_backward(&backward).unwrap();
+        assert_eq!(reply.decrypt_with_ikm().unwrap().data, b"eco: pregunta");
+        assert!(matches!(
+            encryptor.decrypt_backward(&backward),
+            Err(CircuitError::ReplayedCell(_))
+        ));
+    }
+}
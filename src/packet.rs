@@ -1,70 +1,416 @@
+use crate::error::TaiorError;
 use chacha20poly1305::{aead::Aead, aead::KeyInit, ChaCha20Poly1305, Key, Nonce};
 use hkdf::Hkdf;
 use rand_core::{OsRng, RngCore};
 use sha2::Sha256;
 use serde::{Deserialize, Serialize};
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+
+/// HKDF info label for packets keyed from a random IKM.
+const PACKET_KEY_LABEL: &[u8] = b"taior-packet-v1";
+/// HKDF info label for packets keyed from an X25519 exchange with the recipient.
+const SEALED_KEY_LABEL: &[u8] = b"taior-sealed-v1";
+
+/// Wire format version written as the first byte of every encoded packet.
+pub const WIRE_VERSION: u8 = 1;
+/// Size of the key material slot (raw IKM, ephemeral X25519 public key or circuit id).
+pub const KEY_MATERIAL_LEN: usize = 32;
+/// Fixed header: version, flags, ttl, key material, payload length (u32 BE).
+pub const HEADER_LEN: usize = 1 + 1 + 1 + KEY_MATERIAL_LEN + 4;
+/// Largest encrypted payload accepted on the wire.
+pub const MAX_PAYLOAD_LEN: usize = 32 * 1024;
+/// Smallest valid encrypted payload: an empty plaintext plus the Poly1305 tag.
+const MIN_PAYLOAD_LEN: usize = 16;
+
+/// Length of the per-hop circuit identifier carried by onion packets.
+pub const CIRCUIT_ID_LEN: usize = 16;
+
+const FLAG_RECIPIENT_KEYED: u8 = 0b0000_0001;
+const FLAG_SPHINX: u8 = 0b0000_0010;
+const FLAG_ONION: u8 = 0b0000_0100;
+const FLAG_CONTROL: u8 = 0b0000_1000;
+const KNOWN_FLAGS: u8 = FLAG_RECIPIENT_KEYED | FLAG_SPHINX | FLAG_ONION | FLAG_CONTROL;
+
+/// Control message types, carried as the first payload byte of `PacketFormat::Control` packets.
+pub const CONTROL_CREATE: u8 = 0x01;
+pub const CONTROL_CREATED: u8 = 0x02;
+/// DHT request or response, see `dht`.
+pub const CONTROL_DHT: u8 = 0x10;
+/// Descriptor sample swapped with a neighbour, see `gossip`.
+pub const CONTROL_GOSSIP: u8 = 0x11;
+/// Keepalive probe or its answer, exchanged by node runtimes.
+pub const CONTROL_KEEPALIVE: u8 = 0x12;
+
+/// Inner frame header: kind byte plus data length (u32 BE). Lives inside the AEAD envelope.
+pub const FRAME_HEADER_LEN: usize = 1 + 4;
+const FRAME_KIND_DATA: u8 = 0;
+const FRAME_KIND_COVER: u8 = 1;
+/// Data frame followed by a reply block length (u32 BE) and the reply block itself.
+const FRAME_KIND_DATA_WITH_REPLY: u8 = 2;
+
+/// Plaintext recovered from a packet: the application data and whether the sender
+/// marked it as cover traffic. Only the party that decrypts can see the marking.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PacketContents {
+    pub data: Vec<u8>,
+    pub is_cover: bool,
+    /// Opaque reply block attached by the sender (an encoded `crate::sphinx::Surb`).
+    pub reply_block: Option<Vec<u8>>,
+}
+
+/// How `encrypted_payload` is laid out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PacketFormat {
+    /// Single end-to-end AEAD envelope.
+    Direct,
+    /// Fixed-size Sphinx packet (see `crate::sphinx`); the key material slot is unused.
+    Sphinx,
+    /// Nested onion layers over a circuit. `circuit_id` tells the receiving hop
+    /// which of its circuits (and therefore which key) the packet belongs to.
+    Onion { circuit_id: [u8; CIRCUIT_ID_LEN] },
+    /// Plaintext link-level control message between two adjacent nodes (circuit
+    /// handshakes and similar). The payload starts with a `CONTROL_*` type byte.
+    Control { circuit_id: [u8; CIRCUIT_ID_LEN] },
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TaiorPacket {
     pub encrypted_payload: Vec<u8>,
     /// Ephemeral key material needed by the receiver to derive decryption key
     pub ikm: Vec<u8>,
+    /// Sender's ephemeral X25519 public key for recipient-keyed packets.
+    /// When set, `ikm` is empty and only the recipient's secret can derive the key.
+    pub ephemeral_public: Option<[u8; 32]>,
     pub ttl: u8,
-    pub is_cover: bool,
+    pub format: PacketFormat,
 }
 
 impl TaiorPacket {
-    pub fn new(payload: &[u8], ttl: u8, padding_size: usize, is_cover: bool) -> Result<Self, String> {
-        let target = if padding_size > 0 { padding_size } else { payload.len() };
-        let padded = pad_payload(payload, target);
+    pub fn new(payload: &[u8], ttl: u8, padding_size: usize, is_cover: bool) -> Result<Self, TaiorError> {
+        Self::from_frame(&frame_payload(payload, is_cover, padding_size)?, ttl)
+    }
+
+    /// Encrypts an already built (and padded) inner frame, see `frame_payload`.
+    pub fn from_frame(padded: &[u8], ttl: u8) -> Result<Self, TaiorError> {
         let (key, nonce, ikm) = derive_packet_key();
         let cipher = ChaCha20Poly1305::new(&key);
         
         let encrypted_payload = cipher
-            .encrypt(&nonce, padded.as_slice())
-            .map_err(|e| format!("encrypt error: {:?}", e))?;
+            .encrypt(&nonce, padded)
+            .map_err(|e| TaiorError::Crypto(format!("encrypt error: {:?}", e)))?;
 
         Ok(Self {
             encrypted_payload,
             ikm: ikm.to_vec(),
+            ephemeral_public: None,
             ttl,
-            is_cover,
+            format: PacketFormat::Direct,
         })
     }
 
-    pub fn decrypt_with_ikm(&self) -> Result<Vec<u8>, String> {
-        let (key, nonce) = derive_key_from_ikm(&self.ikm)?;
+    /// Encrypts `payload` so that only the holder of the secret behind `recipient` can read it.
+    /// The key comes from an ephemeral X25519 exchange; only the ephemeral public key travels.
+    pub fn new_for_recipient(
+        payload: &[u8],
+        recipient: &PublicKey,
+        ttl: u8,
+        padding_size: usize,
+        is_cover: bool,
+    ) -> Result<Self, TaiorError> {
+        Self::from_frame_for_recipient(&frame_payload(payload, is_cover, padding_size)?, recipient, ttl)
+    }
+
+    /// Encrypts an already built inner frame so that only `recipient` can read it.
+    pub fn from_frame_for_recipient(padded: &[u8], recipient: &PublicKey, ttl: u8) -> Result<Self, TaiorError> {
+        let ephemeral = EphemeralSecret::random_from_rng(OsRng);
+        let ephemeral_public = PublicKey::from(&ephemeral);
+        let shared = ephemeral.diffie_hellman(recipient);
+        if !shared.was_contributory() {
+            return Err(TaiorError::Crypto("recipient public key is a low-order point".to_string()));
+        }
+
+        let (key, nonce) = derive_key_from_shared_secret(shared.as_bytes(), &ephemeral_public, recipient)?;
         let cipher = ChaCha20Poly1305::new(&key);
-        cipher
-            .decrypt(&nonce, self.encrypted_payload.as_slice())
-            .map_err(|e| format!("decrypt error: {:?}", e))
+
+        let encrypted_payload = cipher
+            .encrypt(&nonce, padded)
+            .map_err(|e| TaiorError::Crypto(format!("encrypt error: {:?}", e)))?;
+
+        Ok(Self {
+            encrypted_payload,
+            ikm: Vec::new(),
+            ephemeral_public: Some(ephemeral_public.to_bytes()),
+            ttl,
+            format: PacketFormat::Direct,
+        })
     }
 
-    pub fn decrypt(&self, key: &Key, nonce: &Nonce) -> Result<Vec<u8>, String> {
+    /// Builds a link-level control packet. Control messages never travel more than one hop.
+    pub fn control(circuit_id: [u8; CIRCUIT_ID_LEN], message: Vec<u8>) -> Self {
+        Self {
+            encrypted_payload: message,
+            ikm: Vec::new(),
+            ephemeral_public: None,
+            ttl: 1,
+            format: PacketFormat::Control { circuit_id },
+        }
+    }
+
+    pub fn decrypt_with_ikm(&self) -> Result<PacketContents, TaiorError> {
+        let (key, nonce) = derive_key_from_ikm(&self.ikm)?;
+        self.decrypt(&key, &nonce)
+    }
+
+    /// Decrypts a packet built with `new_for_recipient` using the recipient's secret.
+    pub fn decrypt_for_recipient(&self, secret: &StaticSecret) -> Result<PacketContents, TaiorError> {
+        let ephemeral_public = self
+            .ephemeral_public
+            .map(PublicKey::from)
+            .ok_or_else(|| TaiorError::PacketFormat("packet is not recipient-keyed".to_string()))?;
+
+        let shared = secret.diffie_hellman(&ephemeral_public);
+        if !shared.was_contributory() {
+            return Err(TaiorError::Crypto("ephemeral public key is a low-order point".to_string()));
+        }
+
+        let recipient = PublicKey::from(secret);
+        let (key, nonce) = derive_key_from_shared_secret(shared.as_bytes(), &ephemeral_public, &recipient)?;
+        self.decrypt(&key, &nonce)
+    }
+
+    pub fn decrypt(&self, key: &Key, nonce: &Nonce) -> Result<PacketContents, TaiorError> {
         let cipher = ChaCha20Poly1305::new(key);
-        cipher
+        let plaintext = cipher
             .decrypt(nonce, self.encrypted_payload.as_slice())
-            .map_err(|e| format!("decrypt error: {:?}", e))
+            .map_err(|_| TaiorError::DecryptionFailed)?;
+        unframe_payload(&plaintext)
     }
 
     pub fn size(&self) -> usize {
-        self.encrypted_payload.len() + self.ikm.len()
+        self.encrypted_payload.len() + self.ikm.len() + self.ephemeral_public.map_or(0, |pk| pk.len())
+    }
+
+    /// Encodes the packet into the fixed-layout binary wire format:
+    ///
+    /// ```text
+    /// [version: 1][flags: 1][ttl: 1][key material: 32][payload len: 4, BE][encrypted payload]
+    /// ```
+    pub fn encode(&self) -> Result<Vec<u8>, TaiorError> {
+        let payload_len = self.encrypted_payload.len();
+        if !(MIN_PAYLOAD_LEN..=MAX_PAYLOAD_LEN).contains(&payload_len) {
+            return Err(TaiorError::PacketFormat(format!("payload length {} out of range", payload_len)));
+        }
+
+        let mut key_material = [0u8; KEY_MATERIAL_LEN];
+        let flags = match self.format {
+            PacketFormat::Direct => {
+                if let Some(pk) = &self.ephemeral_public {
+                    if !self.ikm.is_empty() {
+                        return Err(TaiorError::PacketFormat("recipient-keyed packet must not carry an IKM".to_string()));
+                    }
+                    key_material.copy_from_slice(pk);
+                    FLAG_RECIPIENT_KEYED
+                } else {
+                    if self.ikm.len() != KEY_MATERIAL_LEN {
+                        return Err(TaiorError::PacketFormat(format!(
+                            "key material must be {} bytes, got {}",
+                            KEY_MATERIAL_LEN,
+                            self.ikm.len()
+                        )));
+                    }
+                    key_material.copy_from_slice(&self.ikm);
+                    0
+                }
+            }
+            PacketFormat::Sphinx | PacketFormat::Onion { .. } | PacketFormat::Control { .. } => {
+                if !self.ikm.is_empty() || self.ephemeral_public.is_some() {
+                    return Err(TaiorError::PacketFormat("routed packet must not carry end-to-end key material".to_string()));
+                }
+                match self.format {
+                    PacketFormat::Onion { circuit_id } => {
+                        key_material[..CIRCUIT_ID_LEN].copy_from_slice(&circuit_id);
+                        FLAG_ONION
+                    }
+                    PacketFormat::Control { circuit_id } => {
+                        key_material[..CIRCUIT_ID_LEN].copy_from_slice(&circuit_id);
+                        FLAG_CONTROL
+                    }
+                    _ => FLAG_SPHINX,
+                }
+            }
+        };
+
+        let mut out = Vec::with_capacity(HEADER_LEN + payload_len);
+        out.push(WIRE_VERSION);
+        out.push(flags);
+        out.push(self.ttl);
+        out.extend_from_slice(&key_material);
+        out.extend_from_slice(&(payload_len as u32).to_be_bytes());
+        out.extend_from_slice(&self.encrypted_payload);
+        Ok(out)
+    }
+
+    /// Decodes a packet produced by `encode`. The buffer must contain exactly one packet.
+    pub fn decode(bytes: &[u8]) -> Result<Self, TaiorError> {
+        if bytes.len() < HEADER_LEN {
+            return Err(TaiorError::PacketFormat(format!("packet too short: {} bytes", bytes.len())));
+        }
+        if bytes[0] != WIRE_VERSION {
+            return Err(TaiorError::PacketFormat(format!("unsupported wire version {}", bytes[0])));
+        }
+
+        let flags = bytes[1];
+        if flags & !KNOWN_FLAGS != 0 {
+            return Err(TaiorError::PacketFormat(format!("unknown flags 0x{:02x}", flags)));
+        }
+
+        let ttl = bytes[2];
+        let key_material = &bytes[3..3 + KEY_MATERIAL_LEN];
+        let mut len_bytes = [0u8; 4];
+        len_bytes.copy_from_slice(&bytes[3 + KEY_MATERIAL_LEN..HEADER_LEN]);
+        let payload_len = u32::from_be_bytes(len_bytes) as usize;
+
+        if !(MIN_PAYLOAD_LEN..=MAX_PAYLOAD_LEN).contains(&payload_len) {
+            return Err(TaiorError::PacketFormat(format!("payload length {} out of range", payload_len)));
+        }
+        if bytes.len() - HEADER_LEN != payload_len {
+            return Err(TaiorError::PacketFormat(format!(
+                "payload length mismatch: header says {}, got {}",
+                payload_len,
+                bytes.len() - HEADER_LEN
+            )));
+        }
+
+        let (format, ikm, ephemeral_public) = match flags {
+            0 => (PacketFormat::Direct, key_material.to_vec(), None),
+            FLAG_RECIPIENT_KEYED => {
+                let mut pk = [0u8; KEY_MATERIAL_LEN];
+                pk.copy_from_slice(key_material);
+                (PacketFormat::Direct, Vec::new(), Some(pk))
+            }
+            FLAG_SPHINX => (PacketFormat::Sphinx, Vec::new(), None),
+            FLAG_ONION => {
+                let mut circuit_id = [0u8; CIRCUIT_ID_LEN];
+                circuit_id.copy_from_slice(&key_material[..CIRCUIT_ID_LEN]);
+                (PacketFormat::Onion { circuit_id }, Vec::new(), None)
+            }
+            FLAG_CONTROL => {
+                let mut circuit_id = [0u8; CIRCUIT_ID_LEN];
+                circuit_id.copy_from_slice(&key_material[..CIRCUIT_ID_LEN]);
+                (PacketFormat::Control { circuit_id }, Vec::new(), None)
+            }
+            _ => return Err(TaiorError::PacketFormat(format!("conflicting flags 0x{:02x}", flags))),
+        };
+
+        Ok(Self {
+            encrypted_payload: bytes[HEADER_LEN..].to_vec(),
+            ikm,
+            ephemeral_public,
+            ttl,
+            format,
+        })
     }
 }
 
 pub fn pad_payload(payload: &[u8], target_len: usize) -> Vec<u8> {
+    pad_payload_with(payload, target_len, &mut OsRng)
+}
+
+/// Like `pad_payload`, drawing the padding from `rng`.
+pub fn pad_payload_with<R: RngCore + ?Sized>(payload: &[u8], target_len: usize, rng: &mut R) -> Vec<u8> {
     let mut out = Vec::with_capacity(target_len);
     out.extend_from_slice(payload);
     if payload.len() < target_len {
         let pad_len = target_len - payload.len();
         let mut padding = vec![0u8; pad_len];
-        OsRng.fill_bytes(&mut padding);
+        rng.fill_bytes(&mut padding);
         out.extend_from_slice(&padding);
     }
     out.truncate(target_len);
     out
 }
 
+/// Wraps `data` in the inner frame and pads it to a multiple of `padding_size`
+/// (or leaves it unpadded when `padding_size` is 0). Real and cover frames of the
+/// same padded size are indistinguishable once encrypted.
+pub fn frame_payload(data: &[u8], is_cover: bool, padding_size: usize) -> Result<Vec<u8>, TaiorError> {
+    let kind = if is_cover { FRAME_KIND_COVER } else { FRAME_KIND_DATA };
+    build_frame(kind, data, None, padding_size)
+}
+
+/// Like `frame_payload` for real data, with a reply block the recipient can answer through.
+pub fn frame_payload_with_reply(data: &[u8], reply_block: &[u8], padding_size: usize) -> Result<Vec<u8>, TaiorError> {
+    build_frame(FRAME_KIND_DATA_WITH_REPLY, data, Some(reply_block), padding_size)
+}
+
+fn build_frame(kind: u8, data: &[u8], reply_block: Option<&[u8]>, padding_size: usize) -> Result<Vec<u8>, TaiorError> {
+    let data_len = u32::try_from(data.len())
+        .map_err(|_| TaiorError::PacketFormat("payload too large to frame".to_string()))?;
+
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + data.len());
+    frame.push(kind);
+    frame.extend_from_slice(&data_len.to_be_bytes());
+    frame.extend_from_slice(data);
+    if let Some(reply_block) = reply_block {
+        let reply_len = u32::try_from(reply_block.len())
+            .map_err(|_| TaiorError::PacketFormat("reply block too large to frame".to_string()))?;
+        frame.extend_from_slice(&reply_len.to_be_bytes());
+        frame.extend_from_slice(reply_block);
+    }
+
+    Ok(pad_payload(&frame, padded_len(frame.len(), padding_size)))
+}
+
+/// Length a frame of `len` bytes is padded to: the next multiple of `padding_size`,
+/// or `len` itself when `padding_size` is 0.
+pub fn padded_len(len: usize, padding_size: usize) -> usize {
+    if padding_size > 0 {
+        len.div_ceil(padding_size) * padding_size
+    } else {
+        len
+    }
+}
+
+/// Parses a decrypted frame produced by `frame_payload`, dropping the padding.
+pub fn unframe_payload(frame: &[u8]) -> Result<PacketContents, TaiorError> {
+    if frame.len() < FRAME_HEADER_LEN {
+        return Err(TaiorError::PacketFormat("frame too short".to_string()));
+    }
+
+    let is_cover = match frame[0] {
+        FRAME_KIND_DATA => false,
+        FRAME_KIND_DATA_WITH_REPLY => false,
+        FRAME_KIND_COVER => true,
+        other => return Err(TaiorError::PacketFormat(format!("unknown frame kind {}", other))),
+    };
+
+    let (data, rest) = split_length_prefixed(&frame[1..])?;
+    let reply_block = if frame[0] == FRAME_KIND_DATA_WITH_REPLY {
+        Some(split_length_prefixed(rest)?.0.to_vec())
+    } else {
+        None
+    };
+
+    Ok(PacketContents {
+        data: data.to_vec(),
+        is_cover,
+        reply_block,
+    })
+}
+
+/// Splits a u32 BE length-prefixed field off the front of `bytes`.
+fn split_length_prefixed(bytes: &[u8]) -> Result<(&[u8], &[u8]), TaiorError> {
+    if bytes.len() < 4 {
+        return Err(TaiorError::PacketFormat("frame too short".to_string()));
+    }
+    let (len_bytes, rest) = bytes.split_at(4);
+    let len = u32::from_be_bytes(len_bytes.try_into().expect("four bytes")) as usize;
+    if rest.len() < len {
+        return Err(TaiorError::PacketFormat("frame length exceeds payload".to_string()));
+    }
+    Ok(rest.split_at(len))
+}
+
 pub fn derive_packet_key() -> (Key, Nonce, [u8; 32]) {
     let mut ikm = [0u8; 32];
     OsRng.fill_bytes(&mut ikm);
@@ -73,16 +419,134 @@ pub fn derive_packet_key() -> (Key, Nonce, [u8; 32]) {
     (key, nonce, ikm)
 }
 
-pub fn derive_key_from_ikm(ikm: &[u8]) -> Result<(Key, Nonce), String> {
+pub fn derive_key_from_ikm(ikm: &[u8]) -> Result<(Key, Nonce), TaiorError> {
     if ikm.len() < 32 {
-        return Err("IKM too short: expected at least 32 bytes".to_string());
+        return Err(TaiorError::Crypto("IKM too short: expected at least 32 bytes".to_string()));
     }
-    let hk = Hkdf::<Sha256>::new(None, ikm);
+    expand_key(None, ikm, PACKET_KEY_LABEL)
+}
+
+/// Derives the packet key from an X25519 shared secret. Both public keys are bound
+/// into the HKDF salt so the key is tied to this exact sender/recipient exchange.
+pub fn derive_key_from_shared_secret(
+    shared_secret: &[u8; 32],
+    ephemeral_public: &PublicKey,
+    recipient: &PublicKey,
+) -> Result<(Key, Nonce), TaiorError> {
+    let mut salt = [0u8; 64];
+    salt[..32].copy_from_slice(ephemeral_public.as_bytes());
+    salt[32..].copy_from_slice(recipient.as_bytes());
+    expand_key(Some(&salt), shared_secret, SEALED_KEY_LABEL)
+}
+
+fn expand_key(salt: Option<&[u8]>, ikm: &[u8], label: &[u8]) -> Result<(Key, Nonce), TaiorError> {
+    let hk = Hkdf::<Sha256>::new(salt, ikm);
     let mut okm = [0u8; 44];
-    hk.expand(b"taior-packet-v1", &mut okm)
-        .map_err(|e| format!("hkdf expand error: {:?}", e))?;
+    hk.expand(label, &mut okm)
+        .map_err(|e| TaiorError::Crypto(format!("hkdf expand error: {:?}", e)))?;
     
     let key = *Key::from_slice(&okm[..32]);
     let nonce = *Nonce::from_slice(&okm[32..]);
     Ok((key, nonce))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::EphemeralIdentity;
+
+    #[test]
+    fn test_recipient_keyed_roundtrip() {
+        let recipient = EphemeralIdentity::new();
+        let packet = TaiorPacket::new_for_recipient(b"hola", &recipient.public_key, 3, 64, false).unwrap();
+
+        assert!(packet.ikm.is_empty());
+        let contents = packet.decrypt_for_recipient(&recipient.secret).unwrap();
+        assert_eq!(contents.data, b"hola");
+        assert!(!contents.is_cover);
+        assert_eq!(packet.encrypted_payload.len(), 64 + 16);
+    }
+
+    #[test]
+    fn test_recipient_keyed_rejects_other_identities() {
+        let recipient = EphemeralIdentity::new();
+        let eavesdropper = EphemeralIdentity::new();
+        let packet = TaiorPacket::new_for_recipient(b"hola", &recipient.public_key, 3, 64, false).unwrap();
+
+        assert!(matches!(
+            packet.decrypt_for_recipient(&eavesdropper.secret),
+            Err(TaiorError::DecryptionFailed)
+        ));
+        assert!(packet.decrypt_with_ikm().is_err());
+    }
+
+    #[test]
+    fn test_wire_roundtrip() {
+        let recipient = EphemeralIdentity::new();
+        let sealed = TaiorPacket::new_for_recipient(b"hola", &recipient.public_key, 4, 512, false).unwrap();
+        let keyed = TaiorPacket::new(b"hola", 4, 512, false).unwrap();
+
+        for packet in [sealed, keyed] {
+            let encoded = packet.encode().unwrap();
+            assert_eq!(encoded.len(), HEADER_LEN + 512 + 16);
+
+            let decoded = TaiorPacket::decode(&encoded).unwrap();
+            assert_eq!(decoded.encrypted_payload, packet.encrypted_payload);
+            assert_eq!(decoded.ikm, packet.ikm);
+            assert_eq!(decoded.ephemeral_public, packet.ephemeral_public);
+            assert_eq!(decoded.ttl, 4);
+        }
+    }
+
+    #[test]
+    fn test_cover_flag_only_visible_after_decryption() {
+        let real = TaiorPacket::new(b"mensaje real", 3, 512, false).unwrap();
+        let cover = TaiorPacket::new(b"dummy", 3, 512, true).unwrap();
+
+        let real_wire = real.encode().unwrap();
+        let cover_wire = cover.encode().unwrap();
+        assert_eq!(real_wire.len(), cover_wire.len());
+        assert_eq!(real_wire[..3], cover_wire[..3]);
+
+        assert!(!real.decrypt_with_ikm().unwrap().is_cover);
+        assert!(cover.decrypt_with_ikm().unwrap().is_cover);
+    }
+
+    #[test]
+    fn test_frame_pads_to_padding_multiple() {
+        assert_eq!(frame_payload(&[7u8; 10], false, 64).unwrap().len(), 64);
+        assert_eq!(frame_payload(&[7u8; 60], false, 64).unwrap().len(), 128);
+        assert_eq!(frame_payload(&[7u8; 10], false, 0).unwrap().len(), FRAME_HEADER_LEN + 10);
+
+        let contents = unframe_payload(&frame_payload(&[7u8; 60], false, 64).unwrap()).unwrap();
+        assert_eq!(contents.data, vec![7u8; 60]);
+        assert_eq!(contents.reply_block, None);
+
+        let framed = frame_payload_with_reply(&[7u8; 10], &[9u8; 20], 64).unwrap();
+        assert_eq!(framed.len(), 64);
+        let contents = unframe_payload(&framed).unwrap();
+        assert_eq!(contents.data, vec![7u8; 10]);
+        assert_eq!(contents.reply_block, Some(vec![9u8; 20]));
+        assert!(!contents.is_cover);
+    }
+
+    #[test]
+    fn test_decode_rejects_malformed_input() {
+        let encoded = TaiorPacket::new(b"hola", 2, 64, false).unwrap().encode().unwrap();
+
+        assert!(TaiorPacket::decode(&encoded[..HEADER_LEN - 1]).is_err());
+        assert!(TaiorPacket::decode(&encoded[..encoded.len() - 1]).is_err());
+
+        let mut trailing = encoded.clone();
+        trailing.push(0);
+        assert!(TaiorPacket::decode(&trailing).is_err());
+
+        let mut bad_version = encoded.clone();
+        bad_version[0] = WIRE_VERSION + 1;
+        assert!(TaiorPacket::decode(&bad_version).is_err());
+
+        let mut bad_flags = encoded;
+        bad_flags[1] = 0x80;
+        assert!(TaiorPacket::decode(&bad_flags).is_err());
+    }
+}
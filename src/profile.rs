@@ -0,0 +1,252 @@
+//! Named privacy profiles: a routing configuration and, with the mixing stack, a
+//! cover traffic configuration, loadable from TOML or JSON files so that deployments
+//! can tune them without rebuilding. Every profile is validated when loaded.
+//!
+//! ```toml
+//! name = "high-privacy"
+//!
+//! [routing]
+//! mode = "mix"
+//! hops = 4
+//! cover_traffic = true
+//! jitter_ms = 200
+//! padding_size = 512
+//!
+//! [cover]
+//! enabled = true
+//! packets_per_second = 2.0
+//! min_size = 512
+//! max_size = 2048
+//! jitter_ms = 500
+//! ```
+
+use crate::circuit::{MAX_CIRCUIT_HOPS, MIN_CIRCUIT_HOPS};
+#[cfg(feature = "mix-mode")]
+use crate::cover_traffic::CoverTrafficConfig;
+use crate::modes::{ModeConfig, RoutingMode};
+use crate::packet::MAX_PAYLOAD_LEN;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use thiserror::Error;
+
+/// Smallest block payloads are padded to. This is not an MTU floor: fitting packets
+/// to the link is up to the transport. It bounds how finely padding may leak message
+/// lengths, as a block this size holds little more than the 5-byte frame header and
+/// a short text, so smaller blocks would tell short messages apart by their length.
+pub const MIN_PADDING_SIZE: usize = 64;
+/// Ceiling of the jitter of routing and cover traffic.
+pub const MAX_JITTER_MS: u64 = 1000;
+
+pub const LOW_LATENCY: &str = "low-latency";
+pub const HIGH_PRIVACY: &str = "high-privacy";
+pub const RESEARCH: &str = "research";
+
+#[derive(Error, Debug)]
+pub enum ProfileError {
+    #[error("Perfil desconocido: {0}")]
+    UnknownProfile(String),
+    #[error("Perfil mal formado: {0}")]
+    Parse(String),
+    #[error("Error leyendo el perfil: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Modo {0:?} no compilado")]
+    ModeNotCompiled(RoutingMode),
+    #[error("Padding de {0} bytes fuera de rango")]
+    PaddingOutOfRange(usize),
+    #[error("{0} saltos fuera de rango")]
+    HopsOutOfRange(u8),
+    #[error("Jitter de {0} ms por encima del máximo")]
+    JitterTooHigh(u64),
+    #[error("Cover traffic inválido: {0}")]
+    InvalidCover(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PrivacyProfile {
+    pub name: String,
+    pub routing: ModeConfig,
+    #[cfg(feature = "mix-mode")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cover: Option<CoverTrafficConfig>,
+}
+
+impl PrivacyProfile {
+    /// Looks up a built-in profile by name.
+    pub fn builtin(name: &str) -> Result<Self, ProfileError> {
+        let routing = match name {
+            LOW_LATENCY => ModeConfig::fast(),
+            HIGH_PRIVACY => ModeConfig::mix(),
+            // Longest circuits and delays, for measurements rather than latency
+            RESEARCH => ModeConfig {
+                hops: MAX_CIRCUIT_HOPS as u8,
+                jitter_ms: Some(500),
+                padding_size: 1024,
+                ..ModeConfig::mix()
+            },
+            _ => return Err(ProfileError::UnknownProfile(name.to_string())),
+        };
+
+        Ok(Self {
+            name: name.to_string(),
+            #[cfg(feature = "mix-mode")]
+            cover: routing.cover_traffic.then(CoverTrafficConfig::default),
+            routing,
+        })
+    }
+
+    pub fn builtin_names() -> [&'static str; 3] {
+        [LOW_LATENCY, HIGH_PRIVACY, RESEARCH]
+    }
+
+    pub fn from_toml(text: &str) -> Result<Self, ProfileError> {
+        let profile: Self = toml::from_str(text).map_err(|e| ProfileError::Parse(e.to_string()))?;
+        profile.validate()?;
+        Ok(profile)
+    }
+
+    pub fn from_json(text: &str) -> Result<Self, ProfileError> {
+        let profile: Self = serde_json::from_str(text).map_err(|e| ProfileError::Parse(e.to_string()))?;
+        profile.validate()?;
+        Ok(profile)
+    }
+
+    /// Loads a profile file, as JSON if its extension is `.json` and as TOML otherwise.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ProfileError> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)?;
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") => Self::from_json(&text),
+            _ => Self::from_toml(&text),
+        }
+    }
+
+    pub fn to_toml(&self) -> Result<String, ProfileError> {
+        toml::to_string(self).map_err(|e| ProfileError::Parse(e.to_string()))
+    }
+
+    /// Checks that the profile can be sent with in this build and stays within the
+    /// bounds routing works with.
+    pub fn validate(&self) -> Result<(), ProfileError> {
+        let routing = &self.routing;
+        if !routing.mode.is_enabled() {
+            return Err(ProfileError::ModeNotCompiled(routing.mode));
+        }
+        if !(MIN_PADDING_SIZE..=MAX_PAYLOAD_LEN).contains(&routing.padding_size) {
+            return Err(ProfileError::PaddingOutOfRange(routing.padding_size));
+        }
+        if !(MIN_CIRCUIT_HOPS..=MAX_CIRCUIT_HOPS).contains(&(routing.hops as usize)) {
+            return Err(ProfileError::HopsOutOfRange(routing.hops));
+        }
+        if let Some(jitter) = routing.jitter_ms.filter(|&jitter| jitter > MAX_JITTER_MS) {
+            return Err(ProfileError::JitterTooHigh(jitter));
+        }
+
+        #[cfg(feature = "mix-mode")]
+        if let Some(cover) = &self.cover {
+            if !cover.packets_per_second.is_finite() || cover.packets_per_second < 0.0 {
+                return Err(ProfileError::InvalidCover("packets_per_second must be a rate".to_string()));
+            }
+            if cover.min_size < MIN_PADDING_SIZE || cover.min_size > cover.max_size || cover.max_size > MAX_PAYLOAD_LEN {
+                return Err(ProfileError::InvalidCover(format!(
+                    "sizes {}..{} out of range",
+                    cover.min_size, cover.max_size
+                )));
+            }
+            // The cover generator draws its jitter modulo twice this value
+            if cover.jitter_ms == 0 {
+                return Err(ProfileError::InvalidCover("jitter_ms must not be zero".to_string()));
+            }
+            if cover.jitter_ms > MAX_JITTER_MS {
+                return Err(ProfileError::JitterTooHigh(cover.jitter_ms));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::SendOptions;
+    use crate::error::TaiorError;
+
+    #[test]
+    fn test_builtin_profiles_validate_when_compiled_in() {
+        for name in PrivacyProfile::builtin_names() {
+            let profile = PrivacyProfile::builtin(name).unwrap();
+            assert_eq!(profile.validate().is_ok(), profile.routing.mode.is_enabled());
+            if profile.routing.mode.is_enabled() {
+                assert_eq!(PrivacyProfile::from_toml(&profile.to_toml().unwrap()).unwrap(), profile);
+            }
+        }
+        assert!(matches!(PrivacyProfile::builtin("paranoid"), Err(ProfileError::UnknownProfile(_))));
+    }
+
+    #[cfg(feature = "mix-mode")]
+    #[test]
+    fn test_profiles_load_from_toml_and_json() {
+        let toml = r#"
+            name = "custom"
+
+            [routing]
+            mode = "mix"
+            hops = 3
+            cover_traffic = false
+            padding_size = 768
+        "#;
+        let profile = PrivacyProfile::from_toml(toml).unwrap();
+        assert_eq!(profile.routing.hops, 3);
+        assert_eq!(profile.routing.jitter_ms, None);
+        assert!(profile.cover.is_none());
+
+        let json = r#"{
+            "name": "custom",
+            "routing": { "mode": "mix", "hops": 3, "cover_traffic": true, "jitter_ms": 300, "padding_size": 768 },
+            "cover": { "enabled": true, "packets_per_second": 1.0, "min_size": 512, "max_size": 1024, "jitter_ms": 250 }
+        }"#;
+        let profile = PrivacyProfile::from_json(json).unwrap();
+        assert_eq!(profile.routing.jitter_ms, Some(300));
+        assert_eq!(profile.cover.unwrap().max_size, 1024);
+
+        let path = std::env::temp_dir().join(format!("taior-profile-{}.json", std::process::id()));
+        std::fs::write(&path, json).unwrap();
+        assert_eq!(PrivacyProfile::load(&path).unwrap().routing.padding_size, 768);
+        std::fs::remove_file(&path).unwrap();
+
+        let options = SendOptions::profile(HIGH_PRIVACY).unwrap();
+        assert_eq!(options.mode, RoutingMode::Mix);
+        assert_eq!(options.config, Some(ModeConfig::mix()));
+    }
+
+    #[test]
+    fn test_out_of_range_profiles_are_rejected() {
+        let name = if cfg!(feature = "fast-mode") { LOW_LATENCY } else { HIGH_PRIVACY };
+        let base = PrivacyProfile::builtin(name).unwrap();
+        let with = |routing: ModeConfig| PrivacyProfile { routing, ..base.clone() }.validate();
+
+        assert!(matches!(
+            with(ModeConfig { padding_size: 16, ..base.routing.clone() }),
+            Err(ProfileError::PaddingOutOfRange(16))
+        ));
+        assert!(matches!(
+            with(base.routing.clone().with_hops(0)),
+            Err(ProfileError::HopsOutOfRange(0))
+        ));
+        assert!(matches!(
+            with(base.routing.clone().with_hops(MAX_CIRCUIT_HOPS as u8 + 1)),
+            Err(ProfileError::HopsOutOfRange(_))
+        ));
+        assert!(matches!(
+            with(base.routing.clone().with_jitter(Some(MAX_JITTER_MS + 1))),
+            Err(ProfileError::JitterTooHigh(_))
+        ));
+
+        let unknown_field = "name = \"x\"\n[routing]\nmode = \"fast\"\nhops = 1\ncover_traffic = false\npadding_size = 64\nmtu = 1\n";
+        assert!(matches!(PrivacyProfile::from_toml(unknown_field), Err(ProfileError::Parse(_))));
+        assert!(matches!(
+            SendOptions::profile("paranoid"),
+            Err(TaiorError::Profile(ProfileError::UnknownProfile(_)))
+        ));
+    }
+}
@@ -1,30 +1,105 @@
-use aorp::{DecisionConfig, DecisionEngine, EntropySource, MetricView, NeighborSet, PolicyConstraints};
+use aorp::{DecisionConfig, DecisionEngine, NeighborSet, PolicyConstraints};
+use crate::entropy::Entropy;
+use crate::metrics::LinkMetrics;
 use crate::modes::ModeConfig;
 
+/// How the router treats neighbours found on the local network (see
+/// `NodeDiscovery::add_local_descriptor`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LocalPolicy {
+    /// Local neighbours are weighed like any other.
+    #[default]
+    Neutral,
+    /// Only local neighbours are chosen from while any is live, keeping the first
+    /// hop on the LAN.
+    Prefer,
+    /// Local neighbours are never chosen: they see our LAN address and may well
+    /// share our observers.
+    Avoid,
+}
+
 pub struct Router {
     engine: DecisionEngine,
+    metrics: LinkMetrics,
+    entropy: Entropy,
+    local_policy: LocalPolicy,
 }
 
 impl Router {
     pub fn new() -> Self {
+        Self::from_entropy(Entropy::secure())
+    }
+
+    /// Router whose decisions are replayed exactly from `seed`; see `Entropy`.
+    #[cfg(feature = "insecure-research")]
+    pub fn with_entropy(seed: u64) -> Self {
+        Self::from_entropy(Entropy::from_seed(seed))
+    }
+
+    pub fn from_entropy(entropy: Entropy) -> Self {
         Self {
             engine: DecisionEngine::new(DecisionConfig::new(Some(10))),
+            metrics: LinkMetrics::new(),
+            entropy,
+            local_policy: LocalPolicy::default(),
+        }
+    }
+
+    /// Draws further decisions from `entropy`, keeping the link measurements.
+    pub fn set_entropy(&mut self, entropy: Entropy) {
+        self.entropy = entropy;
+    }
+
+    /// Link measurements the latency and bandwidth weights of each decision use.
+    pub fn metrics(&self) -> &LinkMetrics {
+        &self.metrics
+    }
+
+    pub fn metrics_mut(&mut self) -> &mut LinkMetrics {
+        &mut self.metrics
+    }
+
+    pub fn local_policy(&self) -> LocalPolicy {
+        self.local_policy
+    }
+
+    pub fn set_local_policy(&mut self, policy: LocalPolicy) {
+        self.local_policy = policy;
+    }
+
+    /// The neighbours the local policy lets decisions choose from, given which of
+    /// them are local.
+    pub fn eligible_neighbors(&self, neighbors: Vec<String>, is_local: impl Fn(&str) -> bool) -> Vec<String> {
+        match self.local_policy {
+            LocalPolicy::Neutral => neighbors,
+            LocalPolicy::Prefer => {
+                let (local, remote): (Vec<String>, Vec<String>) = neighbors.into_iter().partition(|node| is_local(node));
+                if local.is_empty() { remote } else { local }
+            }
+            LocalPolicy::Avoid => neighbors.into_iter().filter(|node| !is_local(node)).collect(),
         }
     }
 
     pub fn decide_next_hop(
         &mut self,
-        neighbors: Vec<String>,
+        mut neighbors: Vec<String>,
         config: &ModeConfig,
     ) -> Option<String> {
         if neighbors.is_empty() {
             return None;
         }
+        // Neighbours come out of hash sets in a different order on every run, which
+        // seeded decisions must not depend on
+        neighbors.sort_unstable();
+        // Neighbours measured as lossy are only chosen when no other is left
+        if neighbors.iter().any(|node| !self.metrics.is_lossy(node)) {
+            neighbors.retain(|node| !self.metrics.is_lossy(node));
+        }
 
         let neighbor_set = NeighborSet::from_peers(neighbors.iter().map(|s| s.as_str()));
-        
-        let metrics = MetricView::builder().build();
-        
+
+        let metrics = self.metrics.view(&neighbors);
+
         let diversity = match config.hops {
             1 => aorp::interfaces::types::DiversityLevel::Low,
             2..=3 => aorp::interfaces::types::DiversityLevel::Medium,
@@ -45,7 +120,7 @@ impl Router {
         let hop = self.engine.decide_next_hop(
             neighbor_set,
             metrics,
-            EntropySource::secure_random(),
+            self.entropy.source(),
             policies,
         );
 
@@ -58,3 +133,22 @@ impl Default for Router {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_local_policy_filters_neighbors() {
+        let neighbors = || vec!["lan:1".to_string(), "wan:1".to_string(), "wan:2".to_string()];
+        let is_local = |node: &str| node.starts_with("lan");
+        let mut router = Router::new();
+
+        assert_eq!(router.eligible_neighbors(neighbors(), is_local).len(), 3);
+        router.set_local_policy(LocalPolicy::Prefer);
+        assert_eq!(router.eligible_neighbors(neighbors(), is_local), vec!["lan:1".to_string()]);
+        assert_eq!(router.eligible_neighbors(vec!["wan:1".to_string()], is_local), vec!["wan:1".to_string()]);
+        router.set_local_policy(LocalPolicy::Avoid);
+        assert_eq!(router.eligible_neighbors(neighbors(), is_local), vec!["wan:1".to_string(), "wan:2".to_string()]);
+    }
+}
@@ -0,0 +1,231 @@
+//! Mixing delays for outgoing packets. Every packet, cover included, is held for a
+//! delay drawn on its own from its mode's `DelayDistribution`, so that the time it
+//! leaves does not reveal when the application sent it. Time comes from a `Clock`,
+//! which tests replace with a `ManualClock` to control exactly when packets are due.
+
+use crate::modes::DelayDistribution;
+use crate::packet::TaiorPacket;
+use rand_core::OsRng;
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+#[async_trait::async_trait]
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+    async fn sleep_until(&self, deadline: Instant);
+}
+
+/// Clock backed by the tokio timer.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioClock;
+
+#[async_trait::async_trait]
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    async fn sleep_until(&self, deadline: Instant) {
+        tokio::time::sleep_until(deadline).await
+    }
+}
+
+/// Clock that only moves when `advance` is called.
+pub struct ManualClock {
+    now: watch::Sender<Instant>,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        Self {
+            now: watch::Sender::new(Instant::now()),
+        }
+    }
+
+    /// Moves the clock forward, waking whoever sleeps until a time now reached.
+    pub fn advance(&self, by: Duration) {
+        self.now.send_modify(|now| *now += by);
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait::async_trait]
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.borrow()
+    }
+
+    async fn sleep_until(&self, deadline: Instant) {
+        let mut now = self.now.subscribe();
+        let _ = now.wait_for(|now| *now >= deadline).await;
+    }
+}
+
+struct Scheduled {
+    release: Instant,
+    // Keeps packets due at the same time in the order they were scheduled
+    sequence: u64,
+    dest: SocketAddr,
+    packet: TaiorPacket,
+}
+
+impl PartialEq for Scheduled {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Scheduled {}
+
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scheduled {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.release, self.sequence).cmp(&(other.release, other.sequence))
+    }
+}
+
+/// Holds outgoing packets until their mixing delay has passed.
+pub struct SendScheduler {
+    clock: Arc<dyn Clock>,
+    queue: BinaryHeap<Reverse<Scheduled>>,
+    sequence: u64,
+}
+
+impl SendScheduler {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            clock,
+            queue: BinaryHeap::new(),
+            sequence: 0,
+        }
+    }
+
+    /// Schedules `packet` for `dest` after a delay drawn from `delay` and returns
+    /// when it will be due.
+    pub fn schedule(&mut self, dest: SocketAddr, packet: TaiorPacket, delay: &DelayDistribution) -> Instant {
+        self.schedule_after(dest, packet, delay.sample(&mut OsRng))
+    }
+
+    pub fn schedule_after(&mut self, dest: SocketAddr, packet: TaiorPacket, delay: Duration) -> Instant {
+        let release = self.clock.now() + delay;
+        self.queue.push(Reverse(Scheduled {
+            release,
+            sequence: self.sequence,
+            dest,
+            packet,
+        }));
+        self.sequence += 1;
+        release
+    }
+
+    /// Takes the earliest packet if it is due.
+    pub fn pop_due(&mut self) -> Option<(SocketAddr, TaiorPacket)> {
+        let Reverse(next) = self.queue.peek()?;
+        if next.release > self.clock.now() {
+            return None;
+        }
+        self.queue.pop().map(|Reverse(due)| (due.dest, due.packet))
+    }
+
+    /// Waits until the earliest packet is due and takes it; never completes while
+    /// nothing is scheduled. Packets are only taken once due, so the future can be
+    /// dropped at any point, e.g. when another `select!` branch wins.
+    pub async fn next_due(&mut self) -> (SocketAddr, TaiorPacket) {
+        loop {
+            if let Some(due) = self.pop_due() {
+                return due;
+            }
+            match self.queue.peek() {
+                Some(Reverse(next)) => self.clock.sleep_until(next.release).await,
+                None => std::future::pending().await,
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modes::{ModeConfig, MAX_DELAY_FACTOR};
+    use rand::rngs::mock::StepRng;
+
+    fn packet(tag: u8) -> TaiorPacket {
+        TaiorPacket::new(&[tag], 1, 64, false).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_packets_leave_once_their_delay_has_passed() {
+        let clock = Arc::new(ManualClock::new());
+        let mut scheduler = SendScheduler::new(clock.clone());
+        let dest: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+
+        for (tag, delay) in [(0u8, 30), (1, 10), (2, 20), (3, 10)] {
+            scheduler.schedule_after(dest, packet(tag), Duration::from_millis(delay));
+        }
+        assert!(scheduler.pop_due().is_none());
+        assert!(tokio::time::timeout(Duration::ZERO, scheduler.next_due()).await.is_err());
+
+        let ((_, first), ()) = tokio::join!(scheduler.next_due(), async {
+            clock.advance(Duration::from_millis(10));
+        });
+        let (_, second) = scheduler.pop_due().unwrap();
+        assert_eq!(first.decrypt_with_ikm().unwrap().data, [1]);
+        assert_eq!(second.decrypt_with_ikm().unwrap().data, [3]);
+        assert!(scheduler.pop_due().is_none());
+
+        clock.advance(Duration::from_millis(20));
+        let order: Vec<u8> = std::iter::from_fn(|| scheduler.pop_due())
+            .map(|(_, packet)| packet.decrypt_with_ikm().unwrap().data[0])
+            .collect();
+        assert_eq!(order, [2, 0]);
+        assert!(scheduler.is_empty());
+    }
+
+    #[test]
+    fn test_delays_follow_mode_config() {
+        let mut low = StepRng::new(0, 0);
+        let mut high = StepRng::new(u64::MAX, 0);
+
+        assert_eq!(ModeConfig::fast().delay(), DelayDistribution::None);
+        assert_eq!(ModeConfig::fast().delay().sample(&mut high), Duration::ZERO);
+
+        let adaptive = ModeConfig::adaptive().delay();
+        assert_eq!(adaptive, DelayDistribution::Uniform(Duration::from_millis(50)));
+        assert_eq!(adaptive.sample(&mut low), Duration::ZERO);
+        assert!(adaptive.sample(&mut high) <= Duration::from_millis(50));
+
+        let mix = ModeConfig::mix().delay();
+        let mean = Duration::from_millis(200);
+        assert_eq!(mix, DelayDistribution::Exponential(mean));
+        assert_eq!(mix.sample(&mut low), Duration::ZERO);
+        assert_eq!(mix.sample(&mut high), mean * MAX_DELAY_FACTOR);
+
+        // 1 - 1/e of the range lands on the mean
+        let mut median = StepRng::new((u64::MAX as f64 * (1.0 - (-1.0f64).exp())) as u64, 0);
+        let sample = mix.sample(&mut median);
+        assert!(sample.abs_diff(mean) < Duration::from_millis(1));
+    }
+}
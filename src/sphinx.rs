@@ -0,0 +1,713 @@
+//! Sphinx-style packet format for Mix circuits.
+//!
+//! Every packet carries a constant-size header (blinded group element, per-hop
+//! routing information and a MAC) plus a body whose length never changes while
+//! it travels. A hop learns only its predecessor, its successor and whether it is
+//! the last hop; packet length no longer reveals its position on the route.
+//!
+//! Each hop removes a layer of LIONESS, a wide-block cipher, from the body. Changing
+//! any bit of the body on the way scrambles all of it, so a hop cannot tag a packet
+//! for a colluding hop further on to recognise; the last hop finds the body corrupt.
+
+use crate::identity::TaiorAddress;
+use crate::packet::{PacketFormat, TaiorPacket, MAX_PAYLOAD_LEN};
+use rand_core::{OsRng, RngCore};
+use thiserror::Error;
+use x25519_dalek::{x25519, PublicKey, StaticSecret, X25519_BASEPOINT_BYTES};
+
+/// Longest route a header can describe.
+pub const MAX_HOPS: usize = 5;
+/// Length of the group element (X25519 public key) at the start of the header.
+pub const ALPHA_LEN: usize = 32;
+/// Length of the truncated header MAC.
+pub const MAC_LEN: usize = 16;
+/// Per-hop routing block: command byte, next hop address, next hop MAC.
+pub const HOP_INFO_LEN: usize = 1 + 32 + MAC_LEN;
+/// Routing information section, sized for `MAX_HOPS` regardless of the real route length.
+pub const ROUTING_INFO_LEN: usize = MAX_HOPS * HOP_INFO_LEN;
+/// Total header length. Constant for every packet and at every hop.
+pub const HEADER_LEN: usize = ALPHA_LEN + ROUTING_INFO_LEN + MAC_LEN;
+/// Bytes of the body spent on the integrity tag and the payload length.
+pub const BODY_OVERHEAD: usize = BODY_TAG_LEN + 4;
+/// Longest body that still fits, with the header, in a `TaiorPacket` payload.
+pub const MAX_BODY_LEN: usize = MAX_PAYLOAD_LEN - HEADER_LEN;
+
+/// Zero bytes the body starts with; also the left half of the LIONESS block.
+const BODY_TAG_LEN: usize = 32;
+const CMD_FORWARD: u8 = 0x01;
+const CMD_DELIVER: u8 = 0x02;
+
+const CONTEXT_ROUTING: &str = "taior sphinx v1 routing-info stream";
+const CONTEXT_BODY: &str = "taior sphinx v1 body lioness key";
+const CONTEXT_MAC: &str = "taior sphinx v1 header mac";
+const CONTEXT_BLINDING: &str = "taior sphinx v1 blinding factor";
+const CONTEXT_REPLAY: &str = "taior sphinx v1 replay tag";
+
+/// Serialized SURB: first hop, header, body key, body length (u32 BE), expiry (u64 BE).
+pub const SURB_LEN: usize = 32 + HEADER_LEN + 32 + 4 + 8;
+
+#[derive(Error, Debug)]
+pub enum SphinxError {
+    #[error("Ruta vacía")]
+    EmptyRoute,
+    #[error("Ruta de {0} hops excede el máximo de {MAX_HOPS}")]
+    RouteTooLong(usize),
+    #[error("Payload de {0} bytes no cabe en el cuerpo")]
+    PayloadTooLarge(usize),
+    #[error("Clave de grupo inválida")]
+    InvalidKey,
+    #[error("MAC de cabecera inválido")]
+    InvalidMac,
+    #[error("Cuerpo corrupto")]
+    CorruptBody,
+    #[error("Paquete malformado: {0}")]
+    Malformed(String),
+    #[error("Bloque de respuesta expirado")]
+    SurbExpired,
+    #[error("Bloque de respuesta desconocido o ya usado")]
+    UnknownSurb,
+}
+
+/// One hop of a Sphinx route: where it lives and the X25519 onion key it decrypts with.
+#[derive(Debug, Clone)]
+pub struct SphinxHop {
+    pub address: TaiorAddress,
+    pub onion_key: PublicKey,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SphinxPacket {
+    pub alpha: [u8; ALPHA_LEN],
+    pub routing_info: [u8; ROUTING_INFO_LEN],
+    pub mac: [u8; MAC_LEN],
+    pub body: Vec<u8>,
+}
+
+/// Result of removing one layer from a Sphinx packet.
+#[derive(Debug)]
+pub enum PeeledPacket {
+    Forward {
+        next_hop: TaiorAddress,
+        packet: Box<SphinxPacket>,
+    },
+    Deliver {
+        payload: Vec<u8>,
+    },
+}
+
+/// A peeled packet together with the tag a relay stores to reject replays.
+#[derive(Debug)]
+pub struct PeelResult {
+    pub replay_tag: [u8; 32],
+    pub peeled: PeeledPacket,
+}
+
+impl SphinxPacket {
+    /// Builds a packet that travels `route` in order and delivers `payload` to the last hop.
+    /// `body_len` is the fixed body size; it must hold `payload` plus `BODY_OVERHEAD`.
+    pub fn build(route: &[SphinxHop], payload: &[u8], body_len: usize) -> Result<Self, SphinxError> {
+        let header = Header::build(route)?;
+        let mut body = encode_body(payload, body_len)?;
+        for secret in header.secrets.iter().rev() {
+            Lioness::new(secret).encrypt(&mut body);
+        }
+
+        Ok(Self {
+            alpha: header.alpha,
+            routing_info: header.routing_info,
+            mac: header.mac,
+            body,
+        })
+    }
+
+    /// Removes the layer addressed to the holder of `secret`.
+    pub fn peel(&self, secret: &StaticSecret) -> Result<PeelResult, SphinxError> {
+        if self.body.len() < BODY_OVERHEAD {
+            return Err(SphinxError::Malformed(format!("cuerpo de {} bytes", self.body.len())));
+        }
+        let shared = secret.diffie_hellman(&PublicKey::from(self.alpha));
+        if !shared.was_contributory() {
+            return Err(SphinxError::InvalidKey);
+        }
+        let shared = shared.to_bytes();
+
+        if !constant_time_eq(&header_mac(&shared, &self.routing_info), &self.mac) {
+            return Err(SphinxError::InvalidMac);
+        }
+
+        let mut extended = [0u8; ROUTING_INFO_LEN + HOP_INFO_LEN];
+        extended[..ROUTING_INFO_LEN].copy_from_slice(&self.routing_info);
+        xor_in_place(&mut extended, &routing_stream(&shared));
+
+        let mut body = self.body.clone();
+        Lioness::new(&shared).decrypt(&mut body);
+
+        let replay_tag = blake3::derive_key(CONTEXT_REPLAY, &shared);
+
+        let peeled = match extended[0] {
+            CMD_FORWARD => {
+                let mut next = [0u8; 32];
+                next.copy_from_slice(&extended[1..33]);
+                let mut mac = [0u8; MAC_LEN];
+                mac.copy_from_slice(&extended[33..HOP_INFO_LEN]);
+                let mut routing_info = [0u8; ROUTING_INFO_LEN];
+                routing_info.copy_from_slice(&extended[HOP_INFO_LEN..]);
+
+                PeeledPacket::Forward {
+                    next_hop: TaiorAddress::from_bytes(&next),
+                    packet: Box::new(SphinxPacket {
+                        alpha: x25519(blinding_factor(&self.alpha, &shared), self.alpha),
+                        routing_info,
+                        mac,
+                        body,
+                    }),
+                }
+            }
+            CMD_DELIVER => PeeledPacket::Deliver {
+                payload: decode_body(&body)?,
+            },
+            other => return Err(SphinxError::Malformed(format!("comando desconocido {}", other))),
+        };
+
+        Ok(PeelResult { replay_tag, peeled })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(HEADER_LEN + self.body.len());
+        out.extend_from_slice(&self.alpha);
+        out.extend_from_slice(&self.routing_info);
+        out.extend_from_slice(&self.mac);
+        out.extend_from_slice(&self.body);
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, SphinxError> {
+        if bytes.len() < HEADER_LEN + BODY_OVERHEAD {
+            return Err(SphinxError::Malformed(format!("{} bytes", bytes.len())));
+        }
+
+        let mut alpha = [0u8; ALPHA_LEN];
+        alpha.copy_from_slice(&bytes[..ALPHA_LEN]);
+        let mut routing_info = [0u8; ROUTING_INFO_LEN];
+        routing_info.copy_from_slice(&bytes[ALPHA_LEN..ALPHA_LEN + ROUTING_INFO_LEN]);
+        let mut mac = [0u8; MAC_LEN];
+        mac.copy_from_slice(&bytes[ALPHA_LEN + ROUTING_INFO_LEN..HEADER_LEN]);
+
+        Ok(Self {
+            alpha,
+            routing_info,
+            mac,
+            body: bytes[HEADER_LEN..].to_vec(),
+        })
+    }
+
+    /// Wraps the Sphinx packet for transport. The outer TTL is not decremented by
+    /// relays for Sphinx packets: a changing counter would reveal hop position.
+    pub fn into_taior_packet(self, ttl: u8) -> TaiorPacket {
+        TaiorPacket {
+            encrypted_payload: self.to_bytes(),
+            ikm: Vec::new(),
+            ephemeral_public: None,
+            ttl,
+            format: PacketFormat::Sphinx,
+        }
+    }
+
+    pub fn from_taior_packet(packet: &TaiorPacket) -> Result<Self, SphinxError> {
+        if packet.format != PacketFormat::Sphinx {
+            return Err(SphinxError::Malformed("no es un paquete Sphinx".into()));
+        }
+        Self::from_bytes(&packet.encrypted_payload)
+    }
+}
+
+/// Single-use reply block. Its creator pre-builds a header that routes back to
+/// itself, so whoever holds the SURB can answer without learning the path or the
+/// creator's address. Only the creator, holding the matching `SurbSecret`, can
+/// read the reply, and only once.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Surb {
+    /// Node the reply must be sent to first.
+    pub first_hop: TaiorAddress,
+    pub alpha: [u8; ALPHA_LEN],
+    pub routing_info: [u8; ROUTING_INFO_LEN],
+    pub mac: [u8; MAC_LEN],
+    /// Key the replier encrypts the body with before it enters the network.
+    pub body_key: [u8; 32],
+    pub body_len: usize,
+    /// Unix time (seconds) after which the creator no longer accepts the reply.
+    pub expires_at: u64,
+}
+
+/// What the creator of a SURB keeps to open the reply.
+#[derive(Debug, Clone)]
+pub struct SurbSecret {
+    /// Group element the reply will carry when it reaches the creator; identifies the SURB.
+    pub id: [u8; ALPHA_LEN],
+    own_secret: [u8; 32],
+    relay_secrets: Vec<[u8; 32]>,
+    body_key: [u8; 32],
+    pub expires_at: u64,
+}
+
+impl Surb {
+    /// Builds a reply block over `route`, whose last hop must be the creator itself.
+    pub fn build(route: &[SphinxHop], body_len: usize, expires_at: u64) -> Result<(Self, SurbSecret), SphinxError> {
+        if route.len() < 2 {
+            return Err(SphinxError::EmptyRoute);
+        }
+        if body_len < BODY_OVERHEAD {
+            return Err(SphinxError::PayloadTooLarge(0));
+        }
+        let mut header = Header::build(route)?;
+        let own_secret = header.secrets.pop().ok_or(SphinxError::EmptyRoute)?;
+        let mut body_key = [0u8; 32];
+        OsRng.fill_bytes(&mut body_key);
+
+        let surb = Self {
+            first_hop: route[0].address.clone(),
+            alpha: header.alpha,
+            routing_info: header.routing_info,
+            mac: header.mac,
+            body_key,
+            body_len,
+            expires_at,
+        };
+        let secret = SurbSecret {
+            id: header.final_alpha,
+            own_secret,
+            relay_secrets: header.secrets,
+            body_key,
+            expires_at,
+        };
+        Ok((surb, secret))
+    }
+
+    pub fn is_expired(&self) -> bool {
+        crate::circuit::current_timestamp() > self.expires_at
+    }
+
+    /// Turns the block into the reply packet carrying `payload`. Consumes the SURB:
+    /// its creator accepts a single reply per block.
+    pub fn into_packet(self, payload: &[u8]) -> Result<SphinxPacket, SphinxError> {
+        if self.is_expired() {
+            return Err(SphinxError::SurbExpired);
+        }
+        let mut body = encode_body(payload, self.body_len)?;
+        Lioness::new(&self.body_key).encrypt(&mut body);
+
+        Ok(SphinxPacket {
+            alpha: self.alpha,
+            routing_info: self.routing_info,
+            mac: self.mac,
+            body,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(SURB_LEN);
+        out.extend_from_slice(&self.first_hop.to_bytes().unwrap_or([0u8; 32]));
+        out.extend_from_slice(&self.alpha);
+        out.extend_from_slice(&self.routing_info);
+        out.extend_from_slice(&self.mac);
+        out.extend_from_slice(&self.body_key);
+        out.extend_from_slice(&(self.body_len as u32).to_be_bytes());
+        out.extend_from_slice(&self.expires_at.to_be_bytes());
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, SphinxError> {
+        if bytes.len() != SURB_LEN {
+            return Err(SphinxError::Malformed(format!("SURB de {} bytes", bytes.len())));
+        }
+        let (first_hop, rest) = bytes.split_at(32);
+        let (alpha, rest) = rest.split_at(ALPHA_LEN);
+        let (routing_info, rest) = rest.split_at(ROUTING_INFO_LEN);
+        let (mac, rest) = rest.split_at(MAC_LEN);
+        let (body_key, rest) = rest.split_at(32);
+        let (body_len, expires_at) = rest.split_at(4);
+
+        let mut first_hop_bytes = [0u8; 32];
+        first_hop_bytes.copy_from_slice(first_hop);
+        let mut surb = Self {
+            first_hop: TaiorAddress::from_bytes(&first_hop_bytes),
+            alpha: [0u8; ALPHA_LEN],
+            routing_info: [0u8; ROUTING_INFO_LEN],
+            mac: [0u8; MAC_LEN],
+            body_key: [0u8; 32],
+            body_len: 0,
+            expires_at: 0,
+        };
+        surb.alpha.copy_from_slice(alpha);
+        surb.routing_info.copy_from_slice(routing_info);
+        surb.mac.copy_from_slice(mac);
+        surb.body_key.copy_from_slice(body_key);
+        let mut len_bytes = [0u8; 4];
+        len_bytes.copy_from_slice(body_len);
+        surb.body_len = u32::from_be_bytes(len_bytes) as usize;
+        // The replier allocates the body, so its length is not taken on trust
+        if !(BODY_OVERHEAD..=MAX_BODY_LEN).contains(&surb.body_len) {
+            return Err(SphinxError::Malformed(format!("cuerpo de SURB de {} bytes", surb.body_len)));
+        }
+        let mut expiry_bytes = [0u8; 8];
+        expiry_bytes.copy_from_slice(expires_at);
+        surb.expires_at = u64::from_be_bytes(expiry_bytes);
+        Ok(surb)
+    }
+}
+
+impl SurbSecret {
+    pub fn is_expired(&self) -> bool {
+        crate::circuit::current_timestamp() > self.expires_at
+    }
+
+    /// Opens a reply that arrived through the SURB's route and returns its payload.
+    pub fn open(&self, packet: &SphinxPacket) -> Result<Vec<u8>, SphinxError> {
+        if self.is_expired() {
+            return Err(SphinxError::SurbExpired);
+        }
+        if packet.alpha != self.id {
+            return Err(SphinxError::Malformed("respuesta de otro SURB".into()));
+        }
+        if !constant_time_eq(&header_mac(&self.own_secret, &packet.routing_info), &packet.mac) {
+            return Err(SphinxError::InvalidMac);
+        }
+        let mut routing_info = packet.routing_info;
+        xor_in_place(&mut routing_info, &routing_stream(&self.own_secret));
+        if routing_info[0] != CMD_DELIVER {
+            return Err(SphinxError::Malformed("el SURB no termina en su creador".into()));
+        }
+
+        // Undo the relays' layers, last one first, then the replier's
+        if packet.body.len() < BODY_OVERHEAD {
+            return Err(SphinxError::CorruptBody);
+        }
+        let mut body = packet.body.clone();
+        for secret in self.relay_secrets.iter().rev() {
+            Lioness::new(secret).encrypt(&mut body);
+        }
+        Lioness::new(&self.body_key).decrypt(&mut body);
+        decode_body(&body)
+    }
+}
+
+/// Header material shared by forward packets and reply blocks.
+struct Header {
+    alpha: [u8; ALPHA_LEN],
+    routing_info: [u8; ROUTING_INFO_LEN],
+    mac: [u8; MAC_LEN],
+    /// Shared secret of every hop, in route order.
+    secrets: Vec<[u8; 32]>,
+    /// Group element the last hop will see.
+    final_alpha: [u8; ALPHA_LEN],
+}
+
+impl Header {
+    fn build(route: &[SphinxHop]) -> Result<Self, SphinxError> {
+        let hops = route.len();
+        if hops == 0 {
+            return Err(SphinxError::EmptyRoute);
+        }
+        if hops > MAX_HOPS {
+            return Err(SphinxError::RouteTooLong(hops));
+        }
+
+        let mut addresses = Vec::with_capacity(hops);
+        for hop in route {
+            let bytes = hop.address.to_bytes()
+                .ok_or_else(|| SphinxError::Malformed(format!("dirección inválida {}", hop.address.as_str())))?;
+            addresses.push(bytes);
+        }
+
+        // Per-hop shared secrets. Hop i sees alpha_i = g^(x·b_0···b_{i-1}) and
+        // derives s_i = alpha_i^y_i, which equals Y_i^(x·b_0···b_{i-1}).
+        let mut x = [0u8; 32];
+        OsRng.fill_bytes(&mut x);
+        let mut scalars = vec![x];
+        let mut secrets = Vec::with_capacity(hops);
+        let mut alpha0 = [0u8; 32];
+        let mut final_alpha = [0u8; 32];
+
+        for (i, hop) in route.iter().enumerate() {
+            let alpha = scalars.iter().fold(X25519_BASEPOINT_BYTES, |acc, k| x25519(*k, acc));
+            let secret = scalars.iter().fold(hop.onion_key.to_bytes(), |acc, k| x25519(*k, acc));
+            if secret == [0u8; 32] {
+                return Err(SphinxError::InvalidKey);
+            }
+            if i == 0 {
+                alpha0 = alpha;
+            }
+            final_alpha = alpha;
+            scalars.push(blinding_factor(&alpha, &secret));
+            secrets.push(secret);
+        }
+
+        // Filler: the bytes each hop shifts in at the tail, precomputed so the MACs cover them.
+        let mut filler: Vec<u8> = Vec::with_capacity((hops - 1) * HOP_INFO_LEN);
+        for secret in &secrets[..hops - 1] {
+            filler.extend_from_slice(&[0u8; HOP_INFO_LEN]);
+            let stream = routing_stream(secret);
+            let start = stream.len() - filler.len();
+            xor_in_place(&mut filler, &stream[start..]);
+        }
+
+        // Innermost routing info for the last hop
+        let last = &secrets[hops - 1];
+        let mut routing_info = [0u8; ROUTING_INFO_LEN];
+        routing_info[0] = CMD_DELIVER;
+        let open_len = ROUTING_INFO_LEN - filler.len();
+        OsRng.fill_bytes(&mut routing_info[HOP_INFO_LEN..open_len]);
+        let stream = routing_stream(last);
+        xor_in_place(&mut routing_info[..open_len], &stream[..open_len]);
+        routing_info[open_len..].copy_from_slice(&filler);
+        let mut mac = header_mac(last, &routing_info);
+
+        // Wrap outwards: each hop's block names the next hop and its MAC
+        for i in (0..hops - 1).rev() {
+            let mut wrapped = [0u8; ROUTING_INFO_LEN];
+            wrapped[0] = CMD_FORWARD;
+            wrapped[1..33].copy_from_slice(&addresses[i + 1]);
+            wrapped[33..HOP_INFO_LEN].copy_from_slice(&mac);
+            wrapped[HOP_INFO_LEN..].copy_from_slice(&routing_info[..ROUTING_INFO_LEN - HOP_INFO_LEN]);
+            let stream = routing_stream(&secrets[i]);
+            xor_in_place(&mut wrapped, &stream[..ROUTING_INFO_LEN]);
+
+            routing_info = wrapped;
+            mac = header_mac(&secrets[i], &routing_info);
+        }
+
+        Ok(Self {
+            alpha: alpha0,
+            routing_info,
+            mac,
+            secrets,
+            final_alpha,
+        })
+    }
+}
+
+/// Lays out `[zero tag][payload len: 4, BE][payload][random padding]` over `body_len` bytes.
+fn encode_body(payload: &[u8], body_len: usize) -> Result<Vec<u8>, SphinxError> {
+    if payload.len() + BODY_OVERHEAD > body_len {
+        return Err(SphinxError::PayloadTooLarge(payload.len()));
+    }
+    let mut body = vec![0u8; body_len];
+    body[BODY_TAG_LEN..BODY_OVERHEAD].copy_from_slice(&(payload.len() as u32).to_be_bytes());
+    body[BODY_OVERHEAD..BODY_OVERHEAD + payload.len()].copy_from_slice(payload);
+    OsRng.fill_bytes(&mut body[BODY_OVERHEAD + payload.len()..]);
+    Ok(body)
+}
+
+/// Inverse of `encode_body` once every stream has been removed.
+fn decode_body(body: &[u8]) -> Result<Vec<u8>, SphinxError> {
+    if body.len() < BODY_OVERHEAD || body[..BODY_TAG_LEN].iter().any(|b| *b != 0) {
+        return Err(SphinxError::CorruptBody);
+    }
+    let mut len_bytes = [0u8; 4];
+    len_bytes.copy_from_slice(&body[BODY_TAG_LEN..BODY_OVERHEAD]);
+    let len = u32::from_be_bytes(len_bytes) as usize;
+    body.get(BODY_OVERHEAD..BODY_OVERHEAD + len)
+        .map(|payload| payload.to_vec())
+        .ok_or(SphinxError::CorruptBody)
+}
+
+fn routing_stream(secret: &[u8; 32]) -> [u8; ROUTING_INFO_LEN + HOP_INFO_LEN] {
+    let mut out = [0u8; ROUTING_INFO_LEN + HOP_INFO_LEN];
+    keystream(CONTEXT_ROUTING, secret, &mut out);
+    out
+}
+
+/// LIONESS (Anderson and Biham) over BLAKE3: two keyed-XOF rounds on the right part
+/// of the block and two keyed-hash rounds on its first 32 bytes. Every round undoes
+/// itself, so decrypting runs them in reverse. Blocks must be longer than 32 bytes.
+struct Lioness {
+    keys: [[u8; 32]; 4],
+}
+
+impl Lioness {
+    fn new(secret: &[u8; 32]) -> Self {
+        let mut keys = [[0u8; 32]; 4];
+        for (round, key) in keys.iter_mut().enumerate() {
+            let mut material = [0u8; 33];
+            material[..32].copy_from_slice(secret);
+            material[32] = round as u8;
+            *key = blake3::derive_key(CONTEXT_BODY, &material);
+        }
+        Self { keys }
+    }
+
+    fn encrypt(&self, block: &mut [u8]) {
+        let (left, right) = block.split_at_mut(BODY_TAG_LEN);
+        self.stream_round(0, left, right);
+        self.hash_round(1, left, right);
+        self.stream_round(2, left, right);
+        self.hash_round(3, left, right);
+    }
+
+    fn decrypt(&self, block: &mut [u8]) {
+        let (left, right) = block.split_at_mut(BODY_TAG_LEN);
+        self.hash_round(3, left, right);
+        self.stream_round(2, left, right);
+        self.hash_round(1, left, right);
+        self.stream_round(0, left, right);
+    }
+
+    /// `right ^= S(left ^ key)`
+    fn stream_round(&self, round: usize, left: &[u8], right: &mut [u8]) {
+        let mut key = self.keys[round];
+        xor_in_place(&mut key, left);
+        let mut stream = vec![0u8; right.len()];
+        blake3::Hasher::new_keyed(&key).finalize_xof().fill(&mut stream);
+        xor_in_place(right, &stream);
+    }
+
+    /// `left ^= H(key, right)`
+    fn hash_round(&self, round: usize, left: &mut [u8], right: &[u8]) {
+        xor_in_place(left, blake3::keyed_hash(&self.keys[round], right).as_bytes());
+    }
+}
+
+fn keystream(context: &str, secret: &[u8; 32], out: &mut [u8]) {
+    let key = blake3::derive_key(context, secret);
+    blake3::Hasher::new_keyed(&key).finalize_xof().fill(out);
+}
+
+fn header_mac(secret: &[u8; 32], routing_info: &[u8]) -> [u8; MAC_LEN] {
+    let key = blake3::derive_key(CONTEXT_MAC, secret);
+    let hash = blake3::keyed_hash(&key, routing_info);
+    let mut mac = [0u8; MAC_LEN];
+    mac.copy_from_slice(&hash.as_bytes()[..MAC_LEN]);
+    mac
+}
+
+fn blinding_factor(alpha: &[u8; 32], secret: &[u8; 32]) -> [u8; 32] {
+    let mut material = [0u8; 64];
+    material[..32].copy_from_slice(alpha);
+    material[32..].copy_from_slice(secret);
+    blake3::derive_key(CONTEXT_BLINDING, &material)
+}
+
+fn xor_in_place(data: &mut [u8], stream: &[u8]) {
+    for (byte, key) in data.iter_mut().zip(stream) {
+        *byte ^= key;
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn relay() -> (StaticSecret, SphinxHop) {
+        let secret = StaticSecret::random_from_rng(OsRng);
+        let onion_key = PublicKey::from(&secret);
+        let hop = SphinxHop {
+            address: TaiorAddress::from_public_key(&onion_key),
+            onion_key,
+        };
+        (secret, hop)
+    }
+
+    #[test]
+    fn test_packet_traverses_route_with_constant_size() {
+        let relays: Vec<_> = (0..4).map(|_| relay()).collect();
+        let route: Vec<SphinxHop> = relays.iter().map(|(_, hop)| hop.clone()).collect();
+
+        let mut packet = SphinxPacket::build(&route, b"mensaje mix", 256).unwrap();
+        let wire_len = packet.to_bytes().len();
+        assert_eq!(wire_len, HEADER_LEN + 256);
+
+        for (i, (secret, _)) in relays.iter().enumerate() {
+            match packet.peel(secret).unwrap().peeled {
+                PeeledPacket::Forward { next_hop, packet: next } => {
+                    assert_eq!(next_hop, route[i + 1].address);
+                    assert_eq!(next.to_bytes().len(), wire_len);
+                    packet = *next;
+                }
+                PeeledPacket::Deliver { payload } => {
+                    assert_eq!(i, relays.len() - 1);
+                    assert_eq!(payload, b"mensaje mix");
+                    return;
+                }
+            }
+        }
+        panic!("packet was never delivered");
+    }
+
+    #[test]
+    fn test_wrong_key_and_tampering_are_rejected() {
+        let (secret, hop) = relay();
+        let (other, _) = relay();
+        let packet = SphinxPacket::build(&[hop], b"hola", 128).unwrap();
+
+        assert!(matches!(packet.peel(&other), Err(SphinxError::InvalidMac)));
+
+        let mut tampered = packet.clone();
+        tampered.routing_info[0] ^= 1;
+        assert!(matches!(tampered.peel(&secret), Err(SphinxError::InvalidMac)));
+
+        let mut tampered = packet;
+        tampered.body[0] ^= 1;
+        assert!(matches!(tampered.peel(&secret), Err(SphinxError::CorruptBody)));
+    }
+
+    #[test]
+    fn test_tagged_bodies_are_caught_at_the_last_hop() {
+        let relays: Vec<_> = (0..3).map(|_| relay()).collect();
+        let route: Vec<SphinxHop> = relays.iter().map(|(_, hop)| hop.clone()).collect();
+
+        // Flipping a payload bit past the tag, as a first hop tagging the packet would
+        let mut packet = SphinxPacket::build(&route, b"mensaje mix", 256).unwrap();
+        packet.body[BODY_OVERHEAD + 40] ^= 1;
+        for (secret, _) in &relays[..2] {
+            match packet.peel(secret).unwrap().peeled {
+                PeeledPacket::Forward { packet: next, .. } => packet = *next,
+                PeeledPacket::Deliver { .. } => panic!("delivered early"),
+            }
+        }
+        assert!(matches!(packet.peel(&relays[2].0), Err(SphinxError::CorruptBody)));
+    }
+
+    #[test]
+    fn test_surb_reply_reaches_creator_only() {
+        let relays: Vec<_> = (0..3).map(|_| relay()).collect();
+        let (creator_secret, creator) = relay();
+        let mut route: Vec<SphinxHop> = relays.iter().map(|(_, hop)| hop.clone()).collect();
+        route.push(creator);
+
+        let expires_at = crate::circuit::current_timestamp() + 600;
+        let (surb, secret) = Surb::build(&route, 512, expires_at).unwrap();
+        let surb = Surb::from_bytes(&surb.to_bytes()).unwrap();
+        assert_eq!(surb.first_hop, route[0].address);
+
+        let mut packet = surb.into_packet(b"respuesta anonima").unwrap();
+        for (relay_secret, _) in &relays {
+            match packet.peel(relay_secret).unwrap().peeled {
+                PeeledPacket::Forward { packet: next, .. } => packet = *next,
+                PeeledPacket::Deliver { .. } => panic!("relays must not be able to read the reply"),
+            }
+        }
+
+        // The creator's own onion key alone cannot read it; the SURB secret can
+        assert!(packet.peel(&creator_secret).is_err());
+        assert_eq!(secret.open(&packet).unwrap(), b"respuesta anonima");
+    }
+
+    #[test]
+    fn test_surb_body_lengths_out_of_range_are_rejected() {
+        let route = [relay().1, relay().1];
+        let (surb, _) = Surb::build(&route, 512, crate::circuit::current_timestamp() + 600).unwrap();
+        let mut bytes = surb.to_bytes();
+        let len_at = SURB_LEN - 12;
+
+        for body_len in [u32::MAX, MAX_BODY_LEN as u32 + 1, BODY_OVERHEAD as u32 - 1] {
+            bytes[len_at..len_at + 4].copy_from_slice(&body_len.to_be_bytes());
+            assert!(matches!(Surb::from_bytes(&bytes), Err(SphinxError::Malformed(_))));
+        }
+        bytes[len_at..len_at + 4].copy_from_slice(&(MAX_BODY_LEN as u32).to_be_bytes());
+        assert!(Surb::from_bytes(&bytes).is_ok());
+    }
+}
@@ -1,10 +1,12 @@
 pub mod quic;
 pub mod nat;
 pub mod relay;
+pub mod udp;
 
 pub use quic::{QuicTransport, QuicConfig};
 pub use nat::NatTraversal;
 pub use relay::{RelayClient, RelayServer, RelayAuth};
+pub use udp::UdpTransport;
 
 use crate::packet::TaiorPacket;
 use std::net::SocketAddr;
@@ -24,6 +26,16 @@ pub enum TransportError {
     RelayError(String),
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
+    /// A single packet could not be read; the transport itself is still usable.
+    #[error("Malformed packet: {0}")]
+    Malformed(String),
+}
+
+impl TransportError {
+    /// Whether the transport can no longer be used, as opposed to one packet being lost.
+    pub fn is_fatal(&self) -> bool {
+        !matches!(self, Self::Malformed(_))
+    }
 }
 
 pub type TransportResult<T> = Result<T, TransportError>;
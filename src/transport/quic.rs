@@ -1,10 +1,13 @@
 use super::{Transport, TransportError, TransportResult};
-use crate::packet::TaiorPacket;
+use crate::packet::{TaiorPacket, HEADER_LEN, MAX_PAYLOAD_LEN};
 use quinn::{ClientConfig, Endpoint, ServerConfig, Connection};
 use rustls::pki_types::{CertificateDer, PrivateKeyDer};
 use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use tokio::sync::{mpsc, Mutex};
+
+/// Packets read off a connection and not yet received.
+const INBOUND_QUEUE: usize = 64;
 
 pub struct QuicConfig {
     pub bind_addr: SocketAddr,
@@ -23,6 +26,8 @@ impl Default for QuicConfig {
 pub struct QuicTransport {
     pub endpoint: Endpoint,
     connection: Arc<Mutex<Option<Connection>>>,
+    /// Packets read by `read_streams` from the connected peer.
+    inbound: Option<mpsc::Receiver<TransportResult<(TaiorPacket, SocketAddr)>>>,
 }
 
 impl QuicTransport {
@@ -36,6 +41,7 @@ impl QuicTransport {
         Ok(Self {
             endpoint,
             connection: Arc::new(Mutex::new(None)),
+            inbound: None,
         })
     }
 
@@ -93,6 +99,9 @@ impl Transport for QuicTransport {
             .await
             .map_err(|e| TransportError::ConnectionFailed(format!("handshake: {}", e)))?;
 
+        let (inbound_tx, inbound) = mpsc::channel(INBOUND_QUEUE);
+        tokio::spawn(read_streams(conn.clone(), inbound_tx));
+        self.inbound = Some(inbound);
         *self.connection.lock().await = Some(conn);
         Ok(())
     }
@@ -105,8 +114,8 @@ impl Transport for QuicTransport {
         let mut send_stream = conn.open_uni().await
             .map_err(|e| TransportError::SendFailed(format!("open stream: {}", e)))?;
 
-        let serialized = serde_json::to_vec(packet)
-            .map_err(|e| TransportError::SendFailed(format!("serialize: {}", e)))?;
+        let serialized = packet.encode()
+            .map_err(|e| TransportError::SendFailed(format!("encode: {}", e)))?;
 
         send_stream.write_all(&serialized).await
             .map_err(|e| TransportError::SendFailed(format!("write: {}", e)))?;
@@ -117,22 +126,15 @@ impl Transport for QuicTransport {
         Ok(())
     }
 
+    /// Waits for the next packet from the connected peer. A stream that fails to read
+    /// or decode yields `TransportError::Malformed`. Streams are read by a task of
+    /// their own, so this is cancel safe: nothing is lost when the caller stops waiting.
     async fn receive(&mut self) -> TransportResult<(TaiorPacket, SocketAddr)> {
-        let conn_guard = self.connection.lock().await;
-        let conn = conn_guard.as_ref()
+        let inbound = self.inbound.as_mut()
             .ok_or_else(|| TransportError::ReceiveFailed("not connected".to_string()))?;
 
-        let mut recv_stream = conn.accept_uni().await
-            .map_err(|e| TransportError::ReceiveFailed(format!("accept stream: {}", e)))?;
-
-        let data = recv_stream.read_to_end(1024 * 1024).await
-            .map_err(|e| TransportError::ReceiveFailed(format!("read: {}", e)))?;
-
-        let packet: TaiorPacket = serde_json::from_slice(&data)
-            .map_err(|e| TransportError::ReceiveFailed(format!("deserialize: {}", e)))?;
-
-        let remote_addr = conn.remote_address();
-        Ok((packet, remote_addr))
+        inbound.recv().await
+            .ok_or_else(|| TransportError::ReceiveFailed("connection closed".to_string()))?
     }
 
     async fn close(&mut self) -> TransportResult<()> {
@@ -144,6 +146,24 @@ impl Transport for QuicTransport {
     }
 }
 
+/// Accepts the peer's streams, one packet each, until the connection closes. Each
+/// stream is read in a task of its own so that a slow one holds up no other.
+async fn read_streams(conn: Connection, inbound: mpsc::Sender<TransportResult<(TaiorPacket, SocketAddr)>>) {
+    while let Ok(mut recv_stream) = conn.accept_uni().await {
+        let inbound = inbound.clone();
+        let remote_addr = conn.remote_address();
+        tokio::spawn(async move {
+            let received = match recv_stream.read_to_end(HEADER_LEN + MAX_PAYLOAD_LEN).await {
+                Ok(data) => TaiorPacket::decode(&data)
+                    .map(|packet| (packet, remote_addr))
+                    .map_err(|e| TransportError::Malformed(format!("decode: {}", e))),
+                Err(e) => Err(TransportError::Malformed(format!("read: {}", e))),
+            };
+            let _ = inbound.send(received).await;
+        });
+    }
+}
+
 fn generate_self_signed_cert() -> Result<(CertificateDer<'static>, PrivateKeyDer<'static>), Box<dyn std::error::Error>> {
     let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
     let key = PrivateKeyDer::Pkcs8(cert.key_pair.serialize_der().into());
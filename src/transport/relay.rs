@@ -72,8 +72,7 @@ impl RelayClient {
             packet: packet.clone(),
         };
 
-        let serialized = serde_json::to_vec(&envelope)
-            .map_err(|e| TransportError::RelayError(format!("serialize: {}", e)))?;
+        let serialized = envelope.encode()?;
 
         self.socket.send_to(&serialized, self.relay_addr).await
             .map_err(|e| TransportError::RelayError(format!("send: {}", e)))?;
@@ -86,8 +85,7 @@ impl RelayClient {
         let (len, _) = self.socket.recv_from(&mut buf).await
             .map_err(|e| TransportError::RelayError(format!("recv: {}", e)))?;
 
-        let envelope: RelayEnvelope = serde_json::from_slice(&buf[..len])
-            .map_err(|e| TransportError::RelayError(format!("deserialize: {}", e)))?;
+        let envelope = RelayEnvelope::decode(&buf[..len])?;
 
         Ok((envelope.packet, envelope.destination))
     }
@@ -118,7 +116,7 @@ impl RelayServer {
             let (len, src_addr) = self.socket.recv_from(&mut buf).await
                 .map_err(|e| TransportError::RelayError(format!("recv: {}", e)))?;
 
-            let envelope: RelayEnvelope = match serde_json::from_slice(&buf[..len]) {
+            let envelope = match RelayEnvelope::decode(&buf[..len]) {
                 Ok(e) => e,
                 Err(_) => continue,
             };
@@ -130,10 +128,8 @@ impl RelayServer {
 
             let clients = self.clients.read().await;
             if let Some(dest_addr) = clients.get(&envelope.destination) {
-                let serialized = serde_json::to_vec(&envelope)
-                    .map_err(|e| TransportError::RelayError(format!("serialize: {}", e)))?;
-
-                let _ = self.socket.send_to(&serialized, dest_addr).await;
+                // Forward the datagram as received; it already decoded cleanly.
+                let _ = self.socket.send_to(&buf[..len], dest_addr).await;
             }
         }
     }
@@ -143,9 +139,51 @@ impl RelayServer {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Relay datagram: `[token len: 1][token][destination len: 1][destination][encoded TaiorPacket]`.
+#[derive(Debug, Clone)]
 struct RelayEnvelope {
     auth_token: String,
     destination: String,
     packet: TaiorPacket,
 }
+
+impl RelayEnvelope {
+    fn encode(&self) -> TransportResult<Vec<u8>> {
+        let packet = self.packet.encode()
+            .map_err(|e| TransportError::RelayError(format!("encode: {}", e)))?;
+
+        let mut out = Vec::with_capacity(2 + self.auth_token.len() + self.destination.len() + packet.len());
+        for field in [&self.auth_token, &self.destination] {
+            let len = u8::try_from(field.len())
+                .map_err(|_| TransportError::RelayError("envelope field too long".to_string()))?;
+            out.push(len);
+            out.extend_from_slice(field.as_bytes());
+        }
+        out.extend_from_slice(&packet);
+        Ok(out)
+    }
+
+    fn decode(bytes: &[u8]) -> TransportResult<Self> {
+        let mut rest = bytes;
+        let mut fields = Vec::with_capacity(2);
+        for _ in 0..2 {
+            let (&len, tail) = rest.split_first()
+                .ok_or_else(|| TransportError::RelayError("truncated envelope".to_string()))?;
+            let len = len as usize;
+            if tail.len() < len {
+                return Err(TransportError::RelayError("truncated envelope".to_string()));
+            }
+            let field = std::str::from_utf8(&tail[..len])
+                .map_err(|e| TransportError::RelayError(format!("decode: {}", e)))?;
+            fields.push(field.to_string());
+            rest = &tail[len..];
+        }
+
+        let packet = TaiorPacket::decode(rest)
+            .map_err(|e| TransportError::RelayError(format!("decode: {}", e)))?;
+        let destination = fields.pop().unwrap_or_default();
+        let auth_token = fields.pop().unwrap_or_default();
+
+        Ok(Self { auth_token, destination, packet })
+    }
+}
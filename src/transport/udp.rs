@@ -0,0 +1,55 @@
+use super::{Transport, TransportError, TransportResult};
+use crate::packet::{TaiorPacket, HEADER_LEN, MAX_PAYLOAD_LEN};
+use std::net::SocketAddr;
+use tokio::net::UdpSocket;
+
+/// Connectionless transport: one encoded `TaiorPacket` per datagram.
+/// Lets a relay talk to any number of peers from a single socket.
+pub struct UdpTransport {
+    socket: UdpSocket,
+}
+
+impl UdpTransport {
+    pub async fn bind(addr: SocketAddr) -> TransportResult<Self> {
+        let socket = UdpSocket::bind(addr).await
+            .map_err(|e| TransportError::ConnectionFailed(format!("bind: {}", e)))?;
+        Ok(Self { socket })
+    }
+
+    pub fn local_addr(&self) -> TransportResult<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+}
+
+#[async_trait::async_trait]
+impl Transport for UdpTransport {
+    async fn send(&mut self, packet: &TaiorPacket, dest: SocketAddr) -> TransportResult<()> {
+        let encoded = packet.encode()
+            .map_err(|e| TransportError::SendFailed(format!("encode: {}", e)))?;
+
+        self.socket.send_to(&encoded, dest).await
+            .map_err(|e| TransportError::SendFailed(format!("send: {}", e)))?;
+        Ok(())
+    }
+
+    /// Waits for the next well-formed packet. Datagrams that fail to decode are dropped.
+    async fn receive(&mut self) -> TransportResult<(TaiorPacket, SocketAddr)> {
+        let mut buf = vec![0u8; HEADER_LEN + MAX_PAYLOAD_LEN];
+        loop {
+            let (len, src) = self.socket.recv_from(&mut buf).await
+                .map_err(|e| TransportError::ReceiveFailed(format!("recv: {}", e)))?;
+
+            if let Ok(packet) = TaiorPacket::decode(&buf[..len]) {
+                return Ok((packet, src));
+            }
+        }
+    }
+
+    async fn connect(&mut self, _addr: SocketAddr) -> TransportResult<()> {
+        Ok(())
+    }
+
+    async fn close(&mut self) -> TransportResult<()> {
+        Ok(())
+    }
+}
@@ -49,8 +49,7 @@ impl TaiorWasm {
         router.decide_next_hop(candidates, &mode_config)
     }
 
-    /// Returns the full serialized packet (encrypted_payload + ikm) as a single byte array.
-    /// Format: [4 bytes: payload_len (big-endian)] [encrypted_payload] [ikm]
+    /// Returns the packet in the binary wire format (see `TaiorPacket::encode`).
     pub fn send(&mut self, data: &[u8], mode: String) -> Result<Vec<u8>, JsValue> {
         let routing_mode = match mode.as_str() {
             "fast" => RoutingMode::Fast,
@@ -67,18 +66,25 @@ impl TaiorWasm {
 
         let opts = SendOptions::custom(routing_mode, hops);
         let packet = self.inner.send(data, opts)
-            .map_err(|e| JsValue::from_str(&e))?;
+            .map_err(|e| JsValue::from_str(&e.to_string()))?
+            .ready()
+            .ok_or_else(|| JsValue::from_str("send queued until enough hops are available"))?
+            .packet;
 
-        // Serialize: [4 bytes payload_len] [encrypted_payload] [ikm]
-        let payload_len = packet.encrypted_payload.len() as u32;
-        let mut result = Vec::with_capacity(4 + packet.encrypted_payload.len() + packet.ikm.len());
-        result.extend_from_slice(&payload_len.to_be_bytes());
-        result.extend_from_slice(&packet.encrypted_payload);
-        result.extend_from_slice(&packet.ikm);
+        packet.encode()
+            .map_err(|e| JsValue::from_str(&e.to_string()))
+    }
 
-        Ok(result)
+    /// Opens a packet in the binary wire format. Cover packets yield `undefined`.
+    pub fn receive(&mut self, packet: &[u8]) -> Result<Option<Vec<u8>>, JsValue> {
+        let packet = crate::packet::TaiorPacket::decode(packet)
+            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+        let message = self.inner.receive(&packet)
+            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+        Ok(message.map(|m| m.data))
     }
 
+    #[cfg(feature = "mix-mode")]
     #[wasm_bindgen(js_name = enableCoverTraffic)]
     pub fn enable_cover_traffic(&mut self, enabled: bool, ratio: f32) {
         self.inner.enable_cover_traffic(enabled, ratio);